@@ -0,0 +1,174 @@
+//! A `Node` driven by an async CAN driver, for single-threaded executors
+//! (e.g. embassy) where busy-polling `WouldBlock` is not wanted.
+//!
+//! The protocol logic is the one of `Node`: it runs on top of `TxQueue`, a
+//! `nb::Can` which only collects the frames the node sends, and `AsyncNode`
+//! forwards them to the async driver after each step.
+
+use core::convert::Infallible;
+use core::fmt::Debug;
+
+use embedded_can::Frame;
+use embedded_can::nb::Can;
+
+use crate::error::ErrorCode;
+use crate::node::Node;
+use crate::prelude::*;
+use crate::util::copy_frame;
+use crate::{error, info};
+
+/// The async counterpart of `embedded_can::nb::Can`.
+// Futures aren't required to be `Send`, the node is not thread-safe anyway.
+#[allow(async_fn_in_trait)]
+pub trait AsyncCan {
+    type Frame: Frame + Debug;
+    type Error: Debug;
+
+    /// Sends a frame, waiting for room in the transmit buffer.
+    async fn transmit(&mut self, frame: &Self::Frame) -> Result<(), Self::Error>;
+
+    /// Waits for the next received frame.
+    async fn receive(&mut self) -> Result<Self::Frame, Self::Error>;
+}
+
+/// The `Can` of the node inside `AsyncNode`: never receives anything and
+/// queues the transmitted frames.
+pub struct TxQueue<F> {
+    frames: Vec<F>,
+}
+
+impl<F: Frame> Can for TxQueue<F> {
+    type Frame = F;
+    type Error = Infallible;
+
+    fn transmit(&mut self, frame: &F) -> nb::Result<Option<F>, Infallible> {
+        if let Some(copy) = copy_frame(frame) {
+            self.frames.push(copy);
+        }
+        Ok(None)
+    }
+
+    fn receive(&mut self) -> nb::Result<F, Infallible> {
+        Err(nb::Error::WouldBlock)
+    }
+}
+
+/// A `Node` doing its frame I/O through an `AsyncCan`.
+pub struct AsyncNode<CAN: AsyncCan> {
+    node: Node<TxQueue<CAN::Frame>>,
+    can: CAN,
+}
+
+impl<CAN: AsyncCan> AsyncNode<CAN> {
+    pub fn new(node_id: u8, eds_content: &str, can: CAN) -> Result<Self, ErrorCode> {
+        let node = Node::new(node_id, eds_content, TxQueue { frames: Vec::new() })?;
+        Ok(AsyncNode { node, can })
+    }
+
+    /// The underlying node, for everything besides the frame I/O. Frames it
+    /// sends are forwarded by the next call of an async method.
+    pub fn node(&mut self) -> &mut Node<TxQueue<CAN::Frame>> {
+        &mut self.node
+    }
+
+    /// See `Node::init()`.
+    pub async fn init(&mut self) -> Result<(), ErrorCode> {
+        self.node.init()?;
+        self.flush().await;
+        Ok(())
+    }
+
+    /// Waits for the next frame, processes it and sends the responses.
+    pub async fn process_one_frame(&mut self) {
+        match self.can.receive().await {
+            Ok(frame) => {
+                for response in self.node.handle_frame(&frame) {
+                    self.transmit(&response).await;
+                }
+            }
+            Err(err) => { info!("Errors in reading CAN frame, {:?}", err); }
+        }
+        self.flush().await;
+    }
+
+    /// See `Node::event_timer_callback()`, to be called every 1ms.
+    pub async fn event_timer_callback(&mut self) {
+        self.node.event_timer_callback();
+        self.flush().await;
+    }
+
+    /// See `Node::timer_tick()`.
+    pub async fn timer_tick(&mut self, elapsed_ms: u32) {
+        self.node.timer_tick(elapsed_ms);
+        self.flush().await;
+    }
+
+    pub async fn transmit(&mut self, frame: &CAN::Frame) {
+        match self.can.transmit(frame).await {
+            Ok(_) => { info!("Sent frame {:x?}", frame); }
+            Err(err) => { error!("Errors in transmit frame {:x?}, err: {:?}", frame, err); }
+        }
+    }
+
+    async fn flush(&mut self) {
+        let frames = core::mem::take(&mut self.node.can_network.frames);
+        for frame in frames.iter() {
+            self.transmit(frame).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::collections::VecDeque;
+    use core::future::Future;
+    use core::pin::pin;
+    use core::task::{Context, Poll, Waker};
+
+    use super::*;
+    use crate::test_util::{MockError, MockFrame, TEST_EDS, TEST_NODE_ID, frame};
+
+    #[derive(Default)]
+    struct MockAsyncCan {
+        rx: VecDeque<MockFrame>,
+        tx: Vec<MockFrame>,
+    }
+
+    impl AsyncCan for MockAsyncCan {
+        type Frame = MockFrame;
+        type Error = MockError;
+
+        async fn transmit(&mut self, frame: &MockFrame) -> Result<(), MockError> {
+            self.tx.push(frame.clone());
+            Ok(())
+        }
+
+        async fn receive(&mut self) -> Result<MockFrame, MockError> {
+            self.rx.pop_front().ok_or(MockError)
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+        }
+    }
+
+    #[test]
+    fn test_async_expedited_sdo_read() {
+        let mut node = AsyncNode::new(TEST_NODE_ID, TEST_EDS, MockAsyncCan::default()).unwrap();
+        block_on(node.init()).unwrap();
+        assert_eq!(node.can.tx.last().unwrap().cob_id(), 0x702);
+        node.can.tx.clear();
+
+        node.can.rx.push_back(frame(0x602, &[0x40, 0x00, 0x20, 0, 0, 0, 0, 0]));
+        block_on(node.process_one_frame());
+        assert_eq!(node.can.tx.len(), 1);
+        assert_eq!(node.can.tx[0].cob_id(), 0x582);
+        assert_eq!(node.can.tx[0].data(), &[0x43, 0x00, 0x20, 0, 0x78, 0x56, 0x34, 0x12]);
+    }
+}
@@ -0,0 +1,49 @@
+// Cross-path byte order checks: a value must look the same on the wire whether it
+// goes through SDO (expedited upload / download) or through a TPDO / RPDO.
+
+use embedded_can::Frame;
+
+use crate::util::u64_to_vec;
+use crate::value::{ByteConvertible, Value};
+use crate::test_util::{frame, new_node, sdo_read, sdo_write_u32, sdo_write_u8, send};
+
+const VALUE: u32 = 0x1234_5678;
+const WIRE_BYTES: [u8; 4] = [0x78, 0x56, 0x34, 0x12];
+
+#[test]
+fn test_sdo_tpdo_rpdo_agree_on_little_endian() {
+    let mut node = new_node();
+
+    // SDO download, then SDO upload.
+    assert_eq!(sdo_write_u32(&mut node, 0x2000, 0, VALUE)[0], 0x60);
+    assert_eq!(sdo_read(&mut node, 0x2000, 0)[4..8], WIRE_BYTES);
+
+    // Map 0x2000 into RPDO1 as well, so the TPDO payload can be fed back.
+    assert_eq!(sdo_write_u8(&mut node, 0x1600, 0, 0)[0], 0x60);
+    assert_eq!(sdo_write_u32(&mut node, 0x1600, 1, 0x2000_0020)[0], 0x60);
+    assert_eq!(sdo_write_u8(&mut node, 0x1600, 0, 1)[0], 0x60);
+
+    // TPDO1 (0x1A00 maps 0x2000) is sent on every SYNC.
+    send(&mut node, frame(0x000, &[0x01, 2]));
+    let tpdo = send(&mut node, frame(0x080, &[]));
+    let tpdo = tpdo.iter().find(|f| f.cob_id() == 0x182).expect("no TPDO on SYNC");
+    assert_eq!(tpdo.data(), &WIRE_BYTES);
+
+    // Clear the object, then write the TPDO payload back via RPDO1.
+    assert_eq!(sdo_write_u32(&mut node, 0x2000, 0, 0)[0], 0x60);
+    assert!(send(&mut node, frame(0x202, tpdo.data())).is_empty());
+    send(&mut node, frame(0x080, &[]));
+    assert_eq!(sdo_read(&mut node, 0x2000, 0)[4..8], WIRE_BYTES);
+}
+
+#[test]
+fn test_byte_convertible_is_little_endian() {
+    assert_eq!(VALUE.to_bytes(), WIRE_BYTES);
+    assert_eq!(u32::from_bytes(&WIRE_BYTES), VALUE);
+    assert_eq!(0x1234u16.to_bytes(), [0x34, 0x12]);
+    assert_eq!((-2i16).to_bytes(), [0xFE, 0xFF]);
+    assert_eq!(1.0f32.to_bytes(), [0x00, 0x00, 0x80, 0x3F]);
+    assert_eq!(Value::from(VALUE).data(), &WIRE_BYTES);
+    assert_eq!(Value::new(WIRE_BYTES.to_vec()).to::<u32>(), VALUE);
+    assert_eq!(u64_to_vec(VALUE as u64, 4), WIRE_BYTES);
+}
@@ -3,28 +3,56 @@ use core::ops::Range;
 /// Canopen Function code prefixes on COB_ID
 pub(crate) const COB_FUNC_NMT: u16 = 0x000;
 pub(crate) const COB_FUNC_SYNC: u16 = 0x080;
-pub(crate) const COB_FUNC_RPDO_0: u16 = 0x200;
+pub(crate) const COB_ID_TIME: u16 = 0x100;
+pub(crate) const COB_FUNC_TPDO_0: u16 = 0x180;
+// pub(crate) const COB_FUNC_RPDO_0: u16 = 0x200;
 // pub(crate) const COB_FUNC_RPDO_1: u16 = 0x300;
 // pub(crate) const COB_FUNC_RPDO_2: u16 = 0x400;
 pub(crate) const COB_FUNC_RPDO_3: u16 = 0x500;
 pub(crate) const COB_FUNC_TRANSMIT_SDO: u16 = 0x580;
 pub(crate) const COB_FUNC_RECEIVE_SDO: u16 = 0x600;
+pub(crate) const COB_FUNC_HEARTBEAT: u16 = 0x700;
 pub(crate) const COB_FUNC_MASK: u16 = 0xFF80;
+pub(crate) const COB_ID_LSS_SLAVE: u16 = 0x7E4;
+pub(crate) const COB_ID_LSS_MASTER: u16 = 0x7E5;
+/// Node-id of a node that has not been configured yet.
+pub const LSS_UNCONFIGURED_NODE_ID: u8 = 0xFF;
 
 
 /// CANOPEN Registers
 pub(crate) const REG_ERROR: u16 = 0x1001;
 // pub(crate) const REG_MANUFACTURER_STATUE: u16 = 0x1002;
 pub(crate) const REG_PRE_DEFINED_ERROR: u16 = 0x1003;
+pub(crate) const REG_COB_ID_SYNC: u16 = 0x1005;
+pub(crate) const REG_COMMUNICATION_CYCLE_PERIOD: u16 = 0x1006;
+pub(crate) const REG_SYNC_WINDOW_LENGTH: u16 = 0x1007;
+pub(crate) const REG_MANUFACTURER_DEVICE_NAME: u16 = 0x1008;
+pub(crate) const REG_MANUFACTURER_HARDWARE_VERSION: u16 = 0x1009;
+pub(crate) const REG_MANUFACTURER_SOFTWARE_VERSION: u16 = 0x100A;
+pub(crate) const REG_STORE_PARAMETERS: u16 = 0x1010;
 pub(crate) const REG_RESTORE_DEFAULT_PARAMETERS: u16 = 0x1011;
+pub(crate) const REG_COB_ID_EMCY: u16 = 0x1014;
+pub(crate) const REG_INHIBIT_TIME_EMCY: u16 = 0x1015;
+pub(crate) const REG_CONSUMER_HEARTBEAT_TIME: u16 = 0x1016;
 pub(crate) const REG_PRODUCER_HEARTBEAT_TIME: u16 = 0x1017;
+pub(crate) const REG_IDENTITY: u16 = 0x1018;
+pub(crate) const REG_SYNC_COUNTER_OVERFLOW: u16 = 0x1019;
 
-pub(crate) const COMMUNICATION_REGISTERS_RANGE: Range<u16> = 0x1000..0x1FFF;
-pub(crate) const APPLICATION_REGISTERS_RANGE: Range<u16> = 0x6000..0x9FFF;
-pub(crate) const ALL_REGISTERS_RANGE: Range<u16> = 0x6000..0x9FFF;
+/// Objects restored by 0x1011 sub 2, the communication profile area.
+pub(crate) const COMMUNICATION_REGISTERS_RANGE: Range<u16> = 0x1000..0x2000;
+/// Objects restored by 0x1011 sub 3, the manufacturer and device profile areas.
+pub(crate) const APPLICATION_REGISTERS_RANGE: Range<u16> = 0x2000..0xA000;
+pub(crate) const ALL_REGISTERS_RANGE: Range<u16> = 0x1000..0xA000;
 
 /// Emergency Codes
+pub(crate) const EMCY_DEVICE_HARDWARE: u16 = 0x5000;
+pub(crate) const EMCY_INTERNAL_SOFTWARE: u16 = 0x6100;
+pub(crate) const EMCY_CAN_OVERRUN: u16 = 0x8110;
+pub(crate) const EMCY_CAN_ERROR_PASSIVE: u16 = 0x8120;
+pub(crate) const EMCY_LIFE_GUARD_ERROR: u16 = 0x8130;
+pub(crate) const EMCY_RECOVERED_FROM_BUS_OFF: u16 = 0x8140;
 pub(crate) const EMCY_PDO_NOT_PROCESSED: u16 = 0x8210;
 
 /// Misc
 pub(crate) const RESET_MAGIC_CODE: u32 = 0x64_61_6F_6C;
+pub(crate) const SAVE_MAGIC_CODE: u32 = 0x65_76_61_73;
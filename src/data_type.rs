@@ -3,6 +3,7 @@ use core::hash::{Hash, Hasher};
 use crate::prelude::*;
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum DataType {
     Unknown = 0x0,
     Boolean = 0x1,
@@ -63,8 +64,9 @@ impl DataType {
         }
     }
 
-    // Return size of a type.
-    // Size 0 means it is variant.
+    // Return size of a type, as read / written over SDO and stored in the OD.
+    // Size 0 means it is variant. In PDOs only the mapped bit length counts,
+    // a Boolean takes 1 bit there.
     pub(crate) fn size(&self) -> usize {
         match self {
             DataType::Unknown => 0,       // Size 0 for unknown data type
@@ -79,13 +81,23 @@ impl DataType {
             DataType::VisibleString => 0, // 1 byte per character, but variable length
             DataType::OctetString => 0,   // 1 byte per character, but variable length
             DataType::UnicodeString => 0, // 2 bytes per character, but variable length
-            DataType::Domain => 4,        // Variable length, assuming 1 for simplicity
+            DataType::Domain => 0,        // Arbitrary bytes, variable length
             DataType::Real64 => 8,        // 8 bytes
             DataType::Integer64 => 8,     // 8 bytes
             DataType::Unsigned64 => 8,    // 8 bytes
         }
     }
 
+    /// String types take any length, the written data defines the new length.
+    pub(crate) fn is_string(&self) -> bool {
+        matches!(self, DataType::VisibleString | DataType::OctetString | DataType::UnicodeString)
+    }
+
+    /// Variable length types: the written data defines the new length.
+    pub(crate) fn is_variable_length(&self) -> bool {
+        self.is_string() || *self == DataType::Domain
+    }
+
     pub(crate) fn default_value(&self) -> Vec<u8> {
         match *self {
             DataType::Unknown | DataType::Boolean => vec![0x0],
@@ -95,7 +107,7 @@ impl DataType {
                 vec![0x0, 0x0, 0x0, 0x0]
             }
             DataType::VisibleString | DataType::OctetString | DataType::UnicodeString => vec![],
-            DataType::Domain => vec![0x0],
+            DataType::Domain => vec![],
             DataType::Real64 | DataType::Integer64 | DataType::Unsigned64 => {
                 vec![0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0]
             }
@@ -144,7 +156,7 @@ mod tests {
         assert_eq!(DataType::VisibleString.size(), 0);
         assert_eq!(DataType::OctetString.size(), 0);
         assert_eq!(DataType::UnicodeString.size(), 0);
-        assert_eq!(DataType::Domain.size(), 4);
+        assert_eq!(DataType::Domain.size(), 0);
         assert_eq!(DataType::Real64.size(), 8);
         assert_eq!(DataType::Integer64.size(), 8);
         assert_eq!(DataType::Unsigned64.size(), 8);
@@ -161,15 +173,25 @@ mod tests {
         assert_eq!(DataType::Unsigned16.default_value(), vec![0x0, 0x0]);
         assert_eq!(DataType::Unsigned32.default_value(), vec![0x0, 0x0, 0x0, 0x0]);
         assert_eq!(DataType::Real32.default_value(), vec![0x0, 0x0, 0x0, 0x0]);
-        assert_eq!(DataType::VisibleString.default_value(), vec![]);
-        assert_eq!(DataType::OctetString.default_value(), vec![]);
-        assert_eq!(DataType::UnicodeString.default_value(), vec![]);
-        assert_eq!(DataType::Domain.default_value(), vec![0x0]);
+        assert_eq!(DataType::VisibleString.default_value(), Vec::<u8>::new());
+        assert_eq!(DataType::OctetString.default_value(), Vec::<u8>::new());
+        assert_eq!(DataType::UnicodeString.default_value(), Vec::<u8>::new());
+        assert_eq!(DataType::Domain.default_value(), Vec::<u8>::new());
         assert_eq!(DataType::Real64.default_value(), vec![0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0]);
         assert_eq!(DataType::Integer64.default_value(), vec![0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0]);
         assert_eq!(DataType::Unsigned64.default_value(), vec![0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0]);
     }
 
+    #[test]
+    fn test_is_variable_length() {
+        assert!(DataType::VisibleString.is_variable_length());
+        assert!(DataType::OctetString.is_variable_length());
+        assert!(DataType::UnicodeString.is_variable_length());
+        assert!(DataType::Domain.is_variable_length());
+        assert!(!DataType::Domain.is_string());
+        assert!(!DataType::Unsigned32.is_variable_length());
+    }
+
     #[test]
     fn test_data_type_ordering() {
         let type1 = DataType::Boolean;
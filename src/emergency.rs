@@ -1,3 +1,4 @@
+use alloc::boxed::Box;
 use alloc::vec;
 use alloc::vec::Vec;
 use core::fmt::Debug;
@@ -5,26 +6,59 @@ use core::fmt::Debug;
 use embedded_can::Frame;
 use embedded_can::nb::Can;
 
-use crate::constant::{COB_FUNC_SYNC, EMCY_PDO_NOT_PROCESSED, REG_ERROR, REG_PRE_DEFINED_ERROR};
+use crate::constant::{COB_FUNC_SYNC, EMCY_CAN_ERROR_PASSIVE, EMCY_CAN_OVERRUN, EMCY_DEVICE_HARDWARE,
+                      EMCY_INTERNAL_SOFTWARE, EMCY_LIFE_GUARD_ERROR, EMCY_PDO_NOT_PROCESSED,
+                      EMCY_RECOVERED_FROM_BUS_OFF, REG_COB_ID_EMCY, REG_ERROR, REG_INHIBIT_TIME_EMCY,
+                      REG_PRE_DEFINED_ERROR};
 use crate::error::ErrorCode;
 use crate::node::Node;
-use crate::util::create_frame_with_padding;
+use crate::util::{create_frame_with_padding, get_cob_id};
+use crate::{error, info, warn};
+
+/// Bit 31 of 0x1014: the node doesn't send EMCY messages.
+const EMCY_INVALID_BIT: u32 = 1 << 31;
+
+/// Receives (node_id, error code, error register, manufacturer specific bytes)
+/// of EMCY messages from other nodes. The error register is passed as received,
+/// several bits may be set, test them with `ErrorRegister::mask()`.
+pub type EmergencyCallback = Box<dyn FnMut(u8, EmergencyErrorCode, u8, [u8; 5])>;
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum EmergencyErrorCode {
+    DeviceHardware,
+    InternalSoftware,
+    CanOverrun,
+    CanErrorPassive,
+    /// Life guard or heartbeat error.
+    LifeGuardError,
+    RecoveredFromBusOff,
     PdoNotProcessed,
+    /// A code received from another node which has no variant here.
+    Unknown(u16),
 }
 
 impl EmergencyErrorCode {
     pub(crate) fn code(&self) -> u16 {
         match *self {
+            EmergencyErrorCode::DeviceHardware => EMCY_DEVICE_HARDWARE,
+            EmergencyErrorCode::InternalSoftware => EMCY_INTERNAL_SOFTWARE,
+            EmergencyErrorCode::CanOverrun => EMCY_CAN_OVERRUN,
+            EmergencyErrorCode::CanErrorPassive => EMCY_CAN_ERROR_PASSIVE,
+            EmergencyErrorCode::LifeGuardError => EMCY_LIFE_GUARD_ERROR,
+            EmergencyErrorCode::RecoveredFromBusOff => EMCY_RECOVERED_FROM_BUS_OFF,
             EmergencyErrorCode::PdoNotProcessed => EMCY_PDO_NOT_PROCESSED,
+            EmergencyErrorCode::Unknown(code) => code,
         }
     }
 
-    #[allow(dead_code)]
     pub(crate) fn from_code(code: u16) -> Option<Self> {
         match code {
+            EMCY_DEVICE_HARDWARE => Some(EmergencyErrorCode::DeviceHardware),
+            EMCY_INTERNAL_SOFTWARE => Some(EmergencyErrorCode::InternalSoftware),
+            EMCY_CAN_OVERRUN => Some(EmergencyErrorCode::CanOverrun),
+            EMCY_CAN_ERROR_PASSIVE => Some(EmergencyErrorCode::CanErrorPassive),
+            EMCY_LIFE_GUARD_ERROR => Some(EmergencyErrorCode::LifeGuardError),
+            EMCY_RECOVERED_FROM_BUS_OFF => Some(EmergencyErrorCode::RecoveredFromBusOff),
             EMCY_PDO_NOT_PROCESSED => Some(EmergencyErrorCode::PdoNotProcessed),
             _ => None,
         }
@@ -59,6 +93,11 @@ impl ErrorRegister {
         }
     }
 
+    /// The bit of the error in the error register (0x1001).
+    pub fn mask(&self) -> u8 {
+        1 << self.code()
+    }
+
     #[allow(dead_code)]
     pub(crate) fn from_code(code: u8) -> Option<Self> {
         match code {
@@ -80,32 +119,192 @@ impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
                                     -> Result<(), ErrorCode> {
         let eec_arr = eec.code().to_le_bytes();
         let (eecl, eech) = (eec_arr[0], eec_arr[1]);
-        let erc = er.code();
+        let erc = er.mask();
         let mut v: Vec<u8> = vec![eecl, eech, erc];
         v.extend_from_slice(data);
-        let frame = create_frame_with_padding(COB_FUNC_SYNC | self.node_id as u16, v.as_slice())?;
-        self.transmit(&frame);
+        // A new error makes a queued error reset stale.
+        self.emcy_reset_pending = false;
+        self.transmit_emergency(&v)?;
 
-        let tmp_count = self.error_count + 1;
-        self.object_directory.set_value(REG_PRE_DEFINED_ERROR, 0x0, &[tmp_count], true)?;
-        self.object_directory.set_value(REG_PRE_DEFINED_ERROR, tmp_count, &[eecl, eech, 0, 0], true)?;
+        // The additional information of the entry holds the error register.
+        self.record_error(&[eecl, eech, erc, 0])?;
         self.object_directory.set_value(REG_ERROR, 0x0, &[erc], true)?;
-        self.error_count = tmp_count;
+        Ok(())
+    }
+
+    /// The COB-ID of the own EMCY messages from 0x1014 (0x080 + node-id when
+    /// the object is missing), `None` when 0x1014 marks it invalid.
+    fn emcy_cob_id(&mut self) -> Option<u16> {
+        match self.object_directory.get_variable(REG_COB_ID_EMCY, 0)
+            .and_then(|var| var.effective_value().try_to::<u32>()) {
+            Ok(cob_id) if cob_id & EMCY_INVALID_BIT != 0 => None,
+            Ok(cob_id) => Some((cob_id & 0x7FF) as u16),
+            Err(_) => Some(COB_FUNC_SYNC | self.node_id as u16),
+        }
+    }
 
-        let mut reset_v: Vec<u8> = vec![0, 0, 0];
-        reset_v.extend_from_slice(data);
-        let reset_frame = create_frame_with_padding(COB_FUNC_SYNC | self.node_id as u16, reset_v.as_slice())?;
-        self.transmit(&reset_frame);
+    /// Sends an EMCY message. It is dropped when 0x1014 is invalid or the
+    /// 0x1015 inhibit time of the previous EMCY is still running.
+    fn transmit_emergency(&mut self, payload: &[u8]) -> Result<(), ErrorCode> {
+        let cob_id = match self.emcy_cob_id() {
+            Some(cob_id) => cob_id,
+            None => return Ok(()),
+        };
+        if self.emcy_inhibit_remaining_us > 0 {
+            info!("EMCY dropped within the inhibit time, {}us left", self.emcy_inhibit_remaining_us);
+            return Ok(());
+        }
+        let frame = create_frame_with_padding(cob_id, payload)?;
+        self.transmit(&frame);
+        let inhibit_time: u16 = self.object_directory.get_variable(REG_INHIBIT_TIME_EMCY, 0)
+            .and_then(|var| var.effective_value().try_to())
+            .unwrap_or(0);
+        self.emcy_inhibit_remaining_us = inhibit_time as u32 * 100;
+        Ok(())
+    }
 
+    /// Called on each timer event, runs down the EMCY inhibit time and sends
+    /// the error reset EMCY queued by `clear_emergency()` once it is over.
+    pub(crate) fn emcy_inhibit_tick(&mut self, elapsed_ms: u32) {
+        self.emcy_inhibit_remaining_us = self.emcy_inhibit_remaining_us.saturating_sub(elapsed_ms.saturating_mul(1000));
+        if self.emcy_reset_pending && self.emcy_inhibit_remaining_us == 0 {
+            self.emcy_reset_pending = false;
+            if let Err(err) = self.transmit_emergency(&[0; 8]) {
+                error!("Errors in sending the error reset EMCY: {:?}", err);
+            }
+        }
+    }
+
+    /// Number of error entries 0x1003 can hold, i.e. its sub-indexes besides 0.
+    fn error_field_capacity(&self) -> u8 {
+        self.object_directory.declared_largest_sub_index(REG_PRE_DEFINED_ERROR).unwrap_or(0)
+    }
+
+    /// Inserts an error into 0x1003 as sub-index 1, shifting older errors
+    /// down and dropping the oldest one once the field is full.
+    fn record_error(&mut self, entry: &[u8]) -> Result<(), ErrorCode> {
+        let capacity = self.error_field_capacity();
+        if capacity == 0 {
+            return Ok(());
+        }
+        let count = (self.error_count + 1).min(capacity);
+        for si in (2..=count).rev() {
+            let older = self.object_directory.get_mut_variable(REG_PRE_DEFINED_ERROR, si - 1)?
+                .effective_value().data().clone();
+            self.object_directory.set_value(REG_PRE_DEFINED_ERROR, si, &older, true)?;
+        }
+        self.object_directory.set_value(REG_PRE_DEFINED_ERROR, 1, entry, true)?;
+        self.object_directory.set_value(REG_PRE_DEFINED_ERROR, 0, &[count], true)?;
+        self.error_count = count;
         Ok(())
     }
+
+    /// Signals that all errors are resolved: clears the error history in
+    /// 0x1001 / 0x1003 and sends the "error reset / no error" EMCY (code 0x0000).
+    /// Within the inhibit time of the previous EMCY, it is sent once the
+    /// inhibit time is over rather than dropped.
+    pub fn clear_emergency(&mut self) -> Result<(), ErrorCode> {
+        self.error_count = 0;
+        self.object_directory.set_value(REG_ERROR, 0x0, &[0], true)?;
+        self.object_directory.set_value(REG_PRE_DEFINED_ERROR, 0x0, &[0], true)?;
+        if self.emcy_inhibit_remaining_us > 0 {
+            self.emcy_reset_pending = true;
+            return Ok(());
+        }
+        self.transmit_emergency(&[0; 8])
+    }
+
+    /// The error register (0x1001) as set by the last emergency, 0 without the object.
+    pub fn error_register(&mut self) -> u8 {
+        self.object_directory.get_variable(REG_ERROR, 0)
+            .and_then(|var| var.effective_value().try_to())
+            .unwrap_or(0)
+    }
+
+    /// Number of errors in the pre-defined error field (0x1003 sub-index 0).
+    pub fn active_error_count(&mut self) -> u8 {
+        self.object_directory.get_variable(REG_PRE_DEFINED_ERROR, 0)
+            .and_then(|var| var.effective_value().try_to())
+            .unwrap_or(0)
+    }
+
+    /// The (error code, error register) of the errors in 0x1003, newest first.
+    pub fn emergency_history(&mut self) -> Vec<(u16, u8)> {
+        (1..=self.active_error_count())
+            .filter_map(|si| {
+                let entry: u32 = self.object_directory.get_variable(REG_PRE_DEFINED_ERROR, si)
+                    .and_then(|var| var.effective_value().try_to()).ok()?;
+                Some((entry as u16, (entry >> 16) as u8))
+            })
+            .collect()
+    }
+
+    /// Sends an EMCY message with the given code, error register and
+    /// manufacturer specific bytes, and records it in 0x1001 / 0x1003.
+    pub fn emit_emergency(&mut self, code: EmergencyErrorCode, register: ErrorRegister, vendor: &[u8; 5])
+                          -> Result<(), ErrorCode> {
+        self.trigger_emergency(code, register, vendor)
+    }
+
+    /// Registers the callback for EMCY messages received from other nodes.
+    pub fn on_emergency(&mut self, callback: EmergencyCallback) {
+        self.emergency_callback = Some(callback);
+    }
+
+    pub(crate) fn process_emergency_frame(&mut self, frame: &CAN::Frame) {
+        let data = frame.data();
+        if data.len() < 3 {
+            warn!("EMCY frame too short: {:x?}", frame);
+            return;
+        }
+        let node_id = match get_cob_id(frame) {
+            Some(cob_id) => (cob_id & 0x7F) as u8,
+            None => return,
+        };
+        let code = u16::from_le_bytes([data[0], data[1]]);
+        let eec = EmergencyErrorCode::from_code(code).unwrap_or(EmergencyErrorCode::Unknown(code));
+        let mut vendor = [0u8; 5];
+        let n = (data.len() - 3).min(5);
+        vendor[..n].copy_from_slice(&data[3..3 + n]);
+
+        if let Some(callback) = self.emergency_callback.as_mut() {
+            callback(node_id, eec, data[2], vendor);
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::cell::RefCell;
     use std::collections::hash_map::DefaultHasher;
     use std::hash::{Hash, Hasher};
+    use std::rc::Rc;
+
+    use crate::test_util::{frame, new_node, sdo_read, sdo_write_u16, sdo_write_u32, send, take_tx};
+
+    #[test]
+    fn test_emergency_consumer() {
+        let mut node = new_node();
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let r = received.clone();
+        node.on_emergency(Box::new(move |nid, eec, er, vendor| {
+            r.borrow_mut().push((nid, eec, er, vendor));
+        }));
+
+        send(&mut node, frame(0x085, &[0x10, 0x82, 0x01, 1, 2, 3, 4, 5]));
+        send(&mut node, frame(0x085, &[0x34, 0x12, 0x11]));
+        send(&mut node, frame(0x085, &[0x00, 0x00, 0x00]));
+        assert_eq!(*received.borrow(), vec![
+            (5, EmergencyErrorCode::PdoNotProcessed, 0x01, [1, 2, 3, 4, 5]),
+            (5, EmergencyErrorCode::Unknown(0x1234), 0x11, [0; 5]),
+            (5, EmergencyErrorCode::Unknown(0), 0x00, [0; 5]),
+        ]);
+        let er = received.borrow()[1].2;
+        assert_ne!(er & ErrorRegister::GenericError.mask(), 0);
+        assert_ne!(er & ErrorRegister::CommunicationError.mask(), 0);
+        assert_eq!(er & ErrorRegister::Voltage.mask(), 0);
+    }
 
     #[test]
     fn test_emergency_error_code() {
@@ -115,6 +314,170 @@ mod tests {
         assert_eq!(EmergencyErrorCode::from_code(0xFFFF), None);
     }
 
+    #[test]
+    fn test_standard_emergency_error_codes() {
+        let codes = [
+            (EmergencyErrorCode::DeviceHardware, 0x5000),
+            (EmergencyErrorCode::InternalSoftware, 0x6100),
+            (EmergencyErrorCode::CanOverrun, 0x8110),
+            (EmergencyErrorCode::CanErrorPassive, 0x8120),
+            (EmergencyErrorCode::LifeGuardError, 0x8130),
+            (EmergencyErrorCode::RecoveredFromBusOff, 0x8140),
+            (EmergencyErrorCode::PdoNotProcessed, 0x8210),
+        ];
+        for (eec, code) in codes {
+            assert_eq!(eec.code(), code);
+            assert_eq!(EmergencyErrorCode::from_code(code), Some(eec));
+        }
+        assert_eq!(EmergencyErrorCode::Unknown(0x1234).code(), 0x1234);
+    }
+
+    #[test]
+    fn test_clear_emergency() {
+        let mut node = new_node();
+        node.emit_emergency(EmergencyErrorCode::CanOverrun, ErrorRegister::CommunicationError, &[0; 5]).unwrap();
+        node.emit_emergency(EmergencyErrorCode::DeviceHardware, ErrorRegister::GenericError, &[0; 5]).unwrap();
+        take_tx(&mut node);
+        assert_eq!(sdo_read(&mut node, 0x1003, 0)[4], 2);
+
+        node.clear_emergency().unwrap();
+        let tx = take_tx(&mut node);
+        assert_eq!(tx.len(), 1);
+        assert_eq!(tx[0].cob_id(), 0x082);
+        assert_eq!(tx[0].data(), &[0; 8]);
+        assert_eq!(sdo_read(&mut node, 0x1003, 0)[4], 0);
+        assert_eq!(sdo_read(&mut node, 0x1001, 0)[4], 0);
+    }
+
+    #[test]
+    fn test_error_state_accessors() {
+        let mut node = new_node();
+        assert_eq!((node.error_register(), node.active_error_count()), (0, 0));
+        assert!(node.emergency_history().is_empty());
+
+        node.emit_emergency(EmergencyErrorCode::CanOverrun, ErrorRegister::CommunicationError, &[0; 5]).unwrap();
+        node.emit_emergency(EmergencyErrorCode::DeviceHardware, ErrorRegister::Voltage, &[0; 5]).unwrap();
+        take_tx(&mut node);
+        assert_eq!(node.error_register(), 0x04);
+        assert_eq!(node.active_error_count(), 2);
+        assert_eq!(node.emergency_history(), vec![(EMCY_DEVICE_HARDWARE, 0x04), (EMCY_CAN_OVERRUN, 0x10)]);
+        assert_eq!(sdo_read(&mut node, 0x1003, 1)[4..8], [0x00, 0x50, 0x04, 0]);
+
+        // The generic error is bit 0, not an empty register.
+        node.emit_emergency(EmergencyErrorCode::DeviceHardware, ErrorRegister::GenericError, &[0; 5]).unwrap();
+        assert_eq!(node.error_register(), 0x01);
+        assert_eq!(take_tx(&mut node)[0].data()[2], 0x01);
+
+        node.clear_emergency().unwrap();
+        assert_eq!((node.error_register(), node.active_error_count()), (0, 0));
+        assert!(node.emergency_history().is_empty());
+    }
+
+    #[test]
+    fn test_error_count_limited_by_error_field() {
+        let mut node = new_node();
+        for _ in 0..10 {
+            node.emit_emergency(EmergencyErrorCode::CanOverrun, ErrorRegister::CommunicationError, &[0; 5]).unwrap();
+        }
+        take_tx(&mut node);
+        assert_eq!(node.error_count, 8);
+        assert_eq!(sdo_read(&mut node, 0x1003, 0)[4], 8);
+    }
+
+    #[test]
+    fn test_error_field_rolls() {
+        let mut node = new_node();
+        let codes = [EmergencyErrorCode::CanOverrun, EmergencyErrorCode::DeviceHardware,
+            EmergencyErrorCode::InternalSoftware];
+        for code in codes {
+            node.emit_emergency(code, ErrorRegister::GenericError, &[0; 5]).unwrap();
+        }
+        take_tx(&mut node);
+        assert_eq!(sdo_read(&mut node, 0x1003, 0)[4], 3);
+        assert_eq!(sdo_read(&mut node, 0x1003, 1)[4..6], [0x00, 0x61]);
+        assert_eq!(sdo_read(&mut node, 0x1003, 2)[4..6], [0x00, 0x50]);
+        assert_eq!(sdo_read(&mut node, 0x1003, 3)[4..6], [0x10, 0x81]);
+
+        // Once full, the oldest entry is dropped.
+        for _ in 0..7 {
+            node.emit_emergency(EmergencyErrorCode::CanErrorPassive, ErrorRegister::GenericError, &[0; 5]).unwrap();
+        }
+        take_tx(&mut node);
+        assert_eq!(sdo_read(&mut node, 0x1003, 0)[4], 8);
+        assert_eq!(sdo_read(&mut node, 0x1003, 8)[4..6], [0x00, 0x61]);
+    }
+
+    #[test]
+    fn test_emit_emergency() {
+        let mut node = new_node();
+        node.emit_emergency(EmergencyErrorCode::CanOverrun, ErrorRegister::CommunicationError,
+                            &[1, 2, 3, 4, 5]).unwrap();
+        // Only the error itself, the error reset is up to clear_emergency().
+        let tx = take_tx(&mut node);
+        assert_eq!(tx.len(), 1);
+        assert_eq!(tx[0].cob_id(), 0x082);
+        assert_eq!(tx[0].data(), &[0x10, 0x81, 0x10, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_configured_emcy_cob_id() {
+        let mut node = new_node();
+        assert_eq!(sdo_write_u32(&mut node, 0x1014, 0, 0xA5)[0], 0x60);
+        node.emit_emergency(EmergencyErrorCode::CanOverrun, ErrorRegister::CommunicationError, &[0; 5]).unwrap();
+        let tx = take_tx(&mut node);
+        assert!(!tx.is_empty());
+        assert!(tx.iter().all(|f| f.cob_id() == 0xA5));
+
+        // An invalid COB-ID silences EMCY, the error is still recorded.
+        assert_eq!(sdo_write_u32(&mut node, 0x1014, 0, 0x8000_00A5)[0], 0x60);
+        node.emit_emergency(EmergencyErrorCode::DeviceHardware, ErrorRegister::GenericError, &[0; 5]).unwrap();
+        assert!(take_tx(&mut node).is_empty());
+        assert_eq!(sdo_read(&mut node, 0x1003, 0)[4], 2);
+    }
+
+    #[test]
+    fn test_emcy_inhibit_time() {
+        let mut node = new_node();
+        // 5ms, in units of 100us.
+        assert_eq!(sdo_write_u16(&mut node, 0x1015, 0, 50)[0], 0x60);
+        node.emit_emergency(EmergencyErrorCode::CanOverrun, ErrorRegister::CommunicationError, &[0; 5]).unwrap();
+        assert!(!take_tx(&mut node).is_empty());
+
+        node.emit_emergency(EmergencyErrorCode::DeviceHardware, ErrorRegister::GenericError, &[0; 5]).unwrap();
+        assert!(take_tx(&mut node).is_empty());
+
+        for _ in 0..5 {
+            node.event_timer_callback();
+        }
+        take_tx(&mut node);
+        node.emit_emergency(EmergencyErrorCode::DeviceHardware, ErrorRegister::GenericError, &[0; 5]).unwrap();
+        assert_eq!(take_tx(&mut node)[0].data()[0..2], [0x00, 0x50]);
+
+        // The error reset waits for the inhibit time instead of being dropped.
+        node.clear_emergency().unwrap();
+        for _ in 0..4 {
+            node.event_timer_callback();
+        }
+        assert!(take_tx(&mut node).is_empty());
+        node.event_timer_callback();
+        let tx = take_tx(&mut node);
+        assert_eq!(tx.len(), 1);
+        assert_eq!(tx[0].data(), &[0; 8]);
+
+        // Unless a new error comes first.
+        for _ in 0..5 {
+            node.event_timer_callback();
+        }
+        node.emit_emergency(EmergencyErrorCode::CanOverrun, ErrorRegister::CommunicationError, &[0; 5]).unwrap();
+        node.clear_emergency().unwrap();
+        node.emit_emergency(EmergencyErrorCode::DeviceHardware, ErrorRegister::GenericError, &[0; 5]).unwrap();
+        take_tx(&mut node);
+        for _ in 0..10 {
+            node.event_timer_callback();
+        }
+        assert!(take_tx(&mut node).is_empty());
+    }
+
     #[test]
     fn test_error_register() {
         assert_eq!(ErrorRegister::GenericError.code(), 0);
@@ -125,6 +488,9 @@ mod tests {
         assert_eq!(ErrorRegister::DeviceProfileSpecific.code(), 5);
         assert_eq!(ErrorRegister::Reserved.code(), 6);
         assert_eq!(ErrorRegister::ManufacturerSpecific.code(), 7);
+        assert_eq!(ErrorRegister::GenericError.mask(), 0x01);
+        assert_eq!(ErrorRegister::CommunicationError.mask(), 0x10);
+        assert_eq!(ErrorRegister::ManufacturerSpecific.mask(), 0x80);
 
         assert_eq!(ErrorRegister::from_code(0), Some(ErrorRegister::GenericError));
         assert_eq!(ErrorRegister::from_code(1), Some(ErrorRegister::Current));
@@ -143,6 +509,7 @@ mod tests {
         assert_eq!(format!("{:?}", error), "GenericError");
     }
     #[test]
+    #[allow(clippy::clone_on_copy)]
     fn test_error_register_copy_clone() {
         let error = ErrorRegister::GenericError;
         let error_copy = error;
@@ -170,6 +537,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::clone_on_copy)]
     fn test_emergency_error_code_clone() {
         let error = EmergencyErrorCode::PdoNotProcessed;
         let error_clone = error.clone();
@@ -11,9 +11,12 @@ pub enum ErrorCode {
     NoCobIdInRpdo { cob_id: u16 },
     StringToValueFailed { data_type: DataType, str: String },
     ProcesedSectionFailed { section_name: String, more_info: String },
+    TruncatedEds { section_name: String },
     AbortCodeWrapper { abort_code: AbortCode, more_info: String },
     NoPdoObjectInIndex { index: usize },
     VariableNotFound {index: u16, sub_index: u8},
+    InvalidNodeId { node_id: u8 },
+    ObjectDirectoryFull { capacity: usize },
     LegacyError { str: String },
 }
 
@@ -29,16 +32,30 @@ impl Debug for ErrorCode {
             ErrorCode::ProcesedSectionFailed { section_name, more_info } =>
                 write!(f, "Processed section failed, section_name: {:?}, more info: {:?}",
                 section_name, more_info),
+            ErrorCode::TruncatedEds { section_name } =>
+                write!(f, "EDS content ends in the incomplete section {:?}", section_name),
             ErrorCode::AbortCodeWrapper { abort_code, more_info } => write!(f,
                 "Got Canopen abort code: {:x?}, and more information: {:?}", abort_code, more_info),
             ErrorCode::NoCobIdInFrame => write!(f, "No cob id"),
             ErrorCode::NoCobIdInRpdo { cob_id } => write!(f, "No cob id ({:x?}) in Rpdo", cob_id),
             ErrorCode::NoPdoObjectInIndex { index } => write!(f, "No index({}) in pdo object", index),
             ErrorCode::VariableNotFound { index, sub_index } => write!(f, "Not variable on ({:x?}, {:x?}", index, sub_index),
+            ErrorCode::InvalidNodeId { node_id } => write!(f, "Invalid node-id: {}", node_id),
+            ErrorCode::ObjectDirectoryFull { capacity } =>
+                write!(f, "Object directory is full, capacity: {} objects", capacity),
         }
     }
 }
 
+/// Same text as `Debug`, for logs using `{}`.
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Debug::fmt(self, f)
+    }
+}
+
+impl core::error::Error for ErrorCode {}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum AbortCode {
     ToggleBitNotAlternated,
@@ -148,8 +165,8 @@ impl AbortCode {
         }
     }
 
-    #[allow(dead_code)]
-    pub(crate) fn from_code(code: u32) -> Option<Self> {
+    /// The abort code for a wire code, `None` for codes not listed in CiA 301.
+    pub fn from_code(code: u32) -> Option<Self> {
         match code {
             0x0503_0000 => Some(AbortCode::ToggleBitNotAlternated),
             0x0504_0000 => Some(AbortCode::SdoProtocolTimedOut),
@@ -183,12 +200,70 @@ impl AbortCode {
             _ => None,
         }
     }
+
+    /// Decodes the 4 little-endian bytes of an abort frame (bytes 4..8),
+    /// `None` if they aren't exactly 4 bytes or an unknown code.
+    pub fn from_wire(bytes: &[u8]) -> Option<Self> {
+        let bytes: [u8; 4] = bytes.try_into().ok()?;
+        Self::from_code(u32::from_le_bytes(bytes))
+    }
 }
 
+/// The abort code to answer an SDO request with when handling it failed:
+/// the wrapped code of `AbortCodeWrapper`, the closest code for a few other
+/// errors, and `GeneralError` for the rest.
+impl From<ErrorCode> for AbortCode {
+    fn from(err: ErrorCode) -> Self {
+        match err {
+            ErrorCode::AbortCodeWrapper { abort_code, .. } => abort_code,
+            ErrorCode::VariableNotFound { .. } => AbortCode::ObjectDoesNotExistInObjectDictionary,
+            ErrorCode::ObjectDirectoryFull { .. } => AbortCode::OutOfMemory,
+            _ => AbortCode::GeneralError,
+        }
+    }
+}
+
+/// The wire code and its description, e.g. "0x08000000: General error".
+impl fmt::Display for AbortCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:08X}: {}", self.code(), self.description())
+    }
+}
+
+impl core::error::Error for AbortCode {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_abort_code_from_error_code() {
+        let wrapped = ErrorCode::AbortCodeWrapper {
+            abort_code: AbortCode::SubIndexDoesNotExist,
+            more_info: "".to_string(),
+        };
+        assert_eq!(AbortCode::from(wrapped), AbortCode::SubIndexDoesNotExist);
+        assert_eq!(AbortCode::from(ErrorCode::VariableNotFound { index: 0x2000, sub_index: 1 }),
+                   AbortCode::ObjectDoesNotExistInObjectDictionary);
+        assert_eq!(AbortCode::from(ErrorCode::ObjectDirectoryFull { capacity: 256 }), AbortCode::OutOfMemory);
+        assert_eq!(AbortCode::from(ErrorCode::ByteLengthExceedsLimit), AbortCode::GeneralError);
+        assert_eq!(AbortCode::from(ErrorCode::LegacyError { str: "x".to_string() }), AbortCode::GeneralError);
+
+        let to_abort = |res: Result<(), ErrorCode>| -> Result<(), AbortCode> { Ok(res?) };
+        assert_eq!(to_abort(Err(ErrorCode::NoCobIdInFrame)), Err(AbortCode::GeneralError));
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(format!("{}", AbortCode::GeneralError), "0x08000000: General error");
+        assert_eq!(AbortCode::SubIndexDoesNotExist.to_string(), "0x06090011: Sub-index does not exist");
+        assert_eq!(format!("{}", ErrorCode::InvalidStandardId { cob_id: 0x123 }), "Invalid Standard ID: 291");
+        assert_eq!(format!("{}", ErrorCode::NoCobIdInFrame), format!("{:?}", ErrorCode::NoCobIdInFrame));
+
+        let err: &dyn core::error::Error = &AbortCode::CRCError;
+        assert_eq!(err.to_string(), "0x05040004: CRC error (block mode only)");
+    }
+
     #[test]
     fn test_error_code_debug() {
         assert_eq!(format!("{:?}", ErrorCode::ByteLengthExceedsLimit), "Byte length exceeds limit");
@@ -361,4 +436,12 @@ mod tests {
 
         assert_eq!(AbortCode::from_code(0xFFFFFFFF), None);
     }
+
+    #[test]
+    fn test_from_wire() {
+        assert_eq!(AbortCode::from_wire(&[0x02, 0, 0x01, 0x06]), Some(AbortCode::AttemptToWriteReadOnlyObject));
+        assert_eq!(AbortCode::from_wire(&AbortCode::CRCError.code().to_le_bytes()), Some(AbortCode::CRCError));
+        assert_eq!(AbortCode::from_wire(&[0xFF; 4]), None);
+        assert_eq!(AbortCode::from_wire(&[0x02, 0, 0x01]), None);
+    }
 }
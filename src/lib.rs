@@ -13,9 +13,18 @@ pub mod object_directory;
 pub mod util;
 pub mod value;
 pub mod pdo;
+pub mod emergency;
+pub mod sdo_client;
+pub mod lss;
+#[cfg(feature = "async")]
+pub mod async_node;
 
 mod cmd_header;
 mod prelude;
 mod sdo_server;
-mod emergency;
+mod sync;
 mod constant;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+#[cfg(test)]
+mod byte_order_tests;
@@ -0,0 +1,190 @@
+use core::fmt::Debug;
+
+use embedded_can::Frame;
+use embedded_can::nb::Can;
+
+use crate::constant::{COB_ID_LSS_SLAVE, REG_IDENTITY};
+use crate::error;
+use crate::node::Node;
+use crate::prelude::*;
+use crate::util::{check_node_id, create_frame_with_padding};
+
+// LSS command specifiers (CiA 305).
+const LSS_SWITCH_STATE_GLOBAL: u8 = 0x04;
+const LSS_CONFIGURE_NODE_ID: u8 = 0x11;
+const LSS_STORE_CONFIGURATION: u8 = 0x17;
+const LSS_SWITCH_SELECTIVE_VENDOR: u8 = 0x40;
+const LSS_SWITCH_SELECTIVE_SERIAL: u8 = 0x43;
+const LSS_SWITCH_SELECTIVE_RESPONSE: u8 = 0x44;
+
+pub use crate::constant::LSS_UNCONFIGURED_NODE_ID;
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum LssState {
+    Waiting,
+    Configuration,
+}
+
+impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
+    pub fn lss_state(&self) -> LssState {
+        self.lss_state
+    }
+
+    pub(crate) fn process_lss_frame(&mut self, frame: &CAN::Frame) {
+        let data = frame.data();
+        if data.len() < 2 {
+            return;
+        }
+        match data[0] {
+            LSS_SWITCH_STATE_GLOBAL => {
+                self.lss_state = if data[1] == 1 { LssState::Configuration } else { LssState::Waiting };
+                self.lss_selection = 0;
+            }
+            LSS_SWITCH_SELECTIVE_VENDOR..=LSS_SWITCH_SELECTIVE_SERIAL => self.lss_switch_selective(data),
+            LSS_CONFIGURE_NODE_ID if self.lss_state == LssState::Configuration => {
+                let node_id = data[1];
+                if node_id == LSS_UNCONFIGURED_NODE_ID {
+                    // The `$NODEID` based values are left as they are, `$NODEID`
+                    // can't be evaluated with 0xFF. The node only serves LSS
+                    // until it gets a node-id again.
+                    self.node_id = node_id;
+                    self.lss_respond(&[LSS_CONFIGURE_NODE_ID, 0]);
+                } else if check_node_id(node_id).is_ok() {
+                    if let Err(err) = self.set_node_id(node_id) {
+                        error!("Errors in changing the node-id to {}: {:?}", node_id, err);
+                    }
+                    self.lss_respond(&[LSS_CONFIGURE_NODE_ID, 0]);
+                } else {
+                    self.lss_respond(&[LSS_CONFIGURE_NODE_ID, 1]);
+                }
+            }
+            LSS_STORE_CONFIGURATION if self.lss_state == LssState::Configuration => {
+                // The derived COB-IDs survive NMT resets from now on.
+                self.save_communication();
+                self.lss_respond(&[LSS_STORE_CONFIGURATION, 0]);
+            }
+            _ => {}
+        }
+    }
+
+    /// Vendor-id, product code, revision and serial number have to match 0x1018
+    /// in this order, then the node switches to the configuration state.
+    fn lss_switch_selective(&mut self, data: &[u8]) {
+        if data.len() < 5 {
+            return;
+        }
+        let step = data[0] - LSS_SWITCH_SELECTIVE_VENDOR;
+        let value = u32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+        let expected = self.object_directory.get_variable(REG_IDENTITY, step + 1)
+            .and_then(|var| var.effective_value().try_to::<u32>());
+        if step != self.lss_selection || expected != Ok(value) {
+            self.lss_selection = 0;
+            return;
+        }
+        self.lss_selection += 1;
+        if data[0] == LSS_SWITCH_SELECTIVE_SERIAL {
+            self.lss_selection = 0;
+            self.lss_state = LssState::Configuration;
+            self.lss_respond(&[LSS_SWITCH_SELECTIVE_RESPONSE]);
+        }
+    }
+
+    fn lss_respond(&mut self, data: &[u8]) {
+        match create_frame_with_padding(COB_ID_LSS_SLAVE, data) {
+            Ok(frame) => self.transmit(&frame),
+            Err(err) => { error!("Errors in creating LSS frame: {:?}", err); }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_can::Frame;
+
+    use crate::node::NodeState;
+    use crate::test_util::{frame, new_node, sdo_read, send, take_tx};
+
+    use super::*;
+
+    fn lss(node: &mut Node<crate::test_util::MockCan>, data: &[u8]) -> Vec<Vec<u8>> {
+        let mut req = data.to_vec();
+        req.resize(8, 0);
+        send(node, frame(0x7E5, &req)).iter()
+            .filter(|f| f.cob_id() == 0x7E4)
+            .map(|f| f.data().to_vec())
+            .collect()
+    }
+
+    fn selective(value: u32, cs: u8) -> Vec<u8> {
+        let mut data = vec![cs];
+        data.extend_from_slice(&value.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_selective_switch_and_configure_node_id() {
+        let mut node = new_node();
+        assert_eq!(node.lss_state(), LssState::Waiting);
+        // Not in the configuration state yet.
+        assert!(lss(&mut node, &[0x11, 5]).is_empty());
+
+        assert!(lss(&mut node, &selective(0xABCD, 0x40)).is_empty());
+        assert!(lss(&mut node, &selective(0x1234, 0x41)).is_empty());
+        assert!(lss(&mut node, &selective(0x0001_0001, 0x42)).is_empty());
+        assert_eq!(lss(&mut node, &selective(0x42, 0x43)), vec![vec![0x44, 0, 0, 0, 0, 0, 0, 0]]);
+        assert_eq!(node.lss_state(), LssState::Configuration);
+
+        assert_eq!(lss(&mut node, &[0x11, 200]), vec![vec![0x11, 1, 0, 0, 0, 0, 0, 0]]);
+        assert_eq!(lss(&mut node, &[0x11, 5]), vec![vec![0x11, 0, 0, 0, 0, 0, 0, 0]]);
+        assert_eq!(lss(&mut node, &[0x17]), vec![vec![0x17, 0, 0, 0, 0, 0, 0, 0]]);
+        lss(&mut node, &[0x04, 0]);
+        assert_eq!(node.lss_state(), LssState::Waiting);
+
+        // SDO and PDO COB-IDs follow the new node-id.
+        assert_eq!(node.node_id, 5);
+        assert_eq!(sdo_read(&mut node, 0x1800, 1)[4..8], [0x85, 0x01, 0, 0]);
+        assert_eq!(sdo_read(&mut node, 0x1400, 1)[4..8], [0x05, 0x02, 0, 0]);
+    }
+
+    #[test]
+    fn test_configure_unconfigured_node_id() {
+        let mut node = new_node();
+        node.set_heartbeat_period(10).unwrap();
+        lss(&mut node, &[0x04, 1]);
+        assert_eq!(lss(&mut node, &[0x11, 0xFF]), vec![vec![0x11, 0, 0, 0, 0, 0, 0, 0]]);
+        assert!(node.is_unconfigured());
+
+        // The PDO COB-IDs are not derived from 0xFF.
+        assert_eq!(node.pdo_cob_id(0x1800), Some(0x182));
+        assert_eq!(node.pdo_cob_id(0x1400), Some(0x202));
+
+        // Neither NMT nor SDO nor the timers do anything.
+        send(&mut node, frame(0x000, &[0x01, 0]));
+        assert_eq!(node.state(), NodeState::PreOperational);
+        assert!(send(&mut node, frame(0x6FF, &[0x40, 0x00, 0x10, 0, 0, 0, 0, 0])).is_empty());
+        for _ in 0..20 {
+            node.timer_tick(1);
+        }
+        assert!(take_tx(&mut node).is_empty());
+
+        // Back in business with a node-id.
+        assert_eq!(lss(&mut node, &[0x11, 5]), vec![vec![0x11, 0, 0, 0, 0, 0, 0, 0]]);
+        assert!(!node.is_unconfigured());
+        assert_eq!(node.pdo_cob_id(0x1800), Some(0x185));
+        assert_eq!(sdo_read(&mut node, 0x1000, 0)[0], 0x43);
+    }
+
+    #[test]
+    fn test_selective_switch_mismatch() {
+        let mut node = new_node();
+        lss(&mut node, &selective(0xABCD, 0x40));
+        // Wrong product code resets the selection.
+        lss(&mut node, &selective(0x9999, 0x41));
+        lss(&mut node, &selective(0x0001_0001, 0x42));
+        assert!(lss(&mut node, &selective(0x42, 0x43)).is_empty());
+        assert_eq!(node.lss_state(), LssState::Waiting);
+
+        lss(&mut node, &[0x04, 1]);
+        assert_eq!(node.lss_state(), LssState::Configuration);
+    }
+}
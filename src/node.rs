@@ -1,17 +1,21 @@
+use alloc::boxed::Box;
 use core::ops::Range;
 
 use embedded_can::{Frame, nb::Can};
 
-use crate::{error, info};
-use crate::constant::{ALL_REGISTERS_RANGE, APPLICATION_REGISTERS_RANGE, COB_FUNC_MASK, COB_FUNC_NMT, COB_FUNC_RECEIVE_SDO, COB_FUNC_RPDO_0, COB_FUNC_RPDO_3, COB_FUNC_SYNC, COMMUNICATION_REGISTERS_RANGE};
-use crate::emergency::{EmergencyErrorCode, ErrorRegister};
+use crate::{error, info, warn};
+use crate::constant::{ALL_REGISTERS_RANGE, APPLICATION_REGISTERS_RANGE, COB_FUNC_HEARTBEAT, COB_FUNC_MASK, COB_FUNC_NMT, COB_ID_LSS_MASTER, COB_ID_TIME, COB_FUNC_RECEIVE_SDO, COB_FUNC_RPDO_3, COB_FUNC_SYNC, COB_FUNC_TPDO_0, COB_FUNC_TRANSMIT_SDO, COB_ID_LSS_SLAVE, COMMUNICATION_REGISTERS_RANGE, LSS_UNCONFIGURED_NODE_ID, REG_CONSUMER_HEARTBEAT_TIME, REG_IDENTITY, REG_PRODUCER_HEARTBEAT_TIME};
+use crate::emergency::{EmergencyCallback, EmergencyErrorCode, ErrorRegister};
+use crate::error::AbortCode::GeneralError;
 use crate::error::ErrorCode;
+use crate::lss::LssState;
 use crate::object_directory::ObjectDirectory;
-use crate::pdo::PdoObjects;
+use crate::pdo::{PdoErrorCallback, PdoObjects, RpdoCallback};
 use crate::prelude::*;
 use crate::sdo_server::SdoState;
 use crate::sdo_server::SdoState::Normal;
-use crate::util::{create_frame, get_cob_id};
+use crate::util::{check_node_id, copy_frame, create_frame, get_cob_id, make_abort_error};
+use crate::value::Value;
 
 const DEFAULT_BLOCK_SIZE: u8 = 0x7F;
 
@@ -34,6 +38,49 @@ impl NodeState {
     }
 }
 
+/// What the node does with frames outside of the CiA 301 predefined connection
+/// set which it doesn't process either, e.g. on 0x7FF.
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub enum UnknownFramePolicy {
+    #[default]
+    Ignore,
+    /// Logs a warning for each of them.
+    Log,
+    /// Counts them, see `Node::unknown_frame_count()`.
+    Count,
+}
+
+/// Called with (old state, new state) whenever the NMT state changes.
+pub type StateChangeCallback = Box<dyn FnMut(NodeState, NodeState)>;
+
+/// Sees every frame the node receives or sends, e.g. to keep a frame log in a
+/// ring buffer independently of the logging macros.
+pub trait FrameTracer<F> {
+    fn on_rx(&mut self, frame: &F);
+    fn on_tx(&mut self, frame: &F);
+}
+
+/// Whether `cob_id` belongs to the CiA 301 predefined connection set (of any node) or LSS.
+fn is_predefined_cob_id(cob_id: u16) -> bool {
+    matches!(cob_id, COB_FUNC_NMT | COB_ID_TIME | COB_ID_LSS_SLAVE | COB_ID_LSS_MASTER)
+        || matches!(cob_id & COB_FUNC_MASK,
+                    COB_FUNC_SYNC | COB_FUNC_TPDO_0..=COB_FUNC_RPDO_3 | COB_FUNC_TRANSMIT_SDO | COB_FUNC_RECEIVE_SDO
+                    | COB_FUNC_HEARTBEAT)
+}
+
+/// The state an NMT command `cs` leads to from `state`, `None` if CiA 301
+/// doesn't allow it. Init is only left by the boot-up, resets work from anywhere.
+fn nmt_transition(state: NodeState, cs: u8) -> Option<NodeState> {
+    match (state, cs) {
+        (_, NODE_RESET | NODE_RESET_COMMUNICATION) => Some(NodeState::Init),
+        (NodeState::Init, _) => None,
+        (_, NODE_START) => Some(NodeState::Operational),
+        (_, NODE_STOP) => Some(NodeState::Stopped),
+        (_, NODE_PRE_OPERATE) => Some(NodeState::PreOperational),
+        _ => None,
+    }
+}
+
 // Node commands:
 const NODE_START: u8 = 0x1;
 // To operational
@@ -43,6 +90,15 @@ const NODE_PRE_OPERATE: u8 = 0x80;
 const NODE_RESET: u8 = 0x81;
 const NODE_RESET_COMMUNICATION: u8 = 0x82;
 
+/// The identity object (0x1018), a missing sub-index reads as 0.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct Identity {
+    pub vendor_id: u32,
+    pub product_code: u32,
+    pub revision: u32,
+    pub serial: u32,
+}
+
 #[derive(PartialEq, Clone, Copy, Debug)]
 pub enum NodeEvent {
     RegularTimerEvent = 1,
@@ -68,7 +124,8 @@ pub struct Node<CAN> where CAN: Can, CAN::Frame: Frame + Debug {
     pub(crate) node_id: u8,
     pub(crate) can_network: CAN,
     pub(crate) object_directory: ObjectDirectory,
-    backup_od: ObjectDirectory,
+    // The values the last 0x1010 save captured where they differ from the EDS.
+    saved_values: HashMap<(u16, u8), Value>,
     pub(crate) pdo_objects: PdoObjects,
 
     // SDO specific data below:
@@ -77,67 +134,298 @@ pub struct Node<CAN> where CAN: Can, CAN::Frame: Frame + Debug {
     pub(crate) read_buf_index: usize,
     pub(crate) next_read_toggle: u8,
     pub(crate) write_buf: Option<Vec<u8>>,
+    pub(crate) next_write_toggle: u8,
     pub(crate) reserved_index: u16,
     pub(crate) reserved_sub_index: u8,
     pub(crate) write_data_size: usize,
     pub(crate) need_crc: bool,
     pub(crate) block_size: u8,
+    // Block size the server asks for in block downloads, see set_block_size().
+    pub(crate) download_block_size: u8,
     // sequences_per_block?
     pub(crate) current_seq_number: u8,
     pub(crate) crc_enabled: bool,
 
     pub(crate) sync_count: u32,
-    pub(crate) event_count: u32,
+    // SYNC producer / consumer counters (0x1019), 0 when no counter is in use.
+    pub(crate) sync_producer_counter: u8,
+    pub(crate) sync_producer_timer: u32,
+    pub(crate) sync_consumer_counter: u8,
+    // Microseconds since the last SYNC, advanced by the 1ms timer; None before the first SYNC.
+    pub(crate) sync_elapsed_us: Option<u32>,
     pub(crate) state: NodeState,
     pub(crate) error_count: u8,
+    // Milliseconds since the last heartbeat.
     pub(crate) heartbeats: u32,
     pub(crate) heartbeats_timer: u32,
+    // Per-object NMT states in which SDO writes are accepted.
+    pub(crate) write_state_policies: HashMap<u16, Vec<NodeState>>,
+    pub(crate) emergency_callback: Option<EmergencyCallback>,
+    // Time left until the next EMCY may be sent, armed with 0x1015 (100us units) on each EMCY.
+    pub(crate) emcy_inhibit_remaining_us: u32,
+    // An error reset EMCY waiting for the inhibit time to run out.
+    pub(crate) emcy_reset_pending: bool,
+    state_change_callback: Option<StateChangeCallback>,
+    pub(crate) rpdo_callback: Option<RpdoCallback>,
+    pub(crate) pdo_error_callback: Option<PdoErrorCallback>,
+    pub(crate) lss_state: LssState,
+    // Number of 0x1018 fields matched so far by a LSS selective switch.
+    pub(crate) lss_selection: u8,
+    // Inclusive COB-ID ranges accepted by process_one_frame().
+    pub(crate) acceptance_filter: Vec<(u16, u16)>,
+    // Collects the transmitted frames instead of sending them while handle_frame() runs.
+    captured_frames: Option<Vec<CAN::Frame>>,
+    tracer: Option<Box<dyn FrameTracer<CAN::Frame>>>,
+    unknown_frame_policy: UnknownFramePolicy,
+    unknown_frame_count: u32,
+    // Whether init() sends the informal 0x234 "ready" frame before the boot-up.
+    send_ready_frame: bool,
+    // Replaces 0x580 + node-id as COB-ID of the SDO responses.
+    pub(crate) sdo_response_cob_id: Option<u16>,
 }
 
 impl<CAN> Node<CAN> where CAN: Can, CAN::Frame: Frame + Debug {
+    /// Creates a node from EDS content. Fails with `InvalidNodeId` for node-ids
+    /// outside 1..=127, the LSS unconfigured node-id 0xFF included.
+    ///
+    /// The node starts in Init, where it ignores every NMT command but the
+    /// resets: call `init()` to send the boot-up message and enter
+    /// Pre-operational before expecting it to follow an NMT start.
     pub fn new(
         node_id: u8,
         eds_content: &str,
         can_network: CAN,
     ) -> Result<Self, ErrorCode> {
         let object_directory = ObjectDirectory::new(node_id, eds_content)?;
-        let backup_od = object_directory.clone();
+        Self::from_object_directory(node_id, object_directory, can_network)
+    }
+
+    /// Creates a node from an object directory built in code, see `ObjectDirectory::empty()`.
+    /// The PDOs are derived as for an EDS, an inconsistent configuration fails with the
+    /// offending mapping parameter in the error, e.g. `ExceedPDOSize` for a 0x1Axx
+    /// mapping of more than 64 bits. Absent PDO objects are no error: a PDO without its
+    /// 0x14xx - 0x1Bxx objects stays disabled.
+    pub fn from_object_directory(
+        node_id: u8,
+        object_directory: ObjectDirectory,
+        can_network: CAN,
+    ) -> Result<Self, ErrorCode> {
+        check_node_id(node_id)?;
         let pdo_objects = PdoObjects::new();
         let mut node = Node {
             node_id,
             can_network,
             object_directory,
-            backup_od,
+            saved_values: HashMap::new(),
             pdo_objects,
             sdo_state: Normal,
             read_buf: None,
             read_buf_index: 0,
             write_buf: None,
+            next_write_toggle: 0,
             reserved_index: 0,
             reserved_sub_index: 0,
             write_data_size: 0,
             need_crc: false,
             block_size: DEFAULT_BLOCK_SIZE,
+            download_block_size: DEFAULT_BLOCK_SIZE,
             current_seq_number: 0,
             next_read_toggle: 0,
             crc_enabled: true,
             sync_count: 0,
-            event_count: 0,
+            sync_producer_counter: 0,
+            sync_producer_timer: 0,
+            sync_consumer_counter: 0,
+            sync_elapsed_us: None,
             state: NodeState::Init,
             error_count: 0,
             heartbeats: 0,
             heartbeats_timer: 0,
+            write_state_policies: HashMap::new(),
+            emergency_callback: None,
+            emcy_inhibit_remaining_us: 0,
+            emcy_reset_pending: false,
+            state_change_callback: None,
+            rpdo_callback: None,
+            pdo_error_callback: None,
+            lss_state: LssState::Waiting,
+            lss_selection: 0,
+            acceptance_filter: Vec::new(),
+            captured_frames: None,
+            tracer: None,
+            unknown_frame_policy: UnknownFramePolicy::Ignore,
+            unknown_frame_count: 0,
+            send_ready_frame: false,
+            sdo_response_cob_id: None,
         };
         node.update_pdo_params()?;
+        node.update_acceptance_filter();
+        for issue in node.pdo_mapping_issues() {
+            warn!("Inconsistent PDO mapping: {:x?}", issue);
+        }
         Ok(node)
     }
 
+    /// Same as `from_object_directory()`.
+    pub fn new_from_od(
+        node_id: u8,
+        object_directory: ObjectDirectory,
+        can_network: CAN,
+    ) -> Result<Self, ErrorCode> {
+        Self::from_object_directory(node_id, object_directory, can_network)
+    }
+
+    /// Changes the node-id: SDO / heartbeat / EMCY COB-IDs follow it directly,
+    /// `$NODEID` based defaults of the object directory are re-evaluated and the
+    /// PDO parameters are reloaded. Node-ids are checked as in `new()`.
+    pub fn set_node_id(&mut self, node_id: u8) -> Result<(), ErrorCode> {
+        check_node_id(node_id)?;
+        self.node_id = node_id;
+        self.object_directory.set_node_id(node_id);
+        self.rebuild_pdo_params()
+    }
+
+    /// Whether LSS set the node-id to the unconfigured 0xFF, the node then
+    /// ignores everything but LSS.
+    pub fn is_unconfigured(&self) -> bool {
+        self.node_id == LSS_UNCONFIGURED_NODE_ID
+    }
+
+    pub fn state(&self) -> NodeState {
+        self.state
+    }
+
+    /// Registers the callback invoked on every NMT state transition, replacing
+    /// the previous one.
+    pub fn on_state_change(&mut self, callback: StateChangeCallback) {
+        self.state_change_callback = Some(callback);
+    }
+
+    pub(crate) fn set_state(&mut self, state: NodeState) {
+        let old = self.state;
+        self.state = state;
+        if old != state {
+            if let Some(callback) = self.state_change_callback.as_mut() {
+                callback(old, state);
+            }
+        }
+    }
+
+    /// The heartbeat producer time (0x1017) in ms, 0 when no heartbeat is sent.
+    pub fn heartbeat_period(&self) -> u16 {
+        self.heartbeats_timer as u16
+    }
+
+    /// Sets the heartbeat producer time (0x1017) in ms, 0 stops the heartbeat.
+    pub fn set_heartbeat_period(&mut self, ms: u16) -> Result<(), ErrorCode> {
+        self.object_directory.set_value(REG_PRODUCER_HEARTBEAT_TIME, 0, &ms.to_le_bytes(), true)?;
+        self.restart_heartbeat(ms);
+        Ok(())
+    }
+
+    /// Applies a new heartbeat producer time, the period restarts from now.
+    pub(crate) fn restart_heartbeat(&mut self, ms: u16) {
+        self.heartbeats_timer = ms as u32;
+        self.heartbeats = 0;
+    }
+
+    /// The identity object (0x1018) of this node.
+    pub fn identity(&mut self) -> Identity {
+        let mut field = |sub_index| self.object_directory.get_variable(REG_IDENTITY, sub_index)
+            .and_then(|var| var.effective_value().try_to::<u32>())
+            .unwrap_or(0);
+        Identity { vendor_id: field(1), product_code: field(2), revision: field(3), serial: field(4) }
+    }
+
+    /// Starts staging the writes to object `index`, SDO writes included: they
+    /// only take effect on `commit_transaction()`, e.g. to remap a PDO without
+    /// sending it half-configured. Only one transaction can be open at a time.
+    pub fn begin_transaction(&mut self, index: u16) -> Result<(), ErrorCode> {
+        self.object_directory.begin_transaction(index)
+    }
+
+    /// Applies the staged writes at once and re-derives the PDOs from them.
+    /// A staged PDO mapping has to be consistent as a whole, otherwise nothing
+    /// is applied. Either way the transaction is closed.
+    pub fn commit_transaction(&mut self) -> Result<(), ErrorCode> {
+        let index = self.object_directory.staged_object().map(|(index, _)| index)
+            .ok_or(make_abort_error(GeneralError, "No open transaction".to_string()))?;
+        if matches!(index, 0x1600..=0x17FF | 0x1A00..=0x1BFF) {
+            if let Err(err) = self.validate_staged_pdo_mapping(index) {
+                self.object_directory.rollback();
+                return Err(err);
+            }
+        }
+        self.object_directory.commit()?;
+        if (0x1400..=0x1BFF).contains(&index) {
+            self.rebuild_pdo_params()?;
+        }
+        Ok(())
+    }
+
+    /// Drops the staged writes and closes the transaction, if any.
+    pub fn rollback_transaction(&mut self) {
+        self.object_directory.rollback();
+    }
+
+    /// Whether the writes to `index` are staged by an open transaction.
+    pub(crate) fn in_transaction(&self, index: u16) -> bool {
+        matches!(self.object_directory.staged_object(), Some((i, _)) if i == index)
+    }
+
+    /// The raw bytes of object `index` / `sub_index` as stored, see `Variable::raw_bytes()`.
+    pub fn object_raw(&mut self, index: u16, sub_index: u8) -> Result<Vec<u8>, ErrorCode> {
+        Ok(self.object_directory.get_variable(index, sub_index)?.raw_bytes().to_vec())
+    }
+
+    /// Installs the tracer of all received and sent frames, replacing the previous one.
+    pub fn set_tracer(&mut self, tracer: Box<dyn FrameTracer<CAN::Frame>>) {
+        self.tracer = Some(tracer);
+    }
+
+    pub fn set_unknown_frame_policy(&mut self, policy: UnknownFramePolicy) {
+        self.unknown_frame_policy = policy;
+    }
+
+    /// Makes `init()` send the non-standard frame 0x234 [1, 2, 3, 5] before the
+    /// boot-up message, for legacy test setups waiting for it. Off by default.
+    pub fn set_send_ready_frame(&mut self, enabled: bool) {
+        self.send_ready_frame = enabled;
+    }
+
+    /// Sends the SDO responses on `cob_id` instead of 0x580 + node-id, e.g. for
+    /// gateways relaying SDO traffic. `None` restores the default.
+    pub fn set_sdo_response_cob_id(&mut self, cob_id: Option<u16>) {
+        self.sdo_response_cob_id = cob_id;
+    }
+
+    /// Number of frames on unknown COB-IDs seen with `UnknownFramePolicy::Count`.
+    pub fn unknown_frame_count(&self) -> u32 {
+        self.unknown_frame_count
+    }
+
+    pub fn can_network(&mut self) -> &mut CAN {
+        &mut self.can_network
+    }
+
     pub fn pdo_objects(&mut self) -> &mut PdoObjects {
         &mut self.pdo_objects
     }
+
+    /// Restricts SDO writes to object `index` to the given NMT states, writes in
+    /// other states are aborted with `DataTransferOrStoreFailedDueToDeviceState`.
+    pub fn set_write_state_policy(&mut self, index: u16, allowed_states: &[NodeState]) {
+        self.write_state_policies.insert(index, allowed_states.to_vec());
+    }
+
+    pub fn clear_write_state_policy(&mut self, index: u16) {
+        self.write_state_policies.remove(&index);
+    }
 }
 
 impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
+    /// Derives the PDOs from the 0x1400..=0x1BFF objects present in the object
+    /// directory, only malformed ones are an error.
     pub(crate) fn update_pdo_params(&mut self) -> Result<(), ErrorCode> {
         // TODO(zephyr): don't hard-code here.
         for i in (0x1400..=0x1BFF).step_by(0x200) {
@@ -146,7 +434,7 @@ impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
 
                 if let Ok(var) = self.object_directory.get_variable(idx, 0) {
                     let var_clone = var.clone();
-                    let len: u8 = var_clone.default_value().to();
+                    let len: u8 = var_clone.effective_value().try_to()?;
                     for k in 1..=len {
                         if let Ok(sub_var) = self.object_directory.get_variable(idx, k) {
                             let sub_var_clone = sub_var.clone();
@@ -163,7 +451,7 @@ impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
                     if let Ok(var) = self.object_directory.get_variable(idx, k) {
                         let var_clone = var.clone();
                         self.update(&var_clone)?;
-                        if k == 0 { len = var_clone.default_value().to(); }
+                        if k == 0 { len = var_clone.effective_value().try_to()?; }
                     }
                     k += 1;
                 }
@@ -172,105 +460,218 @@ impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
         Ok(())
     }
 
-    pub(crate) fn filter_frame(&self, frame: &CAN::Frame) -> bool {
-        if let Some(cob_id) = get_cob_id(frame) {
-            if cob_id & 0x7F == self.node_id as u16 {
-                return false;
+    /// Re-derives the PDOs from the object directory from scratch, dropping the
+    /// RPDO COB-IDs of the previous configuration.
+    pub(crate) fn rebuild_pdo_params(&mut self) -> Result<(), ErrorCode> {
+        self.pdo_objects = PdoObjects::new();
+        self.update_pdo_params()?;
+        self.update_acceptance_filter();
+        Ok(())
+    }
+
+    /// Rebuilds the table of COB-IDs the node processes: NMT, SYNC / EMCY, TIME,
+    /// its own SDO, its enabled RPDOs, LSS and the heartbeats monitored in 0x1016.
+    pub(crate) fn update_acceptance_filter(&mut self) {
+        let mut filter = vec![
+            (COB_FUNC_NMT, COB_FUNC_NMT),
+            (COB_FUNC_SYNC, COB_FUNC_SYNC | 0x7F),
+            (COB_ID_TIME, COB_ID_TIME),
+            (COB_FUNC_RECEIVE_SDO | self.node_id as u16, COB_FUNC_RECEIVE_SDO | self.node_id as u16),
+            (COB_ID_LSS_MASTER, COB_ID_LSS_MASTER),
+        ];
+        for cob_id in self.pdo_objects.rpdo_cob_ids() {
+            filter.push((cob_id, cob_id));
+        }
+        let consumers = self.object_directory.get_variable(REG_CONSUMER_HEARTBEAT_TIME, 0)
+            .and_then(|var| var.effective_value().try_to::<u8>())
+            .unwrap_or(0);
+        for sub_index in 1..=consumers {
+            let entry: u32 = match self.object_directory.get_variable(REG_CONSUMER_HEARTBEAT_TIME, sub_index)
+                .and_then(|var| var.effective_value().try_to()) {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            // Bits 16..23 hold the monitored node-id, bits 0..15 the time (0: unused).
+            let node_id = ((entry >> 16) & 0x7F) as u16;
+            if node_id != 0 && entry & 0xFFFF != 0 {
+                filter.push((COB_FUNC_HEARTBEAT | node_id, COB_FUNC_HEARTBEAT | node_id));
             }
         }
-        true
+        self.acceptance_filter = filter;
     }
 
-    fn reset_object_directory_range(&mut self, range: Range<u16>, full_range: bool) -> bool {
-        let indexes_to_reset: Vec<u16> = if full_range {
-            self.object_directory.index_to_object.keys().cloned().collect()
-        } else {
-            self.object_directory.index_to_object
-                .keys()
-                .cloned()
-                .filter(|&index| range.contains(&index))
-                .collect()
-        };
+    /// The COB-ID ranges (inclusive) the node processes, other frames are dropped on receive.
+    pub fn acceptance_filter(&self) -> &[(u16, u16)] {
+        &self.acceptance_filter
+    }
 
-        // Replace the objects at these indexes
-        for index in indexes_to_reset {
-            // Remove the object from the current ObjectDirectory
-            self.object_directory.index_to_object.remove(&index);
+    pub fn accepts_cob_id(&self, cob_id: u16) -> bool {
+        self.acceptance_filter.iter().any(|&(first, last)| (first..=last).contains(&cob_id))
+    }
 
-            // If the backup contains the corresponding object, replace it
-            if let Some(backup_object) = self.backup_od.index_to_object.get(&index) {
-                self.object_directory.index_to_object.insert(index, backup_object.clone());
+    /// Restores EDS defaults for the range and drops the saved values of it,
+    /// as done by a 0x1011 restore. The PDOs follow the restored parameters.
+    fn reset_object_directory_range(&mut self, range: Range<u16>) -> bool {
+        self.object_directory.reset_values(&range);
+        self.saved_values.retain(|(index, _), _| !range.contains(index));
+        match self.apply_reloaded_object_directory() {
+            Ok(_) => true,
+            Err(ec) => {
+                error!("Errors in updating PDO params after a reset: {:?}", ec);
+                false
             }
         }
+    }
 
-        // Clean up the name_to_index mapping
-        self.object_directory.name_to_index.retain(|_name, &mut index| {
-            self.object_directory.index_to_object.contains_key(&index)
-        });
+    /// Loads the last saved values for the range, as done by an NMT reset.
+    fn reload_object_directory_range(&mut self, range: Range<u16>) -> Result<(), ErrorCode> {
+        self.object_directory.reset_values(&range);
+        self.object_directory.load_values(self.saved_values.iter().filter(|((index, _), _)| range.contains(index)));
+        self.apply_reloaded_object_directory()
+    }
+
+    /// Re-derives the state kept outside the object directory after it got
+    /// reloaded or restored: the heartbeat producer time (0x1017), the PDOs
+    /// and the acceptance filter.
+    fn apply_reloaded_object_directory(&mut self) -> Result<(), ErrorCode> {
+        let heartbeat: u16 = self.object_directory.get_variable(REG_PRODUCER_HEARTBEAT_TIME, 0)
+            .and_then(|var| var.effective_value().try_to())
+            .unwrap_or(0);
+        self.restart_heartbeat(heartbeat);
+        if let Err(ec) = self.rebuild_pdo_params() {
+            // The monitored heartbeats of 0x1016 are still taken over.
+            self.update_acceptance_filter();
+            return Err(ec);
+        }
+        Ok(())
+    }
+
+    /// Captures the current values of the range, as done by a 0x1010 save.
+    fn save_object_directory_range(&mut self, range: Range<u16>) -> bool {
+        let changed = self.object_directory.changed_values(&range);
+        self.saved_values.retain(|(index, _), _| !range.contains(index));
+        self.saved_values.extend(changed);
         true
     }
 
-    /// Rebuilds communication specific fields for the object directory.
-    pub(crate) fn reset_communication(&mut self) -> bool {
-        self.reset_object_directory_range(COMMUNICATION_REGISTERS_RANGE, false)
+    /// Reloads the communication parameters (0x1000..=0x1FFF) last saved with
+    /// 0x1010, or the EDS defaults, as an NMT reset communication does. The PDOs
+    /// follow the reloaded parameters.
+    pub fn reset_communication(&mut self) -> Result<(), ErrorCode> {
+        self.reload_object_directory_range(COMMUNICATION_REGISTERS_RANGE)
+    }
+
+    /// Reloads the application parameters (0x2000..=0x9FFF) last saved with
+    /// 0x1010, or the EDS defaults.
+    pub fn reset_application(&mut self) -> Result<(), ErrorCode> {
+        self.reload_object_directory_range(APPLICATION_REGISTERS_RANGE)
+    }
+
+    /// Restores the EDS defaults of the whole object directory, like a 0x1011 sub 1 restore.
+    pub(crate) fn restore_defaults(&mut self) -> bool {
+        self.reset_object_directory_range(ALL_REGISTERS_RANGE)
+    }
+
+    /// Restores the EDS defaults of the communication parameters, like a 0x1011 sub 2 restore.
+    pub(crate) fn restore_communication_defaults(&mut self) -> bool {
+        self.reset_object_directory_range(COMMUNICATION_REGISTERS_RANGE)
+    }
+
+    /// Restores the EDS defaults of the application parameters, like a 0x1011 sub 3 restore.
+    pub(crate) fn restore_application_defaults(&mut self) -> bool {
+        self.reset_object_directory_range(APPLICATION_REGISTERS_RANGE)
+    }
+
+    pub(crate) fn save_communication(&mut self) -> bool {
+        self.save_object_directory_range(COMMUNICATION_REGISTERS_RANGE)
     }
 
-    /// Rebuilds application specific fields for the object directory.
-    pub(crate) fn reset_application(&mut self) -> bool {
-        self.reset_object_directory_range(APPLICATION_REGISTERS_RANGE, false)
+    pub(crate) fn save_application(&mut self) -> bool {
+        self.save_object_directory_range(APPLICATION_REGISTERS_RANGE)
     }
 
-    /// Rebuilds the whole object directory
-    pub(crate) fn reset(&mut self) -> bool {
-        self.reset_object_directory_range(ALL_REGISTERS_RANGE, true)
+    pub(crate) fn save(&mut self) -> bool {
+        self.save_object_directory_range(ALL_REGISTERS_RANGE)
     }
 
     fn process_nmt_frame(&mut self, frame: &CAN::Frame) {
-        if frame.dlc() != 2 {
-            error!("NMT frame length should be 2");
-            return;
-        }
-        let (cs, nid) = (frame.data()[0], frame.data()[1]);
+        let (cs, nid) = match frame.data() {
+            &[cs, nid] => (cs, nid),
+            _ => {
+                error!("NMT frame length should be 2");
+                return;
+            }
+        };
         info!("process_nmt_frame 1: cs = {:#x}, nid = {}", cs, nid);
-        if nid != self.node_id {
+        // Node-id 0 addresses all nodes.
+        if nid != 0 && nid != self.node_id {
             return;
         }
+        let next = match nmt_transition(self.state, cs) {
+            Some(next) => next,
+            None => {
+                warn!("NMT command {:#x} is not allowed in state {:?}, ignored", cs, self.state);
+                return;
+            }
+        };
         match cs {
             NODE_START => {
                 info!("NMT: change state to OPERATIONAL");
-                self.state = NodeState::Operational;
+                self.set_state(next);
                 self.trigger_event(NodeEvent::NodeStart);
             }
-            NODE_STOP => if self.state != NodeState::Init {
-                info!("NMT: change state to STOPPED");
-                self.state = NodeState::Stopped;
-            },
-            NODE_PRE_OPERATE => {
-                info!("NMT: change state to PRE-OPERATIONAL");
-                self.state = NodeState::PreOperational
-            }
             NODE_RESET => {
                 info!("NMT: change state to INIT, will reset the whole system");
-                self.state = NodeState::Init;
-                self.reset();
+                self.set_state(next);
+                self.reset_sdo_transfer();
+                if let Err(ec) = self.reload_object_directory_range(ALL_REGISTERS_RANGE) {
+                    error!("Errors in updating PDO params after a reset: {:?}", ec);
+                }
+                self.boot_up();
             }
             NODE_RESET_COMMUNICATION => {
                 info!("NMT: change state to INIT, will reset the communication");
-                self.state = NodeState::Init;
-                self.reset_communication();
+                self.set_state(next);
+                self.reset_sdo_transfer();
+                if let Err(ec) = self.reset_communication() {
+                    error!("Errors in updating PDO params after a reset: {:?}", ec);
+                }
+                self.boot_up();
+            }
+            _ => {
+                info!("NMT: change state to {:?}", next);
+                self.set_state(next);
             }
-            _ => {}
         }
     }
 
+    /// Leaves Init: sends the boot-up message (0x700 + node-id, one 0 byte)
+    /// and enters Pre-operational.
+    pub(crate) fn boot_up(&mut self) {
+        match create_frame(COB_FUNC_HEARTBEAT | self.node_id as u16, &[0]) {
+            Ok(frame) => self.transmit(&frame),
+            Err(ec) => { error!("Errors in creating boot-up frame: {:?}", ec); }
+        }
+        self.set_state(NodeState::PreOperational);
+    }
+
     fn process_rpdo_frame(&mut self, frame: &CAN::Frame) {
         let result = (|frame: &CAN::Frame| -> Result<(), ErrorCode>{
             let cob_id = get_cob_id(frame).ok_or(ErrorCode::NoCobIdInFrame)?;
+            let in_sync_window = self.in_sync_window();
             let rpdo = self.pdo_objects.get_mut_rpdo_with_cob_id(cob_id)?;
-            if frame.data().len() != ((rpdo.total_length() + 7) / 8) as usize {
+            if !rpdo.is_pdo_valid() {
+                return Ok(());
+            }
+            if rpdo.is_synchronous() && !in_sync_window {
+                info!("Discard RPDO {:#x} received outside the synchronous window", cob_id);
+                return Ok(());
+            }
+            // Masters often pad RPDOs to 8 bytes, only a frame too short for the mapping is an error.
+            if frame.data().len() < (rpdo.total_length().div_ceil(8)) as usize {
                 // trigger emergency
                 error!("process_rpdo_frame() 1.3: rpdo = {:x?}, frame_len = {}, rpdo_len = {}",
-                    rpdo, frame.data().len(), (rpdo.total_length() + 7) / 8);
+                    rpdo, frame.data().len(), rpdo.total_length().div_ceil(8));
                 let bytes = cob_id.to_le_bytes();
                 return self.trigger_emergency(
                     EmergencyErrorCode::PdoNotProcessed, ErrorRegister::GenericError, &bytes);
@@ -287,6 +688,20 @@ impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
     }
 
     pub(crate) fn transmit(&mut self, frame: &CAN::Frame) {
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.on_tx(frame);
+        }
+        if let Some(frames) = self.captured_frames.as_mut() {
+            match copy_frame(frame) {
+                Some(copy) => frames.push(copy),
+                None => { error!("Errors in copying frame {:x?}", frame); }
+            }
+            return;
+        }
+        self.send_to_network(frame);
+    }
+
+    fn send_to_network(&mut self, frame: &CAN::Frame) {
         match self.can_network.transmit(frame) {
             Ok(_) => {
                 info!("Sent frame {:x?}", frame);
@@ -297,10 +712,15 @@ impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
         }
     }
 
+    /// Finishes the initialisation: sends the boot-up message and enters Pre-operational.
+    /// Required once after `new()` / `from_object_directory()`, as the NMT state
+    /// machine doesn't leave Init on NMT start.
     pub fn init(&mut self) -> Result<(), ErrorCode> {
-        // TODO(zephyr): this is informal, let's figure out a formal way later or just remove this.
-        let ready_frame = create_frame(0x234, &[1, 2, 3, 5])?;
-        self.transmit(&ready_frame);
+        if self.send_ready_frame {
+            let ready_frame = create_frame(0x234, &[1, 2, 3, 5])?;
+            self.transmit(&ready_frame);
+        }
+        self.boot_up();
         Ok(())
     }
 
@@ -314,49 +734,116 @@ impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
                 return;
             }
         };
+        // Already seen by the tracer through handle_frame().
+        for response in self.handle_frame(&frame) {
+            self.send_to_network(&response);
+        }
+    }
+
+    /// Processes a received frame and returns the frames the node sends in
+    /// response, without touching the CAN interface.
+    pub fn handle_frame(&mut self, frame: &CAN::Frame) -> Vec<CAN::Frame> {
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.on_rx(frame);
+        }
+        self.captured_frames = Some(Vec::new());
+        self.process_frame(frame);
+        self.captured_frames.take().unwrap_or_default()
+    }
+
+    fn process_frame(&mut self, frame: &CAN::Frame) {
         info!("got frame: {:x?}", frame);
-        if let Some(cob_id) = get_cob_id(&frame) {
+        if let Some(cob_id) = get_cob_id(frame) {
+            if !self.accepts_cob_id(cob_id) {
+                if !is_predefined_cob_id(cob_id) {
+                    self.on_unknown_frame(frame);
+                }
+                return;
+            }
             match cob_id & COB_FUNC_MASK {
-                COB_FUNC_NMT => self.process_nmt_frame(&frame),
-                COB_FUNC_RPDO_0..=COB_FUNC_RPDO_3 => self.process_rpdo_frame(&frame),
-                COB_FUNC_SYNC => self.process_sync_frame(),
-                COB_FUNC_RECEIVE_SDO => self.process_sdo_frame(&frame),
+                _ if cob_id == COB_ID_LSS_MASTER => self.process_lss_frame(frame),
+                _ if self.is_unconfigured() => {}
+                COB_FUNC_NMT => self.process_nmt_frame(frame),
+                // RPDOs go by their COB-ID, which may lie outside the predefined RPDO range.
+                // CiA 301: PDOs are only exchanged in Operational, SDOs not in Stopped.
+                _ if self.pdo_objects.is_rpdo_cob_id(cob_id) && self.state == NodeState::Operational =>
+                    self.process_rpdo_frame(frame),
+                _ if self.pdo_objects.is_rpdo_cob_id(cob_id) => {}
+                // SYNC is exactly 0x080, 0x081..=0x0FF are EMCY from other nodes.
+                COB_FUNC_SYNC if cob_id == COB_FUNC_SYNC => self.process_sync_frame(frame),
+                COB_FUNC_SYNC => self.process_emergency_frame(frame),
+                COB_FUNC_RECEIVE_SDO if self.state != NodeState::Stopped => self.process_sdo_frame(frame),
                 _ => {}
             }
         }
     }
 
-    fn call_tpdo(&mut self, is_sync: bool, event: NodeEvent, count: u32) {
-        match self.transmit_pdo_messages(is_sync, event, count) {
-            Ok(_) => {}
-            Err(err) => { error!("Errors in transmit PDO message: {:x?}", err); }
+    fn on_unknown_frame(&mut self, frame: &CAN::Frame) {
+        match self.unknown_frame_policy {
+            UnknownFramePolicy::Ignore => {}
+            UnknownFramePolicy::Log => { warn!("Frame on an unknown COB-ID: {:x?}", frame); }
+            UnknownFramePolicy::Count => self.unknown_frame_count = self.unknown_frame_count.saturating_add(1),
         }
     }
 
+    fn call_tpdo(&mut self, is_sync: bool, event: NodeEvent, count: u32) {
+        let failures = self.transmit_pdo_messages(is_sync, event, count);
+        self.report_pdo_errors(failures);
+    }
+
+    fn call_rpdo(&mut self, is_sync: bool, event: NodeEvent, count: u32) {
+        let failures = self.save_rpdo_messages(is_sync, event, count);
+        self.report_pdo_errors(failures);
+    }
+
     fn trigger_event(&mut self, event: NodeEvent) {
         if event == NodeEvent::NodeStart {
-            self.event_count = 0;
+            self.pdo_objects.restart_event_timers();
             self.sync_count = 0;
             self.error_count = 0;
             self.heartbeats = 0;
-            self.call_tpdo(false, event, self.event_count);
+            self.call_tpdo(false, event, 0);
+        }
+    }
+
+    fn process_sync_frame(&mut self, frame: &CAN::Frame) {
+        if let Some(&counter) = frame.data().first() {
+            self.check_sync_counter(counter);
         }
+        self.on_sync();
     }
 
-    fn process_sync_frame(&mut self) {
+    pub(crate) fn on_sync(&mut self) {
+        self.sync_elapsed_us = Some(0);
         if self.state == NodeState::Operational {
             self.sync_count += 1;
-            self.save_rpdo_messages(true, NodeEvent::Unused, self.sync_count);
+            self.call_rpdo(true, NodeEvent::Unused, self.sync_count);
             self.call_tpdo(true, NodeEvent::Unused, self.sync_count);
         }
     }
 
-    // TODO(zephyr): In the version, we hard-code the timer as 1ms for timer event.
-    // We may need to modify this in the future.
+    /// The timer event for integrations calling it every 1ms, same as `timer_tick(1)`.
     pub fn event_timer_callback(&mut self) {
+        self.timer_tick(1);
+    }
+
+    /// Advances the node's timers by `elapsed_ms`, for integrations whose
+    /// timer doesn't fire every millisecond. Heartbeat, SYNC production and
+    /// inhibit times follow the elapsed time whatever the call frequency. A PDO
+    /// event timer fires at most once per call, however many periods it covers.
+    pub fn timer_tick(&mut self, elapsed_ms: u32) {
+        if self.is_unconfigured() {
+            return;
+        }
+        self.sync_window_tick(elapsed_ms);
+        self.sync_producer_tick(elapsed_ms);
+        self.pdo_inhibit_tick(elapsed_ms);
+        self.emcy_inhibit_tick(elapsed_ms);
+
         if self.heartbeats_timer > 0 {
-            self.heartbeats += 1;
-            if self.heartbeats % self.heartbeats_timer == 0 {
+            self.heartbeats += elapsed_ms;
+            if self.heartbeats >= self.heartbeats_timer {
+                self.heartbeats %= self.heartbeats_timer;
                 match create_frame(0x700 + self.node_id as u16, &[self.state.heartbeat_code()]) {
                     Ok(frame) => { self.transmit(&frame) }
                     Err(ec) => {
@@ -367,9 +854,547 @@ impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
         }
 
         if self.state == NodeState::Operational {
-            self.event_count += 1;
-            self.save_rpdo_messages(false, NodeEvent::RegularTimerEvent, self.event_count);
-            self.call_tpdo(false, NodeEvent::RegularTimerEvent, self.event_count);
+            self.call_rpdo(false, NodeEvent::RegularTimerEvent, elapsed_ms);
+            self.call_tpdo(false, NodeEvent::RegularTimerEvent, elapsed_ms);
+        }
+    }
+}
+
+/// Replaces the objects of `range` (or all objects) in `dst` with those from `src`.
+#[cfg(test)]
+mod tests {
+    use alloc::rc::Rc;
+    use core::cell::RefCell;
+
+    use embedded_can::Frame;
+
+    use crate::test_util::{MockCan, MockFrame, TEST_EDS, frame, new_node, sdo_read, sdo_write_u16, sdo_write_u32, sdo_write_u8, send, take_tx};
+
+    use super::{FrameTracer, Identity, Node, NodeState, UnknownFramePolicy};
+    use crate::data_type::DataType;
+    use crate::error::{AbortCode, ErrorCode};
+    use crate::object_directory::{AccessType, ObjectDirectory};
+    use crate::value::{ByteConvertible, Value};
+
+    // (sent, frame) in the order seen.
+    struct RecordingTracer(Rc<RefCell<Vec<(bool, MockFrame)>>>);
+
+    impl FrameTracer<MockFrame> for RecordingTracer {
+        fn on_rx(&mut self, frame: &MockFrame) {
+            self.0.borrow_mut().push((false, frame.clone()));
+        }
+        fn on_tx(&mut self, frame: &MockFrame) {
+            self.0.borrow_mut().push((true, frame.clone()));
+        }
+    }
+
+    #[test]
+    fn test_frame_tracer() {
+        let mut node = new_node();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        node.set_tracer(Box::new(RecordingTracer(log.clone())));
+
+        let tx = send(&mut node, frame(0x602, &[0x40, 0x00, 0x20, 0, 0, 0, 0, 0]));
+        assert_eq!(tx.len(), 1);
+        let log = log.borrow();
+        assert_eq!(log.len(), 2);
+        assert_eq!((log[0].0, log[0].1.cob_id(), log[0].1.data()), (false, 0x602, &[0x40, 0x00, 0x20, 0, 0, 0, 0, 0][..]));
+        assert_eq!((log[1].0, log[1].1.cob_id(), log[1].1.data()), (true, 0x582, &[0x43, 0x00, 0x20, 0, 0x78, 0x56, 0x34, 0x12][..]));
+    }
+
+    #[test]
+    fn test_short_frames_never_panic() {
+        let mut node = new_node();
+        send(&mut node, frame(0x000, &[0x01, 2]));
+        let cob_ids = [0x000, 0x080, 0x082, 0x100, 0x202, 0x602, 0x702, 0x703, 0x7E5];
+        // A fixed LCG, so that a failure can be replayed.
+        let mut seed = 0x1234_5678u32;
+        let mut next = move || {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (seed >> 16) as u8
+        };
+        for _ in 0..5000 {
+            let cob_id = cob_ids[next() as usize % cob_ids.len()];
+            let len = next() as usize % 9;
+            let data: Vec<u8> = (0..len).map(|_| next()).collect();
+            send(&mut node, frame(cob_id, &data));
+            // Keep the node reachable, a random NMT command may stop or reset it.
+            if node.state() != NodeState::Operational {
+                send(&mut node, frame(0x000, &[0x01, 2]));
+            }
+        }
+    }
+
+    #[test]
+    fn test_unknown_frame_policy() {
+        let mut node = new_node();
+        send(&mut node, frame(0x7FF, &[1]));
+        assert_eq!(node.unknown_frame_count(), 0);
+
+        node.set_unknown_frame_policy(UnknownFramePolicy::Count);
+        send(&mut node, frame(0x7FF, &[1]));
+        send(&mut node, frame(0x7A0, &[]));
+        assert_eq!(node.unknown_frame_count(), 2);
+        // Traffic of other nodes is expected on the bus.
+        send(&mut node, frame(0x603, &[0x40, 0x00, 0x10, 0, 0, 0, 0, 0]));
+        send(&mut node, frame(0x703, &[0x05]));
+        send(&mut node, frame(0x203, &[1, 2]));
+        assert_eq!(node.unknown_frame_count(), 2);
+    }
+
+    #[test]
+    fn test_acceptance_filter() {
+        let mut node = new_node();
+        assert!(node.accepts_cob_id(0x000));
+        assert!(node.accepts_cob_id(0x080));
+        assert!(node.accepts_cob_id(0x202));
+        assert!(node.accepts_cob_id(0x602));
+        assert!(!node.accepts_cob_id(0x605));
+        assert!(!node.accepts_cob_id(0x203));
+
+        // A stranger's SDO request is dropped, the own one answered.
+        assert!(send(&mut node, frame(0x605, &[0x40, 0x00, 0x10, 0, 0, 0, 0, 0])).is_empty());
+        assert_eq!(send(&mut node, frame(0x602, &[0x40, 0x00, 0x10, 0, 0, 0, 0, 0])).len(), 1);
+
+        // NMT passes the filter.
+        send(&mut node, frame(0x000, &[0x01, 2]));
+        assert_eq!(node.state, NodeState::Operational);
+
+        // A disabled RPDO leaves the table.
+        node.set_pdo_valid(0x1400, false).unwrap();
+        assert!(!node.accepts_cob_id(0x202));
+    }
+
+    #[test]
+    fn test_state_change_callback() {
+        let mut node = new_node();
+        let transitions = Rc::new(RefCell::new(Vec::new()));
+        let recorded = transitions.clone();
+        node.on_state_change(Box::new(move |old, new| recorded.borrow_mut().push((old, new))));
+
+        for cs in [0x01, 0x01, 0x02, 0x80, 0x81] {
+            send(&mut node, frame(0x000, &[cs, 2]));
+        }
+        // Other nodes' commands don't count.
+        send(&mut node, frame(0x000, &[0x01, 3]));
+        assert_eq!(node.state(), NodeState::PreOperational);
+        assert_eq!(*transitions.borrow(), vec![
+            (NodeState::PreOperational, NodeState::Operational),
+            (NodeState::Operational, NodeState::Stopped),
+            (NodeState::Stopped, NodeState::PreOperational),
+            (NodeState::PreOperational, NodeState::Init),
+            (NodeState::Init, NodeState::PreOperational),
+        ]);
+    }
+
+    #[test]
+    fn test_nmt_transitions_from_init() {
+        let mut node = Node::new(2, TEST_EDS, MockCan::default()).unwrap();
+        assert_eq!(node.state(), NodeState::Init);
+        // Operational needs the boot-up into Pre-operational first.
+        send(&mut node, frame(0x000, &[0x01, 2]));
+        send(&mut node, frame(0x000, &[0x02, 2]));
+        assert_eq!(node.state(), NodeState::Init);
+
+        node.init().unwrap();
+        let tx = take_tx(&mut node);
+        assert!(tx.iter().any(|f| f.cob_id() == 0x702 && f.data() == [0]));
+        assert_eq!(node.state(), NodeState::PreOperational);
+        send(&mut node, frame(0x000, &[0x01, 2]));
+        assert_eq!(node.state(), NodeState::Operational);
+    }
+
+    #[test]
+    fn test_nmt_broadcast() {
+        let mut node = new_node();
+        send(&mut node, frame(0x000, &[0x01, 3]));
+        assert_eq!(node.state(), NodeState::PreOperational);
+        send(&mut node, frame(0x000, &[0x01, 0]));
+        assert_eq!(node.state(), NodeState::Operational);
+        send(&mut node, frame(0x000, &[0x02, 3]));
+        assert_eq!(node.state(), NodeState::Operational);
+        send(&mut node, frame(0x000, &[0x02, 0]));
+        assert_eq!(node.state(), NodeState::Stopped);
+    }
+
+    #[test]
+    fn test_reset_communication_boots_up() {
+        let mut node = new_node();
+        send(&mut node, frame(0x000, &[0x01, 2]));
+        let tx = send(&mut node, frame(0x000, &[0x82, 2]));
+        assert_eq!(tx.len(), 1);
+        assert_eq!(tx[0].cob_id(), 0x702);
+        assert_eq!(tx[0].data(), &[0]);
+        assert_eq!(node.state(), NodeState::PreOperational);
+    }
+
+    #[test]
+    fn test_sdo_not_served_when_stopped() {
+        let mut node = new_node();
+        send(&mut node, frame(0x000, &[0x01, 2]));
+        send(&mut node, frame(0x000, &[0x02, 2]));
+        assert_eq!(node.state(), NodeState::Stopped);
+        assert!(send(&mut node, frame(0x602, &[0x40, 0x00, 0x10, 0, 0, 0, 0, 0])).is_empty());
+
+        send(&mut node, frame(0x000, &[0x80, 2]));
+        assert_eq!(send(&mut node, frame(0x602, &[0x40, 0x00, 0x10, 0, 0, 0, 0, 0])).len(), 1);
+    }
+
+    #[test]
+    fn test_rpdo_ignored_when_pre_operational() {
+        let mut node = new_node();
+        send(&mut node, frame(0x000, &[0x80, 2]));
+        send(&mut node, frame(0x202, &[0x34, 0x12, 0x56]));
+        send(&mut node, frame(0x000, &[0x01, 2]));
+        send(&mut node, frame(0x080, &[]));
+        assert_eq!(node.object_directory.get_variable(0x2001, 0).unwrap().effective_value().to::<u16>(), 0);
+
+        send(&mut node, frame(0x202, &[0x34, 0x12, 0x56]));
+        send(&mut node, frame(0x080, &[]));
+        assert_eq!(node.object_directory.get_variable(0x2001, 0).unwrap().effective_value().to::<u16>(), 0x1234);
+    }
+
+    #[test]
+    fn test_disabled_rpdo_frame_ignored() {
+        let mut node = new_node();
+        send(&mut node, frame(0x000, &[0x01, 2]));
+        take_tx(&mut node);
+        assert_eq!(sdo_write_u32(&mut node, 0x1400, 1, 0x8000_0202)[0], 0x60);
+
+        // Even past the acceptance filter, neither written nor reported as too short.
+        node.process_rpdo_frame(&frame(0x202, &[0x34, 0x12, 0x56]));
+        node.process_rpdo_frame(&frame(0x202, &[0x34]));
+        assert!(take_tx(&mut node).is_empty());
+        send(&mut node, frame(0x080, &[]));
+        assert_eq!(node.object_directory.get_variable(0x2001, 0).unwrap().effective_value().to::<u16>(), 0);
+
+        // Data received before the RPDO got disabled is dropped as well.
+        assert_eq!(sdo_write_u32(&mut node, 0x1400, 1, 0x202)[0], 0x60);
+        send(&mut node, frame(0x202, &[0x34, 0x12, 0x56]));
+        assert_eq!(sdo_write_u32(&mut node, 0x1400, 1, 0x8000_0202)[0], 0x60);
+        assert_eq!(sdo_write_u32(&mut node, 0x1400, 1, 0x202)[0], 0x60);
+        send(&mut node, frame(0x080, &[]));
+        assert_eq!(node.object_directory.get_variable(0x2001, 0).unwrap().effective_value().to::<u16>(), 0);
+    }
+
+    #[test]
+    fn test_local_reset_communication_restores_pdos() {
+        let mut node = new_node();
+        send(&mut node, frame(0x000, &[0x01, 2]));
+        assert_eq!(sdo_write_u32(&mut node, 0x1800, 1, 0x190)[0], 0x60);
+        assert!(send(&mut node, frame(0x080, &[])).iter().any(|f| f.cob_id() == 0x190));
+
+        assert_eq!(node.reset_communication(), Ok(()));
+        assert_eq!(node.object_directory.get_variable(0x1800, 1).unwrap().effective_value().to::<u32>(), 0x182);
+        let tx = send(&mut node, frame(0x080, &[]));
+        assert!(tx.iter().any(|f| f.cob_id() == 0x182));
+        assert!(tx.iter().all(|f| f.cob_id() != 0x190));
+
+        // Saved values (0x1010 sub 2) are reloaded, and stay saved.
+        assert_eq!(sdo_write_u32(&mut node, 0x1800, 1, 0x190)[0], 0x60);
+        assert_eq!(sdo_write_u32(&mut node, 0x1010, 2, 0x6576_6173)[0], 0x60);
+        assert_eq!(sdo_write_u32(&mut node, 0x1800, 1, 0x1A0)[0], 0x60);
+        for _ in 0..2 {
+            assert_eq!(node.reset_communication(), Ok(()));
+            assert!(send(&mut node, frame(0x080, &[])).iter().any(|f| f.cob_id() == 0x190));
+        }
+
+        assert_eq!(sdo_write_u16(&mut node, 0x2001, 0, 0x1234)[0], 0x60);
+        assert_eq!(node.reset_application(), Ok(()));
+        assert_eq!(node.object_directory.get_variable(0x2001, 0).unwrap().effective_value().to::<u16>(), 0);
+    }
+
+    #[test]
+    fn test_save_keeps_changed_values_only() {
+        let mut node = new_node();
+        assert_eq!(sdo_write_u32(&mut node, 0x1800, 1, 0x190)[0], 0x60);
+        assert_eq!(sdo_write_u32(&mut node, 0x1010, 1, 0x6576_6173)[0], 0x60);
+        assert_eq!(node.saved_values.len(), 1);
+        assert!(node.saved_values.contains_key(&(0x1800, 1)));
+
+        // Unchanged $NODEID based values follow a new node-id after a reload.
+        node.set_node_id(5).unwrap();
+        assert_eq!(node.reset_communication(), Ok(()));
+        assert_eq!(node.object_directory.get_variable(0x1800, 1).unwrap().effective_value().to::<u32>(), 0x190);
+        assert_eq!(node.object_directory.get_variable(0x1400, 1).unwrap().effective_value().to::<u32>(), 0x205);
+
+        // Restoring the defaults drops the saved values.
+        assert_eq!(sdo_write_u32(&mut node, 0x1011, 2, 0x6461_6F6C)[0], 0x60);
+        assert!(node.saved_values.is_empty());
+    }
+
+    #[test]
+    fn test_failed_restore_aborts() {
+        // EDS defaults the PDOs can't follow, a 96 bit TPDO1 mapping, which is
+        // only fixed in the object directory the node starts with.
+        let eds = TEST_EDS.replace("Mapping entry 2\nObjectType=0x7\nDataType=0x0007\nAccessType=rw\nDefaultValue=0\n",
+                                   "Mapping entry 2\nObjectType=0x7\nDataType=0x0007\nAccessType=rw\nDefaultValue=0x30400040\n")
+            .replace("AccessType=rw\nDefaultValue=1\nPDOMapping=0\n\n[1A00sub1]",
+                     "AccessType=rw\nDefaultValue=2\nPDOMapping=0\n\n[1A00sub1]");
+        let mut od = ObjectDirectory::new(2, &eds).unwrap();
+        od.set_value(0x1A00, 0, &[1], true).unwrap();
+        let mut node = Node::new_from_od(2, od, MockCan::default()).unwrap();
+        // Data transfer or store failed, 0x08000020.
+        assert_eq!(sdo_write_u32(&mut node, 0x1011, 1, 0x6461_6F6C), vec![0x80, 0x11, 0x10, 1, 0x20, 0x00, 0x00, 0x08]);
+    }
+
+    #[test]
+    fn test_nmt_reset_communication_restores_pdos() {
+        let mut node = new_node();
+        send(&mut node, frame(0x000, &[0x01, 2]));
+        assert_eq!(sdo_write_u32(&mut node, 0x1800, 1, 0x190)[0], 0x60);
+        assert_eq!(sdo_write_u32(&mut node, 0x1400, 1, 0x210)[0], 0x60);
+        assert!(send(&mut node, frame(0x080, &[])).iter().any(|f| f.cob_id() == 0x190));
+
+        send(&mut node, frame(0x000, &[0x82, 2]));
+        send(&mut node, frame(0x000, &[0x01, 2]));
+        let tx = send(&mut node, frame(0x080, &[]));
+        assert!(tx.iter().any(|f| f.cob_id() == 0x182));
+        assert!(tx.iter().all(|f| f.cob_id() != 0x190));
+
+        // Only the default RPDO COB-ID is left.
+        assert!(node.pdo_objects().get_mut_rpdo_with_cob_id(0x210).is_err());
+        assert!(!node.accepts_cob_id(0x210));
+        send(&mut node, frame(0x202, &[0x34, 0x12, 0x56]));
+        send(&mut node, frame(0x080, &[]));
+        assert_eq!(node.object_directory.get_variable(0x2001, 0).unwrap().effective_value().to::<u16>(), 0x1234);
+    }
+
+    #[test]
+    fn test_invalid_node_id() {
+        for node_id in [0, 128, 200, 0xFF] {
+            assert_eq!(Node::new(node_id, TEST_EDS, MockCan::default()).err(), Some(ErrorCode::InvalidNodeId { node_id }));
+            assert_eq!(ObjectDirectory::new(node_id, TEST_EDS).err(), Some(ErrorCode::InvalidNodeId { node_id }));
+        }
+        for node_id in [1, 127] {
+            assert!(Node::new(node_id, TEST_EDS, MockCan::default()).is_ok());
+        }
+
+        let mut node = new_node();
+        assert_eq!(node.set_node_id(0), Err(ErrorCode::InvalidNodeId { node_id: 0 }));
+        assert_eq!(node.set_node_id(0xFF), Err(ErrorCode::InvalidNodeId { node_id: 0xFF }));
+        assert_eq!(node.node_id, 2);
+        assert_eq!(node.pdo_cob_id(0x1800), Some(0x182));
+    }
+
+    #[test]
+    fn test_init_ready_frame_is_optional() {
+        let mut node = Node::new(2, TEST_EDS, MockCan::default()).unwrap();
+        node.init().unwrap();
+        let sent: Vec<(u16, Vec<u8>)> = take_tx(&mut node).iter().map(|f| (f.cob_id(), f.data().to_vec())).collect();
+        assert_eq!(sent, vec![(0x702, vec![0])]);
+
+        node.set_send_ready_frame(true);
+        node.init().unwrap();
+        let sent: Vec<u16> = take_tx(&mut node).iter().map(|f| f.cob_id()).collect();
+        assert_eq!(sent, vec![0x234, 0x702]);
+    }
+
+    #[test]
+    fn test_node_without_pdo_objects() {
+        let eds = r#"
+[1000]
+ParameterName=Device type
+ObjectType=0x7
+DataType=0x0007
+AccessType=ro
+DefaultValue=0x00010192
+PDOMapping=0
+
+[1017]
+ParameterName=Producer heartbeat time
+ObjectType=0x7
+DataType=0x0006
+AccessType=rw
+DefaultValue=0
+PDOMapping=0
+"#;
+        let mut node = Node::new(2, eds, MockCan::default()).unwrap();
+        node.init().unwrap();
+        take_tx(&mut node);
+        assert_eq!(sdo_read(&mut node, 0x1000, 0), vec![0x43, 0x00, 0x10, 0, 0x92, 0x01, 0x01, 0]);
+        assert_eq!(sdo_write_u16(&mut node, 0x1017, 0, 100)[0], 0x60);
+        assert_eq!(sdo_read(&mut node, 0x1017, 0)[4..6], [100, 0]);
+
+        // No PDO activity when operational.
+        send(&mut node, frame(0x000, &[0x01, 2]));
+        take_tx(&mut node);
+        assert!(send(&mut node, frame(0x080, &[])).is_empty());
+        assert!(send(&mut node, frame(0x202, &[1, 2, 3, 4])).is_empty());
+    }
+
+    #[test]
+    fn test_identity() {
+        let mut node = new_node();
+        assert_eq!(node.identity(), Identity {
+            vendor_id: 0xABCD, product_code: 0x1234, revision: 0x0001_0001, serial: 0x42,
+        });
+
+        // Sub-indices missing from the object directory read as 0.
+        fn vendor_only() -> Identity {
+            let mut od = ObjectDirectory::empty(2);
+            od.add_record(0x1018, "Identity object");
+            od.add_variable(0x1018, 1, DataType::Unsigned32, AccessType::new(true, false),
+                            Value::new(0x77u32.to_bytes()), false).unwrap();
+            Node::new_from_od(2, od, MockCan::default()).unwrap().identity()
         }
+        assert_eq!(vendor_only(), Identity { vendor_id: 0x77, ..Identity::default() });
+    }
+
+    #[test]
+    fn test_from_object_directory_reports_oversized_pdo() {
+        let mut od = ObjectDirectory::new(2, TEST_EDS).unwrap();
+        od.set_value(0x1A00, 1, &0x2000_0020u32.to_le_bytes(), true).unwrap();
+        od.set_value(0x1A00, 2, &0x3040_0040u32.to_le_bytes(), true).unwrap();
+        od.set_value(0x1A00, 0, &[2], true).unwrap();
+        let err = Node::from_object_directory(2, od, MockCan::default()).err().unwrap();
+        assert_eq!(err, ErrorCode::AbortCodeWrapper {
+            abort_code: AbortCode::ExceedPDOSize,
+            more_info: "PDO mapping 0x1A00 maps 96 bits".to_string(),
+        });
+    }
+
+    #[test]
+    fn test_handle_frame_expedited_read() {
+        let mut node = Node::new(2, TEST_EDS, MockCan::default()).unwrap();
+        let responses = node.handle_frame(&frame(0x602, &[0x40, 0x00, 0x20, 0, 0, 0, 0, 0]));
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].cob_id(), 0x582);
+        assert_eq!(responses[0].data(), &[0x43, 0x00, 0x20, 0, 0x78, 0x56, 0x34, 0x12]);
+        // Nothing went out on the interface.
+        assert!(node.can_network.tx.is_empty());
+
+        // Frames without a response give an empty list.
+        assert!(node.handle_frame(&frame(0x605, &[0x40, 0x00, 0x10, 0, 0, 0, 0, 0])).is_empty());
+    }
+
+    #[test]
+    fn test_object_raw() {
+        let mut node = new_node();
+        assert_eq!(node.object_raw(0x2000, 0).unwrap(), vec![0x78, 0x56, 0x34, 0x12]);
+        node.set_heartbeat_period(0x1234).unwrap();
+        assert_eq!(node.object_raw(0x1017, 0).unwrap(), vec![0x34, 0x12]);
+        assert!(node.object_raw(0x2000, 1).is_err());
+    }
+
+    #[test]
+    fn test_heartbeat_period_api() {
+        let mut node = new_node();
+        assert_eq!(node.heartbeat_period(), 0);
+        node.set_heartbeat_period(3).unwrap();
+        assert_eq!(node.heartbeat_period(), 3);
+        assert_eq!(node.object_directory.get_variable(0x1017, 0).unwrap().effective_value().to::<u16>(), 3);
+
+        for _ in 0..9 {
+            node.event_timer_callback();
+        }
+        let tx = take_tx(&mut node);
+        assert_eq!(tx.len(), 3);
+        assert!(tx.iter().all(|f| f.cob_id() == 0x702 && f.data() == [127]));
+
+        node.set_heartbeat_period(0).unwrap();
+        assert_eq!(node.object_directory.get_variable(0x1017, 0).unwrap().effective_value().to::<u16>(), 0);
+        for _ in 0..9 {
+            node.event_timer_callback();
+        }
+        assert!(take_tx(&mut node).is_empty());
+    }
+
+    #[test]
+    fn test_reset_communication_reloads_heartbeat() {
+        fn heartbeats(node: &mut Node<MockCan>) -> usize {
+            for _ in 0..20 {
+                node.event_timer_callback();
+            }
+            take_tx(node).iter().filter(|f| f.cob_id() == 0x702).count()
+        }
+
+        let mut node = new_node();
+        assert_eq!(sdo_write_u16(&mut node, 0x1017, 0, 10)[0], 0x60);
+        assert_eq!(sdo_write_u32(&mut node, 0x1010, 2, 0x6576_6173)[0], 0x60);
+        assert_eq!(sdo_write_u16(&mut node, 0x1017, 0, 0)[0], 0x60);
+        assert_eq!(heartbeats(&mut node), 0);
+
+        // The saved 10ms come back with the reset, and are in effect at once.
+        send(&mut node, frame(0x000, &[0x82, 2]));
+        take_tx(&mut node);
+        assert_eq!(node.heartbeat_period(), 10);
+        assert_eq!(heartbeats(&mut node), 2);
+
+        // The other way round, a heartbeat that was never saved stops.
+        assert_eq!(sdo_write_u32(&mut node, 0x1011, 2, 0x6461_6F6C)[0], 0x60);
+        assert_eq!(node.heartbeat_period(), 0);
+        assert_eq!(sdo_write_u16(&mut node, 0x1017, 0, 10)[0], 0x60);
+        take_tx(&mut node);
+        send(&mut node, frame(0x000, &[0x82, 2]));
+        take_tx(&mut node);
+        assert_eq!(node.heartbeat_period(), 0);
+        assert_eq!(heartbeats(&mut node), 0);
+    }
+
+    #[test]
+    fn test_reset_communication_reloads_acceptance_filter() {
+        let eds = format!("{}\n[1016]\nParameterName=Consumer heartbeat time\nObjectType=0x8\nSubNumber=2\n\
+            \n[1016sub0]\nParameterName=Number of entries\nObjectType=0x7\nDataType=0x0005\nAccessType=ro\nDefaultValue=1\n\
+            \n[1016sub1]\nParameterName=Consumer heartbeat time 1\nObjectType=0x7\nDataType=0x0007\nAccessType=rw\nDefaultValue=0\n",
+                          TEST_EDS);
+        let mut node = Node::new(2, &eds, MockCan::default()).unwrap();
+        node.init().unwrap();
+        take_tx(&mut node);
+        // Monitor node 5, with a 100ms heartbeat consumer time.
+        assert_eq!(sdo_write_u32(&mut node, 0x1016, 1, 0x0005_0064)[0], 0x60);
+        assert!(node.accepts_cob_id(0x705));
+
+        send(&mut node, frame(0x000, &[0x82, 2]));
+        assert!(!node.accepts_cob_id(0x705));
+    }
+
+    #[test]
+    fn test_heartbeat_with_coarse_timer() {
+        let mut node = new_node();
+        node.set_heartbeat_period(100).unwrap();
+        for round in 0..3 {
+            for _ in 0..9 {
+                node.timer_tick(10);
+            }
+            assert!(take_tx(&mut node).is_empty(), "round {}", round);
+            node.timer_tick(10);
+            assert_eq!(take_tx(&mut node).len(), 1, "round {}", round);
+        }
+    }
+
+    #[test]
+    fn test_pdo_remapping_transaction() {
+        let mut node = new_node();
+        send(&mut node, frame(0x000, &[0x01, 2]));
+        let sync_tpdo = |node: &mut Node<MockCan>| send(node, frame(0x080, &[]))
+            .into_iter().find(|f| f.cob_id() == 0x182).expect("no TPDO on SYNC");
+
+        // The TPDO keeps its mapping until the commit.
+        node.begin_transaction(0x1A00).unwrap();
+        assert_eq!(sdo_write_u8(&mut node, 0x1A00, 0, 0)[0], 0x60);
+        assert_eq!(sdo_write_u32(&mut node, 0x1A00, 1, 0x2001_0010)[0], 0x60);
+        assert_eq!(sdo_write_u32(&mut node, 0x1A00, 2, 0x2002_0008)[0], 0x60);
+        assert_eq!(sdo_write_u8(&mut node, 0x1A00, 0, 2)[0], 0x60);
+        assert_eq!(sync_tpdo(&mut node).dlc(), 4);
+        assert_eq!(sdo_read(&mut node, 0x1A00, 0)[4], 1);
+        node.commit_transaction().unwrap();
+        assert_eq!(node.pdo_mapping(0x1800), Some(vec![(0x2001, 0, 0x10), (0x2002, 0, 0x08)]));
+        assert_eq!(sync_tpdo(&mut node).dlc(), 3);
+
+        // Staged entries may go past 64 bits on the way, not in the end.
+        node.begin_transaction(0x1A00).unwrap();
+        assert_eq!(sdo_write_u32(&mut node, 0x1A00, 1, 0x2000_0040)[0], 0x60);
+        assert!(matches!(node.commit_transaction(),
+            Err(ErrorCode::AbortCodeWrapper { abort_code: AbortCode::ExceedPDOSize, .. })));
+        assert_eq!(sdo_read(&mut node, 0x1A00, 1)[4..], [0x10, 0x00, 0x01, 0x20]);
+        assert_eq!(sync_tpdo(&mut node).dlc(), 3);
+
+        // Nor can they map an object which isn't mappable.
+        node.begin_transaction(0x1A00).unwrap();
+        assert_eq!(sdo_write_u32(&mut node, 0x1A00, 1, 0x1000_0020)[0], 0x80);
+        node.rollback_transaction();
+        assert!(node.commit_transaction().is_err());
+        assert_eq!(node.pdo_mapping(0x1800), Some(vec![(0x2001, 0, 0x10), (0x2002, 0, 0x08)]));
     }
 }
@@ -6,28 +6,34 @@
 
 use alloc::borrow::ToOwned;
 use alloc::string::ToString;
+use core::ops::Range;
 use core::str::FromStr;
 
 use ini_core as ini;
 
-use crate::{info, util};
+use crate::{error, info, util, warn};
+use crate::constant::{REG_MANUFACTURER_DEVICE_NAME, REG_MANUFACTURER_HARDWARE_VERSION, REG_MANUFACTURER_SOFTWARE_VERSION};
 use crate::data_type::DataType;
 use crate::error::ErrorCode;
-use crate::error::AbortCode::{AttemptToReadWriteOnlyObject, AttemptToWriteReadOnlyObject, DataTypeMismatchLengthTooHigh, DataTypeMismatchLengthTooLow, GeneralError, ObjectDoesNotExistInObjectDictionary, SubIndexDoesNotExist};
+use crate::error::AbortCode::{AttemptToReadWriteOnlyObject, AttemptToWriteReadOnlyObject, DataTypeMismatchLengthMismatch, DataTypeMismatchLengthTooHigh, DataTypeMismatchLengthTooLow, GeneralError, ObjectDoesNotExistInObjectDictionary, SubIndexDoesNotExist};
 use crate::error::ErrorCode::ProcesedSectionFailed;
 use crate::prelude::*;
 use crate::util::make_abort_error;
-use crate::value::{ByteConvertible, get_formatted_value_from_properties, Value};
+use crate::value::{ByteConvertible, format_value_with_node_id, get_formatted_value_from_properties, Value};
 
 /// Object Types
+const OBJECT_TYPE_DEFTYPE: u32 = 5;
+const OBJECT_TYPE_DEFSTRUCT: u32 = 6;
 const OBJECT_TYPE_VARIABLE: u32 = 7;
 const OBJECT_TYPE_ARRAY: u32 = 8;
 const OBJECT_TYPE_RECORD: u32 = 9;
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AccessType {
     read_access: bool,
     write_access: bool,
+    constant: bool,
 }
 
 impl FromStr for AccessType {
@@ -38,6 +44,9 @@ impl FromStr for AccessType {
             "rw" => Ok(AccessType::new(true, true)),
             "ro" => Ok(AccessType::new(true, false)),
             "wo" => Ok(AccessType::new(false, true)),
+            // rww / rwr only tell in which PDO direction the object may be mapped.
+            "rww" | "rwr" => Ok(AccessType::new(true, true)),
+            "const" => Ok(AccessType::new_const()),
             _ => Ok(AccessType::new(false, false)),
         }
     }
@@ -48,6 +57,16 @@ impl AccessType {
         AccessType {
             read_access: read,
             write_access: write,
+            constant: false,
+        }
+    }
+
+    /// Readable, never writable, not even by the application.
+    pub fn new_const() -> Self {
+        AccessType {
+            read_access: true,
+            write_access: false,
+            constant: true,
         }
     }
 
@@ -55,20 +74,25 @@ impl AccessType {
     pub fn is_writable(&self) -> bool {
         self.write_access
     }
+    pub fn is_const(&self) -> bool { self.constant }
 }
 
 #[allow(dead_code)]
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Variable {
     name: String,
     storage_location: String,
     data_type: DataType,
     default_value: Value,
+    value: Value,
     min: Option<Value>,
     max: Option<Value>,
     pdo_mappable: bool,
     access_type: AccessType,
     parameter_value: Option<Value>,
+    // The raw DefaultValue when it depends on $NODEID, re-evaluated on node-id changes.
+    default_expression: Option<String>,
     index: u16,
     sub_index: u8,
 }
@@ -80,15 +104,68 @@ impl Variable {
     pub fn data_type(&self) -> DataType {
         self.data_type
     }
+    /// The factory default from the EDS `DefaultValue`, never changed by writes.
     pub fn default_value(&self) -> &Value {
         &self.default_value
     }
+    /// The `ParameterValue` override from the EDS / DCF, if any.
+    pub fn parameter_value(&self) -> &Option<Value> {
+        &self.parameter_value
+    }
+    /// The current value of the variable. It starts from `ParameterValue` when
+    /// present, otherwise from `DefaultValue`, and follows all later writes.
+    pub fn effective_value(&self) -> &Value {
+        &self.value
+    }
+    /// The value the variable was loaded with: `ParameterValue` when present,
+    /// otherwise `DefaultValue`.
+    fn loaded_value(&self) -> &Value {
+        self.parameter_value.as_ref().unwrap_or(&self.default_value)
+    }
+    /// The current value as stored, i.e. the little-endian bytes of numbers.
+    pub fn raw_bytes(&self) -> &[u8] {
+        self.value.data()
+    }
     pub fn min(&self) -> &Option<Value> {
         &self.min
     }
     pub fn max(&self) -> &Option<Value> {
         &self.max
     }
+    /// `LowLimit` as `T`, which has to match the data type of the variable.
+    pub fn min_as<T: ByteConvertible>(&self) -> Option<T> {
+        self.min.as_ref().and_then(|v| v.try_to::<T>().ok())
+    }
+    /// `HighLimit` as `T`, which has to match the data type of the variable.
+    pub fn max_as<T: ByteConvertible>(&self) -> Option<T> {
+        self.max.as_ref().and_then(|v| v.try_to::<T>().ok())
+    }
+    /// Whether `data` lies within `LowLimit..=HighLimit`, compared as the data
+    /// type of the variable. Types without an order (strings, domains) are
+    /// always in range, numbers of the wrong size never are.
+    pub fn in_range(&self, data: &[u8]) -> bool {
+        match self.data_type {
+            DataType::Integer8 => self.within::<i8>(data),
+            DataType::Integer16 => self.within::<i16>(data),
+            DataType::Integer32 => self.within::<i32>(data),
+            DataType::Integer64 => self.within::<i64>(data),
+            DataType::Boolean | DataType::Unsigned8 => self.within::<u8>(data),
+            DataType::Unsigned16 => self.within::<u16>(data),
+            DataType::Unsigned32 => self.within::<u32>(data),
+            DataType::Unsigned64 => self.within::<u64>(data),
+            DataType::Real32 => self.within::<f32>(data),
+            DataType::Real64 => self.within::<f64>(data),
+            _ => true,
+        }
+    }
+
+    fn within<T: ByteConvertible + PartialOrd>(&self, data: &[u8]) -> bool {
+        match T::try_from_bytes(data) {
+            None => false,
+            Some(val) => self.min_as::<T>().is_none_or(|min| val >= min)
+                && self.max_as::<T>().is_none_or(|max| val <= max),
+        }
+    }
     pub fn access_type(&self) -> &AccessType {
         &self.access_type
     }
@@ -101,6 +178,20 @@ impl Variable {
     pub fn pdo_mappable(&self) -> bool {
         self.pdo_mappable
     }
+    /// The command byte and payload of an SDO initiate upload response for the
+    /// current value. Values of 1 to 4 bytes go expedited (`0x43` with the
+    /// count of unused bytes), empty values expedited without a size (`0x42`),
+    /// anything longer segmented (`0x41`) with the size in bytes as payload.
+    pub fn sdo_init_payload(&self) -> (u8, Vec<u8>) {
+        let data = self.value.data();
+        if data.is_empty() {
+            (0x42, vec![0; 4])
+        } else if data.len() <= 4 {
+            (0x43 | (((4 - data.len()) as u8) << 2), data.clone())
+        } else {
+            (0x41, (data.len() as u32).to_le_bytes().to_vec())
+        }
+    }
 }
 
 fn add_member_to_container(name_to_index: &mut HashMap<String, u8>, index_to_variable: &mut HashMap<u8, Variable>, var: Variable) {
@@ -108,8 +199,40 @@ fn add_member_to_container(name_to_index: &mut HashMap<String, u8>, index_to_var
     index_to_variable.insert(var.sub_index, var);
 }
 
+/// Sets sub-index 0 to the highest populated sub-index, creating it as a
+/// read-only Unsigned8 when missing.
+fn update_number_of_entries(index: u16, name_to_index: &mut HashMap<String, u8>, index_to_variable: &mut HashMap<u8, Variable>) {
+    let highest = match index_to_variable.keys().max() {
+        Some(&highest) if highest > 0 => highest,
+        _ => return,
+    };
+    let count = Value::new(vec![highest]);
+    match index_to_variable.get_mut(&0) {
+        Some(var) => {
+            var.default_value = count.clone();
+            var.value = count;
+        }
+        None => add_member_to_container(name_to_index, index_to_variable, Variable {
+            name: "Number of entries".to_string(),
+            index,
+            sub_index: 0,
+            data_type: DataType::Unsigned8,
+            default_value: count.clone(),
+            value: count,
+            min: None,
+            max: None,
+            pdo_mappable: false,
+            access_type: AccessType::new(true, false),
+            storage_location: "".to_string(),
+            parameter_value: None,
+            default_expression: None,
+        }),
+    }
+}
+
 #[allow(dead_code)]
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Array {
     name: String,
     index: u16,
@@ -125,10 +248,28 @@ impl Array {
     pub fn index(&self) -> u16 {
         self.index
     }
+    /// Number of populated sub-indexes, sub-index 0 included.
+    pub fn len(&self) -> usize {
+        self.index_to_variable.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.index_to_variable.is_empty()
+    }
+    /// The highest populated sub-index, `None` without any entry.
+    pub fn largest_sub_index(&self) -> Option<u8> {
+        self.index_to_variable.keys().max().cloned()
+    }
 }
 
 impl Array {
+    /// Adds / replaces a member and keeps sub-index 0 equal to the highest sub-index.
     pub fn add_member(&mut self, var: Variable) {
+        self.insert_member(var);
+        update_number_of_entries(self.index, &mut self.name_to_index, &mut self.index_to_variable);
+    }
+
+    /// Adds / replaces a member as is, sub-index 0 is left to the EDS.
+    pub(crate) fn insert_member(&mut self, var: Variable) {
         add_member_to_container(&mut self.name_to_index, &mut self.index_to_variable, var);
     }
 
@@ -147,7 +288,7 @@ impl Array {
                 let mut new_var = base_var.clone();
                 new_var.name = format!("{}_{}", self.name, sub_index);
                 new_var.sub_index = sub_index;
-                self.add_member(new_var);
+                self.insert_member(new_var);
                 return self
                     .index_to_variable
                     .get_mut(&sub_index)
@@ -159,7 +300,8 @@ impl Array {
 }
 
 #[allow(dead_code)]
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Record {
     name: String,
     index: u16,
@@ -181,10 +323,28 @@ impl Record {
     pub fn name_to_index(&self) -> &HashMap<String, u8> {
         &self.name_to_index
     }
+    /// Number of populated sub-indexes, sub-index 0 included.
+    pub fn len(&self) -> usize {
+        self.index_to_variable.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.index_to_variable.is_empty()
+    }
+    /// The highest populated sub-index, `None` without any entry.
+    pub fn largest_sub_index(&self) -> Option<u8> {
+        self.index_to_variable.keys().max().cloned()
+    }
 }
 
 impl Record {
+    /// Adds / replaces a member and keeps sub-index 0 equal to the highest sub-index.
     pub fn add_member(&mut self, var: Variable) {
+        self.insert_member(var);
+        update_number_of_entries(self.index, &mut self.name_to_index, &mut self.index_to_variable);
+    }
+
+    /// Adds / replaces a member as is, sub-index 0 is left to the EDS.
+    pub(crate) fn insert_member(&mut self, var: Variable) {
         add_member_to_container(&mut self.name_to_index, &mut self.index_to_variable, var);
     }
 
@@ -204,13 +364,61 @@ impl Record {
     }
 }
 
-#[derive(Clone, Debug)]
+/// A data type definition declared in the EDS, either a DEFTYPE (ObjectType 5)
+/// aliasing a basic type, or a DEFSTRUCT (ObjectType 6) listing member types.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TypeDefinition {
+    name: String,
+    index: u16,
+    is_struct: bool,
+    data_type: DataType,
+    members: Vec<(u8, DataType)>,
+}
+
+impl TypeDefinition {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+    pub fn is_struct(&self) -> bool {
+        self.is_struct
+    }
+    /// The basic type a DEFTYPE resolves to, `Unknown` for a DEFSTRUCT.
+    pub fn data_type(&self) -> DataType {
+        self.data_type
+    }
+    /// (sub_index, data_type) of each DEFSTRUCT member.
+    pub fn members(&self) -> &[(u8, DataType)] {
+        &self.members
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ObjectType {
     Variable(Variable),
     Array(Array),
     Record(Record),
 }
 
+fn variable_of(obj: Option<&mut ObjectType>, sub_index: u8) -> Result<&mut Variable, ErrorCode> {
+    match obj {
+        Some(ObjectType::Variable(var)) => {
+            if sub_index == 0 {
+                Ok(var)
+            } else {
+                Err(make_abort_error(SubIndexDoesNotExist, "".to_string()))
+            }
+        }
+        Some(ObjectType::Array(arr)) => arr.get_mut_variable(sub_index),
+        Some(ObjectType::Record(rec)) => rec.get_mut_variable(sub_index),
+        None => Err(make_abort_error(ObjectDoesNotExistInObjectDictionary, "".to_string())),
+    }
+}
+
 pub fn obj_to_variable(obj: &ObjectType) -> Option<&Variable> {
     if let ObjectType::Variable(var) = obj {
         return Some(var);
@@ -232,33 +440,306 @@ pub fn obj_to_record(obj: &ObjectType) -> Option<&Record> {
     None
 }
 
-#[derive(Clone, Debug)]
+/// Max number of objects (indexes) with the `heapless-od` feature, a power of two.
+#[cfg(feature = "heapless-od")]
+pub const OD_CAPACITY: usize = 256;
+
+/// The maps of the objects of an `ObjectDirectory`, fixed-capacity with `heapless-od`.
+#[cfg(feature = "heapless-od")]
+pub(crate) type ObjectMap<K, V> = heapless::FnvIndexMap<K, V, OD_CAPACITY>;
+#[cfg(not(feature = "heapless-od"))]
+pub(crate) type ObjectMap<K, V> = HashMap<K, V>;
+
+/// Fails with `ObjectDirectoryFull` if adding `key` to `map` would exceed its capacity.
+#[cfg(feature = "heapless-od")]
+fn check_room<K: Eq + core::hash::Hash, V>(map: &ObjectMap<K, V>, key: &K) -> Result<(), ErrorCode> {
+    if map.len() == map.capacity() && !map.contains_key(key) {
+        return Err(ErrorCode::ObjectDirectoryFull { capacity: OD_CAPACITY });
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "heapless-od"))]
+fn check_room<K, V>(_map: &ObjectMap<K, V>, _key: &K) -> Result<(), ErrorCode> {
+    Ok(())
+}
+
+/// Inserts / replaces `key`, `check_room()` tells beforehand whether it fits.
+#[cfg(feature = "heapless-od")]
+fn insert_into<K: Eq + core::hash::Hash, V>(map: &mut ObjectMap<K, V>, key: K, value: V)
+    -> Result<(), ErrorCode> {
+    map.insert(key, value).map(|_| ()).map_err(|_| ErrorCode::ObjectDirectoryFull { capacity: OD_CAPACITY })
+}
+
+#[cfg(not(feature = "heapless-od"))]
+fn insert_into<K: Eq + core::hash::Hash, V>(map: &mut ObjectMap<K, V>, key: K, value: V)
+    -> Result<(), ErrorCode> {
+    map.insert(key, value);
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ObjectDirectory {
     node_id: u8,
-    pub(crate) index_to_object: HashMap<u16, ObjectType>,
-    pub(crate) name_to_index: HashMap<String, u16>,
+    pub(crate) index_to_object: ObjectMap<u16, ObjectType>,
+    pub(crate) name_to_index: ObjectMap<String, u16>,
+    pub(crate) type_definitions: HashMap<u16, TypeDefinition>,
+    data_type_fallback: DataTypeFallback,
+    // The object of an open transaction and its staged copy.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    transaction: Option<(u16, ObjectType)>,
+}
+
+/// What to do with objects whose DataType is missing or unparseable.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DataTypeFallback {
+    /// Infer the type from DefaultValue, e.g. "0x12345678" is an Unsigned32.
+    #[default]
+    Infer,
+    /// Fail loading the EDS.
+    Strict,
 }
 
 impl ObjectDirectory {
     pub fn new(node_id: u8, eds_content: &str) -> Result<Self, ErrorCode> {
+        Self::new_with_fallback(node_id, eds_content, DataTypeFallback::default())
+    }
+
+    pub fn new_with_fallback(node_id: u8, eds_content: &str, data_type_fallback: DataTypeFallback)
+        -> Result<Self, ErrorCode> {
+        // $NODEID expressions would yield COB-IDs outside the node's range.
+        util::check_node_id(node_id)?;
         let mut od = ObjectDirectory {
             node_id,
-            index_to_object: HashMap::new(),
-            name_to_index: HashMap::new(),
+            index_to_object: ObjectMap::new(),
+            name_to_index: ObjectMap::new(),
+            type_definitions: HashMap::new(),
+            data_type_fallback,
+            transaction: None,
         };
         od.load_from_content(eds_content)?;
         Ok(od)
     }
 
+    /// An object directory without any object, to be filled with `add_variable()`,
+    /// `add_record()` and `add_array()` instead of parsing an EDS.
+    pub fn empty(node_id: u8) -> Self {
+        ObjectDirectory {
+            node_id,
+            index_to_object: ObjectMap::new(),
+            name_to_index: ObjectMap::new(),
+            type_definitions: HashMap::new(),
+            data_type_fallback: DataTypeFallback::default(),
+            transaction: None,
+        }
+    }
+
     pub fn node_id(&self) -> u8 {
         self.node_id
     }
+
+    /// Switches to `node_id`, re-evaluating every `$NODEID` based default. Values
+    /// still equal to their old default follow the new one, written values stay.
+    pub fn set_node_id(&mut self, node_id: u8) {
+        self.node_id = node_id;
+        for obj in self.index_to_object.values_mut() {
+            let vars: Vec<&mut Variable> = match obj {
+                ObjectType::Variable(var) => vec![var],
+                ObjectType::Array(arr) => arr.index_to_variable.values_mut().collect(),
+                ObjectType::Record(rec) => rec.index_to_variable.values_mut().collect(),
+            };
+            for var in vars {
+                let expression = match &var.default_expression {
+                    Some(expression) => expression,
+                    None => continue,
+                };
+                if let Some(value) = format_value_with_node_id(expression, node_id, &var.data_type) {
+                    if var.value == var.default_value && var.parameter_value.is_none() {
+                        var.value = value.clone();
+                    }
+                    var.default_value = value;
+                }
+            }
+        }
+    }
+
+    pub fn type_definition(&self, index: u16) -> Option<&TypeDefinition> {
+        self.type_definitions.get(&index)
+    }
+
+    /// All objects, in index order.
+    pub fn iter_objects(&self) -> impl Iterator<Item = (u16, &ObjectType)> {
+        let mut objects: Vec<(u16, &ObjectType)> = self.index_to_object.iter()
+            .map(|(index, obj)| (*index, obj))
+            .collect();
+        objects.sort_by_key(|(index, _)| *index);
+        objects.into_iter()
+    }
+
+    /// All variables, with the members of arrays and records flattened, in
+    /// index / sub-index order. Items are (index, sub_index, variable).
+    pub fn iter_variables(&self) -> impl Iterator<Item = (u16, u8, &Variable)> {
+        self.iter_objects().flat_map(|(index, obj)| {
+            let mut vars: Vec<&Variable> = match obj {
+                ObjectType::Variable(var) => vec![var],
+                ObjectType::Array(arr) => arr.index_to_variable.values().collect(),
+                ObjectType::Record(rec) => rec.index_to_variable.values().collect(),
+            };
+            vars.sort_by_key(|var| var.sub_index);
+            vars.into_iter().map(move |var| (index, var.sub_index, var))
+        })
+    }
+
+    /// The current values of the objects in `range` that differ from the values
+    /// they were loaded with.
+    pub(crate) fn changed_values(&self, range: &Range<u16>) -> Vec<((u16, u8), Value)> {
+        self.iter_variables()
+            .filter(|(index, _, var)| range.contains(index) && var.value != *var.loaded_value())
+            .map(|(index, sub_index, var)| ((index, sub_index), var.value.clone()))
+            .collect()
+    }
+
+    /// Sets the variables of the objects in `range` back to the values they
+    /// were loaded with, as a 0x1011 restore does.
+    pub(crate) fn reset_values(&mut self, range: &Range<u16>) {
+        for (_, obj) in self.index_to_object.iter_mut().filter(|(index, _)| range.contains(index)) {
+            let vars: Vec<&mut Variable> = match obj {
+                ObjectType::Variable(var) => vec![var],
+                ObjectType::Array(arr) => arr.index_to_variable.values_mut().collect(),
+                ObjectType::Record(rec) => rec.index_to_variable.values_mut().collect(),
+            };
+            for var in vars {
+                var.value = var.loaded_value().clone();
+            }
+        }
+    }
+
+    /// Sets the current values as they are, without access checks, e.g. to
+    /// reload the values from `changed_values()`. Unknown objects are skipped.
+    pub(crate) fn load_values<'a>(&mut self, values: impl Iterator<Item = (&'a (u16, u8), &'a Value)>) {
+        for (&(index, sub_index), value) in values {
+            if let Ok(var) = variable_of(self.index_to_object.get_mut(&index), sub_index) {
+                var.value = value.clone();
+            }
+        }
+    }
+
+    /// CRC-16 over the (index, sub_index, data type, access, default value) of
+    /// every variable in index / sub-index order, to check that a device and a
+    /// master work with the same object directory. Current values don't count.
+    pub fn content_signature(&self) -> u16 {
+        let mut bytes = Vec::new();
+        for (index, sub_index, var) in self.iter_variables() {
+            let access = var.access_type.is_readable() as u8
+                | (var.access_type.is_writable() as u8) << 1
+                | (var.access_type.is_const() as u8) << 2;
+            let default = var.default_value.data();
+            bytes.extend_from_slice(&index.to_le_bytes());
+            bytes.push(sub_index);
+            bytes.extend_from_slice(&(var.data_type as u16).to_le_bytes());
+            bytes.push(access);
+            bytes.extend_from_slice(&(default.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(default);
+        }
+        util::crc16_canopen_with_lut(&bytes)
+    }
+
+    /// The highest sub-index declared for an array / record, without creating
+    /// entries the way `get_mut_variable()` does for arrays.
+    pub(crate) fn declared_largest_sub_index(&self, index: u16) -> Option<u8> {
+        match self.index_to_object.get(&index)? {
+            ObjectType::Array(arr) => arr.largest_sub_index(),
+            ObjectType::Record(rec) => rec.largest_sub_index(),
+            ObjectType::Variable(_) => None,
+        }
+    }
+
+    /// The data type of `index` / `sub_index`, without creating the array
+    /// entries `get_mut_variable()` creates: those take the type of sub-index 1.
+    pub(crate) fn data_type_of(&self, index: u16, sub_index: u8) -> Result<DataType, ErrorCode> {
+        let var = match self.index_to_object.get(&index) {
+            Some(ObjectType::Variable(var)) if sub_index == 0 => Some(var),
+            Some(ObjectType::Variable(_)) => return Err(make_abort_error(SubIndexDoesNotExist, "".to_string())),
+            Some(ObjectType::Array(arr)) => arr.index_to_variable.get(&sub_index)
+                .or_else(|| arr.index_to_variable.get(&1).filter(|_| 0 < sub_index && sub_index < 0xFF)),
+            Some(ObjectType::Record(rec)) => rec.index_to_variable.get(&sub_index),
+            None => None,
+        };
+        var.map(|var| var.data_type)
+            .ok_or(make_abort_error(ObjectDoesNotExistInObjectDictionary, "".to_string()))
+    }
 }
 
 impl ObjectDirectory {
+    /// Adds a variable. Sub-index 0 of an index without an array / record creates
+    /// a plain variable, other sub-indexes need `add_record()` / `add_array()` first.
+    pub fn add_variable(&mut self, index: u16, sub_index: u8, data_type: DataType, access_type: AccessType,
+                        default: Value, pdo_mappable: bool) -> Result<(), ErrorCode> {
+        let var = Variable {
+            name: format!("{:04X}sub{}", index, sub_index),
+            storage_location: "".to_string(),
+            data_type,
+            default_value: default.clone(),
+            value: default,
+            min: None,
+            max: None,
+            pdo_mappable,
+            access_type,
+            parameter_value: None,
+            default_expression: None,
+            index,
+            sub_index,
+        };
+        match self.index_to_object.get_mut(&index) {
+            Some(ObjectType::Record(record)) => record.add_member(var),
+            Some(ObjectType::Array(array)) => array.add_member(var),
+            _ if sub_index == 0 => return self.insert_object(index, var.name.clone(), ObjectType::Variable(var)),
+            _ => return Err(make_abort_error(SubIndexDoesNotExist, "".to_string())),
+        }
+        Ok(())
+    }
+
+    pub fn add_record(&mut self, index: u16, name: &str) {
+        let record = Record {
+            name: name.to_string(),
+            index,
+            storage_location: "".to_string(),
+            index_to_variable: HashMap::new(),
+            name_to_index: HashMap::new(),
+        };
+        self.add_member(index, name.to_string(), ObjectType::Record(record));
+    }
+
+    pub fn add_array(&mut self, index: u16, name: &str) {
+        let array = Array {
+            name: name.to_string(),
+            index,
+            storage_location: "".to_string(),
+            index_to_variable: HashMap::new(),
+            name_to_index: HashMap::new(),
+        };
+        self.add_member(index, name.to_string(), ObjectType::Array(array));
+    }
+
     pub fn add_member(&mut self, index: u16, name: String, obj: ObjectType) {
-        self.index_to_object.insert(index, obj);
-        self.name_to_index.insert(name, index);
+        if let Err(err) = self.insert_object(index, name, obj) {
+            error!("Errors in adding object {:04X}: {:?}", index, err);
+        }
+    }
+
+    /// Adds / replaces the object at `index`, keeping both maps in sync. With
+    /// `heapless-od` it fails without changing either map when they are full.
+    pub(crate) fn insert_object(&mut self, index: u16, name: String, obj: ObjectType) -> Result<(), ErrorCode> {
+        if self.index_to_object.contains_key(&index) {
+            // The replaced object takes its name along.
+            self.name_to_index.retain(|_, i| *i != index);
+        }
+        check_room(&self.index_to_object, &index)?;
+        check_room(&self.name_to_index, &name)?;
+        insert_into(&mut self.index_to_object, index, obj)?;
+        insert_into(&mut self.name_to_index, name, index)
     }
 
     pub fn add_sub_member(&mut self, index: u16, var: Variable) -> Result<(), String> {
@@ -270,31 +751,43 @@ impl ObjectDirectory {
         }
     }
 
-    pub fn set_value_with_fitting_size(&mut self, index: u16, sub_index: u8, data: &[u8]) {
-        match self.get_mut_variable(index, sub_index) {
-            Err(_) => {}
-            Ok(var) => {
-                if !var.access_type.is_writable() {
-                    return;
-                }
-                if var.data_type.size() > data.len() {
-                    return;
-                }
-                var.default_value.set_data(data[0..var.data_type.size()].to_vec());
-                // info!("set_value_with_fitting_size(), var = {:#x?}", var);
-            }
+    /// Writes the first `data_type.size()` bytes of `data`, used for the zero
+    /// extended values unpacked from PDOs. Unlike `set_value()` longer data is
+    /// fine, but a missing / read-only object or too short data is an error.
+    pub fn set_value_with_fitting_size(&mut self, index: u16, sub_index: u8, data: &[u8])
+        -> Result<(), ErrorCode> {
+        let var = self.target_variable(index, sub_index)?;
+        if !var.access_type.is_writable() {
+            return Err(make_abort_error(AttemptToWriteReadOnlyObject, "".to_string()));
+        }
+        if var.data_type.is_variable_length() {
+            var.value.set_data(string_data(var.data_type, data));
+            return Ok(());
+        }
+        if var.data_type.size() > data.len() {
+            return Err(make_abort_error(DataTypeMismatchLengthTooLow, "".to_string()));
         }
+        var.value.set_data(data[0..var.data_type.size()].to_vec());
+        Ok(())
     }
 
     pub fn set_value(&mut self, index: u16, sub_index: u8, data: &[u8], ignore_access_check: bool)
         -> Result<&Variable, ErrorCode> {
-        match self.get_mut_variable(index, sub_index) {
+        match self.target_variable(index, sub_index) {
             Err(code) => Err(code),
             Ok(var) => {
-                if !ignore_access_check && !var.access_type.is_writable() {
+                if var.access_type.is_const() || (!ignore_access_check && !var.access_type.is_writable()) {
                     return Err(make_abort_error(AttemptToWriteReadOnlyObject, "".to_string()));
                 }
 
+                if var.data_type.is_variable_length() {
+                    if var.data_type == DataType::UnicodeString && !data.len().is_multiple_of(2) {
+                        return Err(make_abort_error(DataTypeMismatchLengthMismatch, "".to_string()));
+                    }
+                    var.value.set_data(string_data(var.data_type, data));
+                    return Ok(var);
+                }
+
                 if var.data_type.size() != data.len() {
                     info!("set_value() error: expect data_type size = {}, input data len = {}, data: {:?}",
                         var.data_type.size(), data.len(), data);
@@ -305,7 +798,7 @@ impl ObjectDirectory {
                     }
                 }
 
-                var.default_value.set_data(data.to_vec());
+                var.value.set_data(data.to_vec());
                 Ok(var)
             }
         }
@@ -328,20 +821,48 @@ impl ObjectDirectory {
         index: u16,
         sub_index: u8,
     ) -> Result<&mut Variable, ErrorCode> {
-        match self.index_to_object.get_mut(&index) {
-            Some(ObjectType::Variable(var)) => {
-                if sub_index == 0 {
-                    Ok(var)
-                } else {
-                    Err(make_abort_error(SubIndexDoesNotExist, "".to_string()))
-                }
-            }
-            Some(ObjectType::Array(arr)) => arr.get_mut_variable(sub_index),
-            Some(ObjectType::Record(rec)) => rec.get_mut_variable(sub_index),
-            None => Err(make_abort_error(ObjectDoesNotExistInObjectDictionary, "".to_string())),
+        variable_of(self.index_to_object.get_mut(&index), sub_index)
+    }
+
+    // The variable changed by a write: the staged copy during a transaction on `index`.
+    fn target_variable(&mut self, index: u16, sub_index: u8) -> Result<&mut Variable, ErrorCode> {
+        match &mut self.transaction {
+            Some((i, staged)) if *i == index => variable_of(Some(staged), sub_index),
+            _ => variable_of(self.index_to_object.get_mut(&index), sub_index),
         }
     }
 
+    /// Starts staging the writes to object `index`: until `commit()` or
+    /// `rollback()`, `set_value()` on it changes a copy while every read still
+    /// sees the current values. Only one transaction can be open at a time.
+    pub fn begin_transaction(&mut self, index: u16) -> Result<(), ErrorCode> {
+        if let Some((open, _)) = self.transaction {
+            return Err(make_abort_error(GeneralError, format!("Transaction on {:#06X} still open", open)));
+        }
+        let obj = self.index_to_object.get(&index)
+            .ok_or(make_abort_error(ObjectDoesNotExistInObjectDictionary, "".to_string()))?;
+        self.transaction = Some((index, obj.clone()));
+        Ok(())
+    }
+
+    /// Applies the staged writes at once and closes the transaction.
+    pub fn commit(&mut self) -> Result<(), ErrorCode> {
+        let (index, staged) = self.transaction.take()
+            .ok_or(make_abort_error(GeneralError, "No open transaction".to_string()))?;
+        // The object is still there, so the staged copy fits in its place.
+        insert_into(&mut self.index_to_object, index, staged)
+    }
+
+    /// Drops the staged writes and closes the transaction, if any.
+    pub fn rollback(&mut self) {
+        self.transaction = None;
+    }
+
+    /// The index and staged copy of the object of the open transaction.
+    pub(crate) fn staged_object(&self) -> Option<(u16, &ObjectType)> {
+        self.transaction.as_ref().map(|(index, staged)| (*index, staged))
+    }
+
     pub fn get_object_by_name(&self, name: &str) -> Option<&ObjectType> {
         if let Some(id) = self.name_to_index.get(name) {
             return self.index_to_object.get(id);
@@ -363,15 +884,27 @@ impl ObjectDirectory {
                 |_| make_section_error(section_name, "Invalid index"))?;
             let name = properties.get("ParameterName").ok_or_else(
                 || make_section_error(section_name, "No ParameterName"))?;
-            let ot: u32 = util::parse_number(properties.get("ObjectType").ok_or_else(
-                || make_section_error(section_name, "No ObjectType"))?);
+            // CiA 306: ObjectType may be omitted, meaning VAR.
+            let ot: u32 = properties.get("ObjectType")
+                .map_or(OBJECT_TYPE_VARIABLE, |ot| util::parse_number(ot));
             match ot {
+                OBJECT_TYPE_DEFTYPE | OBJECT_TYPE_DEFSTRUCT => {
+                    let data_type = if ot == OBJECT_TYPE_DEFTYPE {
+                        resolve_data_type(properties, &self.type_definitions)
+                    } else {
+                        DataType::Unknown
+                    };
+                    self.type_definitions.insert(index, TypeDefinition {
+                        name: name.clone(),
+                        index,
+                        is_struct: ot == OBJECT_TYPE_DEFSTRUCT,
+                        data_type,
+                        members: Vec::new(),
+                    });
+                }
                 OBJECT_TYPE_VARIABLE => {
-                    let variable =
-                        build_variable(properties, self.node_id, name, index, None)?;
-                    self.name_to_index.insert(variable.name.clone(), index);
-                    self.index_to_object
-                        .insert(index, ObjectType::Variable(variable));
+                    let variable = build_variable(self, properties, name, index, None)?;
+                    self.insert_object(index, variable.name.clone(), ObjectType::Variable(variable))?;
                 }
                 OBJECT_TYPE_ARRAY => {
                     let mut array = Array {
@@ -390,19 +923,19 @@ impl ObjectDirectory {
                             sub_index: 0,
                             data_type: DataType::Unsigned8,
                             default_value: Value::new(0u32.to_bytes()),
+                            value: Value::new(0u32.to_bytes()),
                             min: None,
                             max: None,
                             pdo_mappable: false,
                             access_type: AccessType::new(false, false),
                             storage_location: "".to_string(),
                             parameter_value: None,
+                            default_expression: None,
                         };
-                        array.add_member(last_subindex);
-                        array.add_member(
-                            build_variable(properties, self.node_id, name, index, Some(1u8))?
-                        );
+                        array.insert_member(last_subindex);
+                        array.insert_member(build_variable(self, properties, name, index, Some(1u8))?);
                     }
-                    self.add_member(index, name.clone(), ObjectType::Array(array));
+                    self.insert_object(index, name.clone(), ObjectType::Array(array))?;
                 }
                 OBJECT_TYPE_RECORD => {
                     let record = Record {
@@ -415,9 +948,7 @@ impl ObjectDirectory {
                         index_to_variable: HashMap::new(),
                         name_to_index: HashMap::new(),
                     };
-                    self.name_to_index.insert(name.clone(), index);
-                    self.index_to_object
-                        .insert(index, ObjectType::Record(record));
+                    self.insert_object(index, name.clone(), ObjectType::Record(record))?;
                 }
                 _ => { // ignore
                 }
@@ -425,30 +956,48 @@ impl ObjectDirectory {
         } else if let Some((index, sub_index)) = util::is_sub(section_name) {
             let name = properties.get("ParameterName").ok_or_else(
                 || make_section_error(section_name, "No name"))?;
-            let variable = build_variable(properties, self.node_id, name, index, Some(sub_index))?;
-            self.add_sub_member(index, variable).map_err(|err| {
-                make_section_error(section_name, format!("add_sub_member error: {:?}", err).as_str())
-            })?;
+            if self.type_definitions.contains_key(&index) {
+                // DEFSTRUCT member description, sub-index 0 only holds the member count.
+                let data_type = resolve_data_type(properties, &self.type_definitions);
+                if let Some(def) = self.type_definitions.get_mut(&index) {
+                    if sub_index > 0 {
+                        def.members.push((sub_index, data_type));
+                    }
+                }
+                return Ok(());
+            }
+            let variable = build_variable(self, properties, name, index, Some(sub_index))?;
+            // The EDS declares sub-index 0 itself, e.g. the number of mapped objects of a PDO.
+            match self.index_to_object.get_mut(&index) {
+                Some(ObjectType::Record(record)) => record.insert_member(variable),
+                Some(ObjectType::Array(array)) => array.insert_member(variable),
+                _ => return Err(make_section_error(section_name, "No array / record for the sub-object")),
+            }
         } else if let Some(index) = util::is_name(section_name) {
             // Logic related to CompactSubObj
-            let t = properties.get("NrOfEntries").ok_or_else(
-                || make_section_error(section_name, "No NrOfEntries"))?;
-            let num_of_entries = t.parse().map_err(|err| make_section_error(
-                section_name, format!("parsing '{}' error: {:?}", t, err).as_str()))?;
+            let num_of_entries = parse_nr_of_entries(section_name, properties)?;
             if let Some(ObjectType::Array(arr)) = self.index_to_object.get_mut(&index) {
-                if let Some(src_var) = arr.index_to_variable.get(&1u8) {
-                    let cloned_src_var = src_var.clone();
-                    let mut new_vars = Vec::new();
-                    for subindex in 1..=num_of_entries {
-                        let mut var = cloned_src_var.clone();
-                        if let Some(name) = properties.get(&subindex.to_string()) {
+                for subindex in 1..=num_of_entries {
+                    if let Some(name) = properties.get(&subindex.to_string()) {
+                        if let Some(mut var) = compact_entry(arr, subindex) {
                             var.name = name.clone();
-                            var.sub_index = subindex;
-                            new_vars.push(var);
+                            arr.insert_member(var);
                         }
                     }
-                    for var in new_vars {
-                        arr.add_member(var);
+                }
+            }
+        } else if let Some(index) = util::is_value(section_name) {
+            // Per-entry values of a CompactSubObj array, entries not listed keep DefaultValue.
+            let num_of_entries = parse_nr_of_entries(section_name, properties)?;
+            if let Some(ObjectType::Array(arr)) = self.index_to_object.get_mut(&index) {
+                for subindex in 1..=num_of_entries {
+                    if let Some(mut var) = compact_entry(arr, subindex) {
+                        if let Some(value) = get_formatted_value_from_properties(
+                            properties, &subindex.to_string(), self.node_id, &var.data_type) {
+                            var.default_value = value.clone();
+                            var.value = value;
+                            arr.insert_member(var);
+                        }
                     }
                 }
             }
@@ -472,8 +1021,8 @@ impl ObjectDirectory {
                     current_section_name = Some(String::from(name));
                 }
                 ini::Item::Property(key, maybe_value) => {
-                    let value = String::from(maybe_value.unwrap_or_default());
-                    current_properties.insert(String::from(key), value);
+                    let value = String::from(property_value(maybe_value.unwrap_or_default()));
+                    current_properties.insert(String::from(key.trim()), value);
                 }
                 _ => {}  // Ignore for other sections, for example comments / section end.
             }
@@ -481,11 +1030,47 @@ impl ObjectDirectory {
 
         // The last section
         if let Some(section_name) = current_section_name {
+            if self.is_truncated_section(&section_name, &current_properties) {
+                return Err(ErrorCode::TruncatedEds { section_name });
+            }
             self.process_section(&section_name, &current_properties)?
         }
 
         Ok(())
     }
+
+    /// An object section cut before the entries `process_section()` needs for its
+    /// object type, as left at the end of a truncated file: a name always, and for
+    /// a variable (ObjectType omitted or VAR, a compact array's entries, a sub-index)
+    /// a DataType or at least a DefaultValue to infer it from.
+    fn is_truncated_section(&self, section_name: &str, properties: &HashMap<String, String>) -> bool {
+        let needs_type = if util::is_top(section_name) {
+            let ot: u32 = properties.get("ObjectType")
+                .map_or(OBJECT_TYPE_VARIABLE, |ot| util::parse_number(ot));
+            match ot {
+                OBJECT_TYPE_VARIABLE => true,
+                OBJECT_TYPE_ARRAY => properties.contains_key("CompactSubObj"),
+                _ => false,
+            }
+        } else if let Some((index, _)) = util::is_sub(section_name) {
+            !self.type_definitions.contains_key(&index)
+        } else {
+            return false;
+        };
+        !properties.contains_key("ParameterName")
+            || (needs_type && !properties.contains_key("DataType")
+                && !properties.contains_key("DefaultValue"))
+    }
+}
+
+/// The value of an EDS property: everything after the first '=', so values may
+/// contain '=' themselves, without a trailing comment. Only a ';' following a
+/// blank starts a comment, "a;b" in a string stays as it is.
+fn property_value(raw: &str) -> &str {
+    let end = raw.char_indices()
+        .find(|&(i, c)| c == ';' && raw[..i].ends_with([' ', '\t']))
+        .map_or(raw.len(), |(i, _)| i);
+    raw[..end].trim()
 }
 
 fn make_section_error(section_name: &str, more_info: &str) -> ErrorCode {
@@ -495,21 +1080,90 @@ fn make_section_error(section_name: &str, more_info: &str) -> ErrorCode {
     }
 }
 
+fn parse_nr_of_entries(section_name: &str, properties: &HashMap<String, String>) -> Result<u8, ErrorCode> {
+    let t = properties.get("NrOfEntries").ok_or_else(
+        || make_section_error(section_name, "No NrOfEntries"))?;
+    t.parse().map_err(|err| make_section_error(
+        section_name, format!("parsing '{}' error: {:?}", t, err).as_str()))
+}
+
+/// Returns the entry `subindex` of a CompactSubObj array, created from
+/// sub-index 1 if it doesn't exist yet.
+fn compact_entry(arr: &Array, subindex: u8) -> Option<Variable> {
+    if let Some(var) = arr.index_to_variable.get(&subindex) {
+        return Some(var.clone());
+    }
+    arr.index_to_variable.get(&1u8).map(|src_var| {
+        let mut var = src_var.clone();
+        var.sub_index = subindex;
+        var
+    })
+}
+
+/// The stored bytes for a variable length write. A VISIBLE_STRING has no terminator,
+/// so a client padding the transfer with NULs gets the string up to the first NUL.
+fn string_data(data_type: DataType, data: &[u8]) -> Vec<u8> {
+    match data_type {
+        DataType::VisibleString => data.iter().take_while(|&&b| b != 0).cloned().collect(),
+        _ => data.to_vec(),
+    }
+}
+
+/// Reads the DataType property, resolving custom DEFTYPE indexes to their basic type.
+fn resolve_data_type(
+    properties: &HashMap<String, String>,
+    type_definitions: &HashMap<u16, TypeDefinition>,
+) -> DataType {
+    let dt_val: u32 = util::parse_number(
+        properties
+            .get(&String::from("DataType"))
+            .unwrap_or(&String::from("")),
+    );
+    match DataType::from_u32(dt_val) {
+        DataType::Unknown => u16::try_from(dt_val).ok()
+            .and_then(|idx| type_definitions.get(&idx))
+            .map(|def| def.data_type)
+            .unwrap_or(DataType::Unknown),
+        dt => dt,
+    }
+}
+
+/// Guesses the type of an object without DataType from its DefaultValue: only
+/// hex literals say how wide the value is, by their digit count, e.g. "0x0010"
+/// is an Unsigned16. Anything else, decimals included, gives no type.
+fn infer_data_type(default_value: &str) -> Option<DataType> {
+    let v = default_value.trim();
+    let hex = v.strip_prefix("0x").or_else(|| v.strip_prefix("0X"))?;
+    u64::from_str_radix(hex, 16).ok()?;
+    Some(match hex.len() {
+        0..=2 => DataType::Unsigned8,
+        3..=4 => DataType::Unsigned16,
+        5..=8 => DataType::Unsigned32,
+        _ => DataType::Unsigned64,
+    })
+}
+
 fn build_variable(
+    od: &ObjectDirectory,
     properties: &HashMap<String, String>,
-    node_id: u8,
     name: &str,
     index: u16,
     sub_index: Option<u8>,
 ) -> Result<Variable, ErrorCode> {
+    let node_id = od.node_id;
     let storage_location = properties
         .get("StorageLocation")
         .unwrap_or(&String::from(""))
         .clone();
+    // The manufacturer name / version strings are read-only unless the EDS says otherwise.
+    let default_access = match index {
+        REG_MANUFACTURER_DEVICE_NAME | REG_MANUFACTURER_HARDWARE_VERSION | REG_MANUFACTURER_SOFTWARE_VERSION => "ro",
+        _ => "rw",
+    };
     let access_type = AccessType::from_str(
         &properties
             .get("AccessType")
-            .unwrap_or(&String::from("rw"))
+            .map_or(default_access, |s| s.as_str())
             .to_lowercase(),
     )?;
     let pdo_mapping = properties
@@ -519,12 +1173,25 @@ fn build_variable(
         .unwrap_or(0)
         != 0;
 
-    let dt_val = util::parse_number(
-        properties
-            .get(&String::from("DataType"))
-            .unwrap_or(&String::from("")),
-    );
-    let dt = DataType::from_u32(dt_val);
+    let dt = match resolve_data_type(properties, &od.type_definitions) {
+        DataType::Unknown => match od.data_type_fallback {
+            DataTypeFallback::Infer => properties.get("DefaultValue")
+                .and_then(|v| infer_data_type(v))
+                .unwrap_or_else(|| {
+                    warn!("No usable DataType for {:04X}sub{}, the object has an unknown type",
+                        index, sub_index.unwrap_or(0));
+                    DataType::Unknown
+                }),
+            DataTypeFallback::Strict => {
+                let section = match sub_index {
+                    Some(si) => format!("{:04X}sub{}", index, si),
+                    None => format!("{:04X}", index),
+                };
+                return Err(make_section_error(&section, "Missing or invalid DataType"));
+            }
+        },
+        dt => dt,
+    };
 
     let min = get_formatted_value_from_properties(properties, "LowLimit", node_id, &dt);
     let max = get_formatted_value_from_properties(properties, "HighLimit", node_id, &dt);
@@ -534,6 +1201,8 @@ fn build_variable(
     let parameter_value = get_formatted_value_from_properties(
         properties, "ParameterValue", node_id, &dt);
 
+    let value = parameter_value.clone().unwrap_or(default_value.clone());
+
     let variable = Variable {
         name: name.to_owned(),
         storage_location,
@@ -543,10 +1212,501 @@ fn build_variable(
         min,
         max,
         default_value,
+        value,
         parameter_value,
+        default_expression: properties.get("DefaultValue").filter(|v| v.contains("$NODEID")).cloned(),
         index,
         sub_index: sub_index.unwrap_or(0),
     };
 
     Ok(variable)
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::{String, ToString};
+    use core::str::FromStr;
+
+    use crate::data_type::DataType;
+    use crate::error::ErrorCode;
+    use crate::test_util::TEST_EDS;
+    use crate::value::{ByteConvertible, Value};
+
+    use super::{AccessType, DataTypeFallback, infer_data_type, ObjectDirectory, ObjectType};
+    #[cfg(feature = "heapless-od")]
+    use super::OD_CAPACITY;
+
+    const DEFTYPE_EDS: &str = r#"
+[0007]
+ParameterName=UNSIGNED32
+ObjectType=0x5
+DataType=0x0007
+AccessType=ro
+DefaultValue=32
+
+[0021]
+ParameterName=CUSTOM_COUNTER
+ObjectType=0x5
+DataType=0x0007
+AccessType=ro
+DefaultValue=32
+
+[0040]
+ParameterName=CUSTOM_STRUCT
+ObjectType=0x6
+SubNumber=3
+
+[0040sub0]
+ParameterName=Number of entries
+ObjectType=0x7
+DataType=0x0005
+AccessType=ro
+DefaultValue=2
+
+[0040sub1]
+ParameterName=First member
+ObjectType=0x7
+DataType=0x0006
+AccessType=ro
+
+[0040sub2]
+ParameterName=Second member
+ObjectType=0x7
+DataType=0x0021
+AccessType=ro
+
+[2100]
+ParameterName=Custom typed counter
+ObjectType=0x7
+DataType=0x0021
+AccessType=rw
+DefaultValue=5
+"#;
+
+    #[test]
+    fn test_deftype_sections_are_tracked() {
+        let mut od = ObjectDirectory::new(2, DEFTYPE_EDS).unwrap();
+
+        let def = od.type_definition(0x21).unwrap();
+        assert_eq!(def.name(), "CUSTOM_COUNTER");
+        assert!(!def.is_struct());
+        assert_eq!(def.data_type(), DataType::Unsigned32);
+        assert_eq!(od.type_definition(0x7).unwrap().data_type(), DataType::Unsigned32);
+
+        let st = od.type_definition(0x40).unwrap();
+        assert!(st.is_struct());
+        assert_eq!(st.members(), &[(1, DataType::Unsigned16), (2, DataType::Unsigned32)]);
+
+        let var = od.get_variable(0x2100, 0).unwrap();
+        assert_eq!(var.data_type(), DataType::Unsigned32);
+        assert_eq!(var.effective_value().to::<u32>(), 5);
+    }
+
+    #[test]
+    fn test_no_type_definitions_in_plain_eds() {
+        let od = ObjectDirectory::new(2, TEST_EDS).unwrap();
+        assert!(od.type_definition(0x21).is_none());
+    }
+
+    const COMPACT_EDS: &str = r#"
+[3004]
+ParameterName=Compact array
+ObjectType=0x8
+DataType=0x0006
+AccessType=rw
+DefaultValue=7
+CompactSubObj=4
+
+[3004Name]
+NrOfEntries=4
+1=First
+2=Second
+3=Third
+4=Fourth
+
+[3004Value]
+NrOfEntries=3
+1=1
+2=0x20
+3=$NODEID+3
+"#;
+
+    #[test]
+    fn test_compact_array_entry_values() {
+        let mut od = ObjectDirectory::new(2, COMPACT_EDS).unwrap();
+        let expected = [("First", 1u16), ("Second", 0x20), ("Third", 5), ("Fourth", 7)];
+        for (si, (name, value)) in expected.iter().enumerate() {
+            let var = od.get_variable(0x3004, si as u8 + 1).unwrap();
+            assert_eq!(var.name(), *name);
+            assert_eq!(var.default_value().to::<u16>(), *value);
+            assert_eq!(var.effective_value().to::<u16>(), *value);
+        }
+    }
+
+    const NO_DATA_TYPE_EDS: &str = r#"
+[2100]
+ParameterName=Untyped u32
+ObjectType=0x7
+AccessType=rw
+DefaultValue=0x12345678
+
+[2101]
+ParameterName=Untyped decimal
+ObjectType=0x7
+AccessType=rw
+DefaultValue=0
+"#;
+
+    #[test]
+    fn test_data_type_fallback() {
+        let mut od = ObjectDirectory::new(2, NO_DATA_TYPE_EDS).unwrap();
+        let var = od.get_variable(0x2100, 0).unwrap();
+        assert_eq!(var.data_type(), DataType::Unsigned32);
+        assert_eq!(var.default_value().to::<u32>(), 0x12345678);
+        assert_eq!(od.get_variable(0x2101, 0).unwrap().data_type(), DataType::Unknown);
+
+        let res = ObjectDirectory::new_with_fallback(2, NO_DATA_TYPE_EDS, DataTypeFallback::Strict);
+        assert!(matches!(res, Err(ErrorCode::ProcesedSectionFailed { .. })));
+    }
+
+    #[test]
+    fn test_infer_data_type() {
+        assert_eq!(infer_data_type("0x12"), Some(DataType::Unsigned8));
+        assert_eq!(infer_data_type("0x0010"), Some(DataType::Unsigned16));
+        assert_eq!(infer_data_type("0x12345678"), Some(DataType::Unsigned32));
+        assert_eq!(infer_data_type("0x123456789"), Some(DataType::Unsigned64));
+        // Decimals don't tell the width, "0" may as well be an Unsigned32.
+        assert_eq!(infer_data_type("0"), None);
+        assert_eq!(infer_data_type("70000"), None);
+        assert_eq!(infer_data_type("-5"), None);
+        assert_eq!(infer_data_type("$NODEID+0x180"), None);
+        assert_eq!(infer_data_type("hello"), None);
+        assert_eq!(infer_data_type("0xZZ"), None);
+        assert_eq!(infer_data_type(""), None);
+    }
+
+    #[test]
+    fn test_truncated_eds() {
+        let cut = TEST_EDS.find("[2003]").unwrap() + "[2003]\nParameterName=Application string\n".len();
+        let res = ObjectDirectory::new(2, &TEST_EDS[..cut]);
+        assert_eq!(res.err(), Some(ErrorCode::TruncatedEds { section_name: "2003".to_string() }));
+
+        let cut = TEST_EDS.find("[2003]").unwrap() + "[2003]\n".len();
+        let res = ObjectDirectory::new(2, &TEST_EDS[..cut]);
+        assert_eq!(res.err(), Some(ErrorCode::TruncatedEds { section_name: "2003".to_string() }));
+
+        // A VAR has to say its type, or at least give a value to infer it from.
+        let cut = TEST_EDS.find("[2003]").unwrap();
+        let eds = format!("{}[2005]\nParameterName=Cut\nObjectType=0x7\n", &TEST_EDS[..cut]);
+        let res = ObjectDirectory::new(2, &eds);
+        assert_eq!(res.err(), Some(ErrorCode::TruncatedEds { section_name: "2005".to_string() }));
+
+        // ObjectType and DataType are optional, the default value makes it an Unsigned32.
+        let eds = format!("{}[2005]\nParameterName=Untyped\nDefaultValue=0x00000005",
+                          &TEST_EDS[..cut]);
+        let mut od = ObjectDirectory::new(2, &eds).unwrap();
+        let var = od.get_variable(0x2005, 0).unwrap();
+        assert_eq!(var.data_type(), DataType::Unsigned32);
+        assert_eq!(var.default_value().to::<u32>(), 5);
+
+        // Cutting right after a complete section is fine.
+        let cut = TEST_EDS.find("[2003]").unwrap();
+        assert!(ObjectDirectory::new(2, &TEST_EDS[..cut]).is_ok());
+    }
+
+    #[test]
+    fn test_string_writes_change_length() {
+        let mut od = ObjectDirectory::new(2, TEST_EDS).unwrap();
+        od.set_value(0x2003, 0, b"Hi", false).unwrap();
+        assert_eq!(od.get_variable(0x2003, 0).unwrap().effective_value().data(), &b"Hi".to_vec());
+
+        od.set_value(0x2003, 0, b"A much longer string value", false).unwrap();
+        assert_eq!(od.get_variable(0x2003, 0).unwrap().effective_value().to::<String>(),
+                   "A much longer string value");
+
+        // NUL padding ends a VISIBLE_STRING.
+        od.set_value(0x2003, 0, b"abc\0\0\0\0", false).unwrap();
+        assert_eq!(od.get_variable(0x2003, 0).unwrap().effective_value().to::<String>(), "abc");
+    }
+
+    #[test]
+    fn test_iterate_objects_and_variables() {
+        let od = ObjectDirectory::new(2, TEST_EDS).unwrap();
+        let indexes: Vec<u16> = od.iter_objects().map(|(index, _)| index).collect();
+        assert_eq!(indexes.first(), Some(&0x1000));
+        assert!(indexes.windows(2).all(|w| w[0] < w[1]));
+
+        let identity: Vec<(u16, u8)> = od.iter_variables()
+            .filter(|(index, _, _)| *index == 0x1018)
+            .map(|(index, sub_index, var)| {
+                assert_eq!((var.index(), var.sub_index()), (index, sub_index));
+                (index, sub_index)
+            })
+            .collect();
+        assert_eq!(identity, vec![(0x1018, 0), (0x1018, 1), (0x1018, 2), (0x1018, 3), (0x1018, 4)]);
+
+        // Plain variables show up as sub-index 0.
+        assert!(od.iter_variables().any(|(index, sub_index, _)| (index, sub_index) == (0x2000, 0)));
+    }
+
+    #[test]
+    fn test_build_object_directory_in_code() {
+        let mut od = ObjectDirectory::empty(2);
+        od.add_variable(0x1017, 0, DataType::Unsigned16, AccessType::new(true, true),
+                        Value::new(500u16.to_bytes()), false).unwrap();
+        od.add_record(0x1018, "Identity object");
+        od.add_variable(0x1018, 0, DataType::Unsigned8, AccessType::new(true, false),
+                        Value::new(1u8.to_bytes()), false).unwrap();
+        od.add_variable(0x1018, 1, DataType::Unsigned32, AccessType::new(true, false),
+                        Value::new(0x1234u32.to_bytes()), false).unwrap();
+
+        assert_eq!(od.get_variable(0x1017, 0).unwrap().effective_value().to::<u16>(), 500);
+        assert_eq!(od.get_variable(0x1018, 1).unwrap().effective_value().to::<u32>(), 0x1234);
+        assert_eq!(od.declared_largest_sub_index(0x1018), Some(1));
+        // Sub-objects need a record / array.
+        assert!(od.add_variable(0x2000, 1, DataType::Unsigned8, AccessType::new(true, true),
+                                Value::new(vec![0]), false).is_err());
+    }
+
+    #[test]
+    fn test_transaction() {
+        let mut od = ObjectDirectory::new(2, TEST_EDS).unwrap();
+        let mapping = |od: &mut ObjectDirectory, si| od.get_variable(0x1A00, si).unwrap().effective_value().to::<u32>();
+        let count = |od: &mut ObjectDirectory| od.get_variable(0x1A00, 0).unwrap().effective_value().to::<u8>();
+
+        // Reads see the current values until the commit.
+        od.begin_transaction(0x1A00).unwrap();
+        assert!(od.begin_transaction(0x1600).is_err());
+        od.set_value(0x1A00, 2, &0x2001_0010u32.to_le_bytes(), false).unwrap();
+        od.set_value(0x1A00, 0, &[2], false).unwrap();
+        assert_eq!((count(&mut od), mapping(&mut od, 2)), (1, 0));
+        od.commit().unwrap();
+        assert_eq!((count(&mut od), mapping(&mut od, 1), mapping(&mut od, 2)), (2, 0x2000_0020, 0x2001_0010));
+        assert!(od.commit().is_err());
+
+        // Rolled back explicitly.
+        od.begin_transaction(0x1A00).unwrap();
+        od.set_value(0x1A00, 1, &0x3000_0020u32.to_le_bytes(), false).unwrap();
+        od.rollback();
+        assert_eq!(mapping(&mut od, 1), 0x2000_0020);
+        assert!(od.staged_object().is_none());
+    }
+
+    #[test]
+    fn test_sub_entry_count() {
+        let od = ObjectDirectory::new(2, TEST_EDS).unwrap();
+        match od.index_to_object.get(&0x1018) {
+            Some(ObjectType::Record(identity)) => {
+                assert_eq!(identity.len(), 5);
+                assert!(!identity.is_empty());
+                assert_eq!(identity.largest_sub_index(), Some(4));
+            }
+            other => panic!("0x1018 is no record: {:?}", other),
+        }
+        match od.index_to_object.get(&0x1003) {
+            Some(ObjectType::Array(errors)) => assert_eq!(errors.largest_sub_index(), Some(8)),
+            other => panic!("0x1003 is no array: {:?}", other),
+        }
+
+        let mut od = ObjectDirectory::empty(2);
+        od.add_record(0x2100, "Record");
+        match od.index_to_object.get(&0x2100) {
+            Some(ObjectType::Record(record)) => assert_eq!((record.len(), record.largest_sub_index()), (0, None)),
+            other => panic!("0x2100 is no record: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_add_member_maintains_sub_index_0() {
+        let mut od = ObjectDirectory::empty(2);
+        od.add_record(0x2100, "Record");
+        for sub_index in 1..=3 {
+            od.add_variable(0x2100, sub_index, DataType::Unsigned16, AccessType::new(true, true),
+                            Value::new(0u16.to_bytes()), true).unwrap();
+        }
+        let sub0 = od.get_variable(0x2100, 0).unwrap();
+        assert_eq!(sub0.data_type(), DataType::Unsigned8);
+        assert!(!sub0.access_type().is_writable());
+        assert_eq!(sub0.effective_value().to::<u8>(), 3);
+
+        // An existing sub-index 0 follows as well.
+        od.add_array(0x2101, "Array");
+        od.add_variable(0x2101, 0, DataType::Unsigned8, AccessType::new(true, false),
+                        Value::new(vec![0]), false).unwrap();
+        od.add_variable(0x2101, 5, DataType::Unsigned8, AccessType::new(true, true),
+                        Value::new(vec![0]), false).unwrap();
+        assert_eq!(od.get_variable(0x2101, 0).unwrap().effective_value().to::<u8>(), 5);
+
+        // The EDS keeps its own counts, e.g. the number of mapped objects.
+        let mut od = ObjectDirectory::new(2, TEST_EDS).unwrap();
+        assert_eq!(od.get_variable(0x1A00, 0).unwrap().effective_value().to::<u8>(), 1);
+    }
+
+    #[test]
+    fn test_access_type_from_str() {
+        assert_eq!(AccessType::from_str("const").unwrap(), AccessType::new_const());
+        assert!(AccessType::new_const().is_readable());
+        assert!(!AccessType::new_const().is_writable());
+        assert!(!AccessType::from_str("ro").unwrap().is_const());
+        assert_eq!(AccessType::from_str("rww").unwrap(), AccessType::new(true, true));
+        assert_eq!(AccessType::from_str("rwr").unwrap(), AccessType::new(true, true));
+        assert_eq!(AccessType::from_str("xx").unwrap(), AccessType::new(false, false));
+    }
+
+    #[test]
+    fn test_const_object() {
+        let mut od = ObjectDirectory::new(2, TEST_EDS).unwrap();
+        let var = od.get_variable(0x1018, 1).unwrap();
+        assert!(var.access_type().is_const());
+        assert_eq!(var.effective_value().to::<u32>(), 0xABCD);
+        // Not even the application may change a const object.
+        assert!(od.set_value(0x1018, 1, &[1, 0, 0, 0], true).is_err());
+        assert_eq!(od.get_variable(0x1018, 1).unwrap().effective_value().to::<u32>(), 0xABCD);
+    }
+
+    #[test]
+    fn test_min_max_and_range() {
+        let mut od = ObjectDirectory::new(2, TEST_EDS).unwrap();
+        let var = od.get_variable(0x3040, 0).unwrap();
+        assert_eq!(var.min_as::<i64>(), Some(i64::MIN));
+        assert_eq!(var.max_as::<i64>(), Some(i64::MAX));
+        // Wrong type for the limits.
+        assert_eq!(var.min_as::<u32>(), None);
+        assert!(var.in_range(&i64::MIN.to_le_bytes()));
+        assert!(var.in_range(&i64::MAX.to_le_bytes()));
+        assert!(!var.in_range(&[0, 0, 0, 0]));
+
+        let var = od.get_variable(0x3041, 0).unwrap();
+        assert!(var.in_range(&(-100i16).to_le_bytes()));
+        assert!(var.in_range(&100i16.to_le_bytes()));
+        assert!(!var.in_range(&(-101i16).to_le_bytes()));
+        assert!(!var.in_range(&101i16.to_le_bytes()));
+
+        // No limits at all.
+        let var = od.get_variable(0x2001, 0).unwrap();
+        assert_eq!(var.max_as::<u16>(), None);
+        assert!(var.in_range(&u16::MAX.to_le_bytes()));
+    }
+
+    #[test]
+    fn test_sdo_init_payload() {
+        let mut od = ObjectDirectory::new(2, TEST_EDS).unwrap();
+        let var = od.get_variable(0x2001, 0).unwrap();
+        let (cmd, data) = var.sdo_init_payload();
+        assert_eq!(cmd, 0x4B);
+        assert_eq!(data.len(), 2);
+
+        let (cmd, data) = od.get_variable(0x2000, 0).unwrap().sdo_init_payload();
+        assert_eq!(cmd, 0x43);
+        assert_eq!(data, vec![0x78, 0x56, 0x34, 0x12]);
+
+        let (cmd, data) = od.get_variable(0x3040, 0).unwrap().sdo_init_payload();
+        assert_eq!(cmd, 0x41);
+        assert_eq!(data, vec![8, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_object_type_defaults_to_variable() {
+        let eds = "[2000]\nParameterName=No object type\nDataType=0x0006\nAccessType=rw\nDefaultValue=0x1234\n";
+        let mut od = ObjectDirectory::new(2, eds).unwrap();
+        assert!(matches!(od.iter_objects().next(), Some((0x2000, super::ObjectType::Variable(_)))));
+        let var = od.get_variable(0x2000, 0).unwrap();
+        assert_eq!(var.data_type(), DataType::Unsigned16);
+        assert_eq!(var.effective_value().to::<u16>(), 0x1234);
+    }
+
+    #[test]
+    fn test_property_with_equal_sign_and_comment() {
+        let eds = "[2000]\nParameterName=Gain = 2\nObjectType=0x7\nDataType=0x0006\nAccessType=rw\n\
+                   DefaultValue=0x10 ; factory setting\n\
+                   [2001]\nParameterName=Separator\nObjectType=0x7\nDataType=0x0009\nAccessType=rw\nDefaultValue=a;b\n";
+        let mut od = ObjectDirectory::new(2, eds).unwrap();
+        let var = od.get_variable(0x2000, 0).unwrap();
+        assert_eq!(var.name(), "Gain = 2");
+        assert_eq!(var.effective_value().to::<u16>(), 0x10);
+        assert_eq!(od.get_variable(0x2001, 0).unwrap().raw_bytes(), b"a;b");
+    }
+
+    #[test]
+    fn test_manufacturer_strings_default_to_read_only() {
+        let eds = "[1008]\nParameterName=Manufacturer device name\nObjectType=0x7\nDataType=0x0009\nDefaultValue=Dev\n\
+                   [2000]\nParameterName=Other\nObjectType=0x7\nDataType=0x0009\nDefaultValue=Abc\n";
+        let mut od = ObjectDirectory::new(2, eds).unwrap();
+        assert_eq!(*od.get_variable(0x1008, 0).unwrap().access_type(), AccessType::new(true, false));
+        assert_eq!(*od.get_variable(0x2000, 0).unwrap().access_type(), AccessType::new(true, true));
+    }
+
+    #[test]
+    fn test_content_signature() {
+        let mut od = ObjectDirectory::new(2, TEST_EDS).unwrap();
+        let signature = od.content_signature();
+        assert_eq!(ObjectDirectory::new(2, TEST_EDS).unwrap().content_signature(), signature);
+
+        // Written values are not part of the content.
+        od.set_value(0x2001, 0, &[1, 2], false).unwrap();
+        assert_eq!(od.content_signature(), signature);
+
+        let changed = TEST_EDS.replace("DefaultValue=0x12345678", "DefaultValue=0x12345679");
+        assert_ne!(changed, TEST_EDS);
+        assert_ne!(ObjectDirectory::new(2, &changed).unwrap().content_signature(), signature);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        // With heapless-od, deserializing the inline maps takes more than the default test stack.
+        std::thread::Builder::new().stack_size(32 << 20).spawn(|| {
+            let od = ObjectDirectory::new(2, TEST_EDS).unwrap();
+            let json = serde_json::to_string(&od).unwrap();
+            let back: ObjectDirectory = serde_json::from_str(&json).unwrap();
+            assert_eq!(back, od);
+        }).unwrap().join().unwrap();
+    }
+
+    #[test]
+    fn test_replaced_object_drops_its_name() {
+        let mut od = ObjectDirectory::empty(2);
+        od.add_variable(0x2000, 0, DataType::Unsigned8, AccessType::new(true, true),
+                        Value::new(vec![0]), false).unwrap();
+        od.add_record(0x2000, "Renamed");
+        assert_eq!(od.name_to_index.len(), 1);
+        assert_eq!(od.name_to_index.get("Renamed"), Some(&0x2000));
+    }
+
+    #[cfg(feature = "heapless-od")]
+    fn eds_with_variables(count: usize) -> String {
+        (0..count).map(|i| format!(
+            "[{:X}]\nParameterName=Var{}\nObjectType=0x7\nDataType=0x0007\nAccessType=rw\nDefaultValue={}\nPDOMapping=0\n\n",
+            0x2000 + i, i, i)).collect()
+    }
+
+    #[cfg(feature = "heapless-od")]
+    #[test]
+    fn test_heapless_od_fills_to_capacity() {
+        let mut od = ObjectDirectory::new(2, &eds_with_variables(OD_CAPACITY)).unwrap();
+        assert_eq!(od.iter_objects().count(), OD_CAPACITY);
+        let last = 0x2000 + OD_CAPACITY as u16 - 1;
+        assert_eq!(od.get_variable(last, 0).unwrap().effective_value().to::<u32>(), OD_CAPACITY as u32 - 1);
+
+        assert_eq!(ObjectDirectory::new(2, &eds_with_variables(OD_CAPACITY + 1)).unwrap_err(),
+                   ErrorCode::ObjectDirectoryFull { capacity: OD_CAPACITY });
+    }
+
+    #[cfg(feature = "heapless-od")]
+    #[test]
+    fn test_heapless_od_add_variable_when_full() {
+        let mut od = ObjectDirectory::empty(2);
+        for i in 0..OD_CAPACITY as u16 {
+            od.add_variable(0x2000 + i, 0, DataType::Unsigned8, AccessType::new(true, true),
+                            Value::new(vec![0]), false).unwrap();
+        }
+        // Replacing an existing object still works, a new one doesn't fit and changes nothing.
+        assert!(od.add_variable(0x2000, 0, DataType::Unsigned8, AccessType::new(true, true),
+                                Value::new(vec![1]), false).is_ok());
+        assert_eq!(od.add_variable(0x1017, 0, DataType::Unsigned16, AccessType::new(true, true),
+                                   Value::new(vec![0, 0]), false),
+                   Err(ErrorCode::ObjectDirectoryFull { capacity: OD_CAPACITY }));
+        assert!(od.get_variable(0x1017, 0).is_err());
+        assert_eq!((od.index_to_object.len(), od.name_to_index.len()), (OD_CAPACITY, OD_CAPACITY));
+    }
+}
@@ -1,3 +1,4 @@
+use alloc::boxed::Box;
 use alloc::string::ToString;
 use alloc::vec;
 use alloc::vec::Vec;
@@ -8,15 +9,39 @@ use embedded_can::nb::Can;
 use hashbrown::HashMap;
 use log::trace;
 
+use crate::emergency::{EmergencyErrorCode, ErrorRegister};
 use crate::error::{AbortCode, ErrorCode};
-use crate::{debug, info};
-use crate::error::AbortCode::ExceedPDOSize;
-use crate::node::{Node, NodeEvent};
-use crate::object_directory::Variable;
-use crate::util::{create_frame, make_abort_error, vec_to_u64};
+use crate::{debug, error, info};
+use crate::error::AbortCode::{ExceedPDOSize, GeneralParameterIncompatibility, ObjectCannotBeMappedToPDO};
+use crate::node::{Node, NodeEvent, NodeState};
+use crate::object_directory::{ObjectType, Variable};
+use crate::util::{convert_bytes_to_u32, create_frame, make_abort_error, vec_to_u64};
 
 pub(crate) const MAX_PDO_MAPPING_LENGTH: u8 = 64;
 
+/// Called with the COB-ID of a RPDO and its decoded (index, sub_index, value)
+/// fields, once they are written to the object directory.
+pub type RpdoCallback = Box<dyn FnMut(u16, &[(u16, u8, u64)])>;
+
+/// Called with the communication parameter index (0x1400.. / 0x1800..) of a
+/// PDO which could not be processed, and the reason.
+pub type PdoErrorCallback = Box<dyn FnMut(u16, &ErrorCode)>;
+
+// RTR-only transmission types, synchronous and event-driven.
+const TRANSMISSION_TYPE_RTR_SYNC: u8 = 0xFC;
+const TRANSMISSION_TYPE_RTR_EVENT: u8 = 0xFD;
+
+/// Remote transmission requests are not supported, so writing one of the
+/// RTR-only transmission types (0xFC / 0xFD) to sub-index 2 of a PDO
+/// communication parameter is rejected instead of silently never transmitting.
+pub(crate) fn validate_transmission_type(data: &[u8]) -> Result<(), ErrorCode> {
+    match data.first() {
+        Some(&TRANSMISSION_TYPE_RTR_SYNC) | Some(&TRANSMISSION_TYPE_RTR_EVENT) =>
+            Err(make_abort_error(GeneralParameterIncompatibility, "RTR-only transmission type".to_string())),
+        _ => Ok(()),
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct PdoObject {
     // Properties
@@ -30,6 +55,10 @@ pub struct PdoObject {
     transmission_type: u8,
     inhibit_time: u16,
     event_timer: u16,
+    // Time since the event timer last expired, restarted when the timer is rewritten.
+    event_elapsed_ms: u32,
+    // Time left until the TPDO may be sent again, armed with inhibit_time (100us units) on each transmission.
+    inhibit_remaining_us: u32,
 
     // Mapping relative
     num_of_map_objs: u8,
@@ -58,6 +87,13 @@ impl PdoObject {
     pub fn event_timer(&self) -> u16 {
         self.event_timer
     }
+    pub fn is_pdo_valid(&self) -> bool {
+        self.is_pdo_valid
+    }
+    /// Transmission types 0..=240 are bound to SYNC.
+    pub fn is_synchronous(&self) -> bool {
+        self.transmission_type <= 240
+    }
 
     pub fn set_cached_data(&mut self, cached_data: &[u8]) {
         self.cached_data.clear();
@@ -69,39 +105,46 @@ impl PdoObject {
 }
 
 impl PdoObject {
-    fn update_comm_params(&mut self, var: &Variable) -> Option<u16> {
+    fn update_comm_params(&mut self, var: &Variable) -> Result<(), ErrorCode> {
         match var.sub_index() {
-            0 => self.largest_sub_index = var.default_value().to(),
+            0 => self.largest_sub_index = var.effective_value().try_to()?,
             1 => {
-                let t: u32 = var.default_value().to();
+                let t: u32 = var.effective_value().try_to()?;
                 self.is_pdo_valid = (t >> 31 & 0x1) == 0;
+                if !self.is_pdo_valid {
+                    // Data received before the RPDO got disabled is never applied.
+                    self.cached_data.clear();
+                }
                 self._not_used_rtr_allowed = (t >> 30 & 0x1) == 1;
                 self._not_used_is_29bit_can_id = (t >> 29 & 0x1) == 1;
                 self.cob_id = (t & 0xFFFF) as u16;
             }
-            2 => self.transmission_type = var.default_value().to(),
-            3 => self.inhibit_time = var.default_value().to(),
-            5 => self.event_timer = var.default_value().to(),
+            2 => self.transmission_type = var.effective_value().try_to()?,
+            3 => self.inhibit_time = var.effective_value().try_to()?,
+            5 => {
+                self.event_timer = var.effective_value().try_to()?;
+                self.event_elapsed_ms = 0;
+            }
             _ => {}
         }
-        Some(self.cob_id)
+        Ok(())
     }
 
-    fn update_map_params(&mut self, var: &Variable) -> Option<u16> {
+    fn update_map_params(&mut self, var: &Variable) -> Result<(), ErrorCode> {
         // info!("xfguo: update_map_params() 0. var = {:#x?}", var);
         if var.sub_index() == 0 {
-            let t = var.default_value().to();
+            let t = var.effective_value().try_to()?;
             self.num_of_map_objs = t;
             // if var.index == 0x1A01 {
             //     info!("xfguo: update_map_params() 1.1, var = {:#x?}, t = {}", var, t);
             // }
         } else {
-            let t: u32 = var.default_value().to();
             let si = var.sub_index() as usize;
-            self.mappings[si - 1] =
-                ((t >> 16) as u16, ((t >> 8) & 0xFF) as u8, (t & 0xFF) as u8);
+            let mapping = self.mappings.get_mut(si - 1).ok_or_else(|| make_abort_error(ExceedPDOSize,
+                format!("PDO mapping {:#06X} has no entry {}", var.index(), si)))?;
+            *mapping = mapping_of(var.effective_value().try_to()?);
         }
-        None
+        Ok(())
     }
 }
 
@@ -111,6 +154,12 @@ pub struct PdoObjects {
     cob_to_index: HashMap<u16, usize>,
 }
 
+impl Default for PdoObjects {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PdoObjects {
     pub fn new() -> Self {
         let default_pdo = PdoObject {
@@ -122,6 +171,8 @@ impl PdoObjects {
             transmission_type: 0x01,
             inhibit_time: 0,
             event_timer: 0,
+            event_elapsed_ms: 0,
+            inhibit_remaining_us: 0,
             num_of_map_objs: 0,
             mappings: [(0, 0, 0); MAX_PDO_MAPPING_LENGTH as usize],
             total_length: 0,
@@ -131,6 +182,28 @@ impl PdoObjects {
         PdoObjects { pdos, cob_to_index: HashMap::new() }
     }
 
+    /// COB-IDs of the enabled RPDOs.
+    /// Whether an enabled RPDO receives `cob_id`.
+    pub(crate) fn is_rpdo_cob_id(&self, cob_id: u16) -> bool {
+        self.pdos[0..4].iter()
+            .any(|pdo| matches!(pdo, Some(pdo) if pdo.is_pdo_valid && pdo.cob_id == cob_id))
+    }
+
+    pub(crate) fn rpdo_cob_ids(&self) -> Vec<u16> {
+        self.pdos[0..4].iter()
+            .filter_map(|pdo| pdo.as_ref())
+            .filter(|pdo| pdo.is_pdo_valid)
+            .map(|pdo| pdo.cob_id)
+            .collect()
+    }
+
+    /// Restarts the event timers of all PDOs, e.g. when entering Operational.
+    pub(crate) fn restart_event_timers(&mut self) {
+        for pdo in self.pdos.iter_mut().filter_map(|x| x.as_mut()) {
+            pdo.event_elapsed_ms = 0;
+        }
+    }
+
     pub fn get_mut_rpdo_with_cob_id(&mut self, cob_id: u16) -> Result<&mut PdoObject, ErrorCode> {
         let index = *self.cob_to_index.get(&cob_id).ok_or(ErrorCode::NoCobIdInRpdo {cob_id})?;
         let pdo = self.pdos[index].as_mut().ok_or(ErrorCode::NoPdoObjectInIndex {index})?;
@@ -138,9 +211,46 @@ impl PdoObjects {
     }
 }
 
-fn should_trigger_pdo(is_sync: bool, event: NodeEvent, transmission_type: u32, event_times: u32, count: u32) -> bool {
+/// A PDO mapping entry whose object access doesn't fit the PDO direction: a
+/// TPDO can only map readable objects, a RPDO only writable ones.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PdoMappingIssue {
+    /// Index / sub-index of the mapping parameter, e.g. (0x1A00, 1).
+    pub mapping_index: u16,
+    pub mapping_sub_index: u8,
+    /// Index / sub-index of the mapped object.
+    pub index: u16,
+    pub sub_index: u8,
+}
+
+/// Splits a mapping entry into (index, sub_index, bit-length).
+fn mapping_of(entry: u32) -> (u16, u8, u8) {
+    ((entry >> 16) as u16, ((entry >> 8) & 0xFF) as u8, (entry & 0xFF) as u8)
+}
+
+/// The slot in `PdoObjects::pdos` of the PDO of communication parameter
+/// `comm_index`: 0..4 for the RPDOs 0x1400..=0x1403, 4..8 for the TPDOs
+/// 0x1800..=0x1803.
+fn slot_for_comm_index(comm_index: u16) -> Option<usize> {
+    match comm_index {
+        0x1400..=0x1403 => Some((comm_index - 0x1400) as usize),
+        0x1800..=0x1803 => Some((comm_index - 0x1800) as usize + 4),
+        _ => None,
+    }
+}
+
+/// Whether CiA 301 (7.3.5) reserves `cob_id` for NMT, SDO, heartbeat, LSS
+/// and other services, so that no PDO may use it.
+fn is_restricted_cob_id(cob_id: u16) -> bool {
+    matches!(cob_id, 0x000..=0x07F | 0x101..=0x180 | 0x581..=0x5FF | 0x601..=0x67F
+        | 0x6E0..=0x6FF | 0x701..=0x77F | 0x780..=0x7FF)
+}
+
+/// `count` is the SYNC counter for SYNC events and the elapsed ms for timer events.
+fn should_trigger_pdo(is_sync: bool, event: NodeEvent, pdo: &mut PdoObject, count: u32) -> bool {
+    let transmission_type = pdo.transmission_type as u32;
     if is_sync {
-        if transmission_type == 0 || transmission_type > 240 || count % transmission_type != 0 {
+        if transmission_type == 0 || transmission_type > 240 || !count.is_multiple_of(transmission_type) {
             // info!("xfguo: should_trigger_pdo 1.1.1, count = {}, transmission_type = {}", count, transmission_type);
             return false;
         }
@@ -150,10 +260,16 @@ fn should_trigger_pdo(is_sync: bool, event: NodeEvent, transmission_type: u32, e
             // info!("xfguo: transmit_pdo_messages 1.1.2, count = {}, tt = {}", count, transmission_type);
             return false;
         }
-        if event_times == 0 || count % event_times != 0 {
-            // info!("xfguo: transmit_pdo_messages 1.1.3, count = {}, event_timer = {}", count, event_times);
+        // The PDO fires once its own event timer elapsed, at most once per tick
+        // however many periods the tick covers.
+        if pdo.event_timer == 0 {
+            return false;
+        }
+        pdo.event_elapsed_ms = pdo.event_elapsed_ms.saturating_add(count);
+        if pdo.event_elapsed_ms < pdo.event_timer as u32 {
             return false;
         }
+        pdo.event_elapsed_ms %= pdo.event_timer as u32;
     }
     true
 }
@@ -161,36 +277,119 @@ fn should_trigger_pdo(is_sync: bool, event: NodeEvent, transmission_type: u32, e
 impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
 
     // RPDO section
-    pub(crate) fn save_rpdo_messages(&mut self, is_sync: bool, event: NodeEvent, count: u32) {
-        for pdo in self.pdo_objects.pdos[0..4].iter_mut().filter_map(|x| x.as_mut()) {
-            let tt = pdo.transmission_type as u32;
-
+    /// Applies the received RPDOs due for this event, returning the failures
+    /// as (communication parameter index, error).
+    pub(crate) fn save_rpdo_messages(&mut self, is_sync: bool, event: NodeEvent, count: u32)
+        -> Vec<(u16, ErrorCode)> {
+        let mut failures = Vec::new();
+        for index in 0..4 {
+            let Some(pdo) = self.pdo_objects.pdos[index].as_mut() else { continue };
             if !pdo.is_pdo_valid
-                || !should_trigger_pdo(is_sync, event, tt, pdo.event_timer as u32, count)
+                || !should_trigger_pdo(is_sync, event, pdo, count)
                 || pdo.cached_data.is_empty() {
                 continue
             }
 
             debug!("save_rpdo_messages() 1.3, count = {}, pdo = {:#x?} ", count, pdo);
-            let mapping_lengths: Vec<u8> = pdo.mappings[..pdo.num_of_map_objs as usize]
-                .iter()
-                .map(|(_, _, l)| *l)
-                .collect();
-
-            let unpacked_data = unpack_data(&pdo.cached_data, &mapping_lengths);
-            if unpacked_data.len() < pdo.num_of_map_objs as usize {
-                // TODO(zephyr): Error, do we need to send EMGY msg?
-                info!("error: unmatch length: unpacked_data = {:?}, mapping = {:?}", unpacked_data, pdo.mappings);
-                continue;
+            let data = core::mem::take(&mut pdo.cached_data);
+            let comm_index = 0x1400 + index as u16;
+            failures.extend(self.write_rpdo_payload(index, &data).into_iter().map(|ec| (comm_index, ec)));
+        }
+        failures
+    }
+
+    /// Writes the payload of a RPDO straight into the mapped objects, as if a
+    /// frame with this COB-ID had been received and its SYNC (if any) had
+    /// come. Meant for tests and for replaying captured traffic.
+    ///
+    /// Fails if the COB-ID belongs to no enabled RPDO, if the payload is too
+    /// short for the mapping, or with the first object that can't be written.
+    pub fn apply_rpdo(&mut self, cob_id: u16, data: &[u8]) -> Result<(), ErrorCode> {
+        let index = *self.pdo_objects.cob_to_index.get(&cob_id).ok_or(ErrorCode::NoCobIdInRpdo {cob_id})?;
+        let rpdo = self.pdo_objects.get_mut_rpdo_with_cob_id(cob_id)?;
+        if !rpdo.is_pdo_valid {
+            return Err(ErrorCode::NoCobIdInRpdo {cob_id});
+        }
+        let needed = rpdo.total_length.div_ceil(8) as usize;
+        if data.len() < needed {
+            return Err(make_abort_error(AbortCode::DataTypeMismatchLengthTooLow,
+                format!("RPDO {:#x} needs {} bytes, got {}", cob_id, needed, data.len())));
+        }
+        match self.write_rpdo_payload(index, data).into_iter().next() {
+            Some(ec) => Err(ec),
+            None => Ok(()),
+        }
+    }
+
+    /// Unpacks a RPDO payload into the mapped objects and calls the RPDO
+    /// callback. Failed writes are reported by an emergency and returned.
+    fn write_rpdo_payload(&mut self, index: usize, data: &[u8]) -> Vec<ErrorCode> {
+        let Some(pdo) = self.pdo_objects.pdos[index].as_ref() else { return Vec::new() };
+        let cob_id = pdo.cob_id;
+        let mappings = pdo.mappings;
+        let num_of_map_objs = pdo.num_of_map_objs as usize;
+        let mapping_lengths: Vec<u8> = mappings[..num_of_map_objs]
+            .iter()
+            .map(|(_, _, l)| *l)
+            .collect();
+
+        let unpacked_data = unpack_data(data, &mapping_lengths);
+        if unpacked_data.len() < num_of_map_objs {
+            // TODO(zephyr): Error, do we need to send EMGY msg?
+            info!("error: unmatch length: unpacked_data = {:?}, mapping = {:?}", unpacked_data, mappings);
+            return vec![make_abort_error(AbortCode::DataTypeMismatchLengthTooLow, "".to_string())];
+        }
+
+        let mut failures = Vec::new();
+        let mut fields = Vec::with_capacity(num_of_map_objs);
+        for (idx, &(i, si, _)) in mappings.iter().enumerate().take(num_of_map_objs) {
+            let (data, _) = unpacked_data[idx];
+            match self.object_directory.set_value_with_fitting_size(i, si, &data.to_le_bytes()) {
+                Ok(_) => fields.push((i, si, data)),
+                Err(ec) => {
+                    error!("Failed to apply RPDO {:#x} to {:#x}sub{}: {:?}", cob_id, i, si, ec);
+                    failures.push(ec);
+                }
             }
+        }
+        if let Some(callback) = self.rpdo_callback.as_mut() {
+            callback(cob_id, &fields);
+        }
 
-            for (idx, &(i, si, _)) in pdo.mappings.iter().enumerate().take(pdo.num_of_map_objs as usize) {
-                let (data, _) = unpacked_data[idx];
-                self.object_directory.set_value_with_fitting_size(i, si, &data.to_le_bytes());
+        if !failures.is_empty() {
+            if let Err(ec) = self.trigger_emergency(
+                EmergencyErrorCode::PdoNotProcessed, ErrorRegister::GenericError, &cob_id.to_le_bytes()) {
+                error!("Errors in reporting a failed RPDO: {:?}", ec);
             }
+        }
+        failures
+    }
 
-            pdo.clear_cached_data();
+    /// Checks the mappings of every enabled PDO against the direction-access
+    /// rules, returning all inconsistent entries.
+    pub fn pdo_mapping_issues(&mut self) -> Vec<PdoMappingIssue> {
+        let mut issues = Vec::new();
+        for (i, pdo) in self.pdo_objects.pdos.iter().enumerate() {
+            let pdo = match pdo {
+                Some(pdo) if pdo.is_pdo_valid => pdo,
+                _ => continue,
+            };
+            let is_tpdo = i >= 4;
+            let mapping_index = if is_tpdo { 0x1A00 + i as u16 - 4 } else { 0x1600 + i as u16 };
+            for (si, &(index, sub_index, _)) in pdo.mappings.iter()
+                .take(pdo.num_of_map_objs as usize).enumerate() {
+                let ok = match self.object_directory.get_mut_variable(index, sub_index) {
+                    Ok(var) if is_tpdo => var.access_type().is_readable(),
+                    Ok(var) => var.access_type().is_writable(),
+                    Err(_) => false,
+                };
+                if !ok {
+                    issues.push(PdoMappingIssue {
+                        mapping_index, mapping_sub_index: si as u8 + 1, index, sub_index });
+                }
+            }
         }
+        issues
     }
 
     fn validate_pdo_mappings(&mut self, pdo: &PdoObject, index: u16) -> Result<(), ErrorCode> {
@@ -201,50 +400,209 @@ impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
         Ok(())
     }
 
-    fn calculate_total_length(pdo: &PdoObject) -> u8 {
+    fn calculate_total_length(pdo: &PdoObject) -> u32 {
         pdo.mappings.iter()
             .take(pdo.num_of_map_objs as usize)
-            .map(|mapping| mapping.2)
+            .map(|mapping| mapping.2 as u32)
             .sum()
     }
 
+    fn validate_total_length(pdo: &PdoObject, index: u16) -> Result<u32, ErrorCode> {
+        let total_length = Node::<CAN>::calculate_total_length(pdo);
+        if total_length > MAX_PDO_MAPPING_LENGTH as u32 {
+            return Err(make_abort_error(ExceedPDOSize,
+                format!("PDO mapping {:#06X} maps {} bits", index, total_length)));
+        }
+        Ok(total_length)
+    }
+
+    /// Checks writing `data` to the mapping parameter `index` / `sub_index`
+    /// against the rest of the mapping before it reaches the object directory,
+    /// so that a rejected write leaves both the entry and the PDO as they were.
+    pub(crate) fn validate_pdo_mapping_write(&mut self, index: u16, sub_index: u8, data: &[u8])
+                                             -> Result<(), ErrorCode> {
+        let Some(Some(mut pdo)) = slot_for_comm_index(index & !0x0200)
+            .map(|slot| self.pdo_objects.pdos[slot].clone()) else {
+            return Ok(());
+        };
+        // A write of the wrong size is rejected by the write itself.
+        if sub_index == 0 {
+            let &[count] = data else { return Ok(()) };
+            pdo.num_of_map_objs = count;
+            self.validate_pdo_mappings(&pdo, index)?;
+        } else {
+            let (Some(mapping), Ok(bytes)) = (pdo.mappings.get_mut(sub_index as usize - 1), <[u8; 4]>::try_from(data))
+                else { return Ok(()) };
+            *mapping = mapping_of(u32::from_le_bytes(bytes));
+        }
+        Node::<CAN>::validate_total_length(&pdo, index).map(|_| ())
+    }
+
+    /// Checks the staged mapping of the open transaction on `index` as a whole:
+    /// every counted entry is mappable and together they fit in a PDO.
+    pub(crate) fn validate_staged_pdo_mapping(&mut self, index: u16) -> Result<(), ErrorCode> {
+        let Some(Some(mut pdo)) = slot_for_comm_index(index & !0x0200)
+            .map(|slot| self.pdo_objects.pdos[slot].clone()) else {
+            return Ok(());
+        };
+        let entries = match self.object_directory.staged_object() {
+            Some((i, ObjectType::Record(rec))) if i == index => {
+                let count: u8 = rec.index_to_variable().get(&0)
+                    .ok_or(make_abort_error(ObjectCannotBeMappedToPDO, "".to_string()))?
+                    .effective_value().try_to()?;
+                (1..=count).map(|si| rec.index_to_variable().get(&si)
+                    .map(|var| var.effective_value().data().to_vec())
+                    .ok_or(make_abort_error(ObjectCannotBeMappedToPDO, "".to_string())))
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+            _ => return Ok(()),
+        };
+        for (si, data) in entries.iter().enumerate() {
+            self.validate_pdo_mapping_params_on_setting(index, si as u8 + 1, data)?;
+            let mapping = pdo.mappings.get_mut(si).ok_or_else(|| make_abort_error(ExceedPDOSize,
+                format!("PDO mapping {:#06X} has no entry {}", index, si + 1)))?;
+            *mapping = mapping_of(convert_bytes_to_u32(data)?);
+        }
+        pdo.num_of_map_objs = entries.len() as u8;
+        Node::<CAN>::validate_total_length(&pdo, index).map(|_| ())
+    }
+
+    /// Registers the callback receiving the decoded fields of every applied RPDO.
+    pub fn on_rpdo(&mut self, callback: RpdoCallback) {
+        self.rpdo_callback = Some(callback);
+    }
+
+    /// Registers the callback receiving every RPDO which couldn't be applied
+    /// and every TPDO which couldn't be sent.
+    pub fn on_pdo_error(&mut self, callback: PdoErrorCallback) {
+        self.pdo_error_callback = Some(callback);
+    }
+
+    pub(crate) fn report_pdo_errors(&mut self, failures: Vec<(u16, ErrorCode)>) {
+        for (comm_index, err) in failures {
+            error!("Errors in processing PDO {:#x}: {:x?}", comm_index, err);
+            if let Some(callback) = self.pdo_error_callback.as_mut() {
+                callback(comm_index, &err);
+            }
+        }
+    }
+
+    /// The PDO of communication parameter `comm_index` (0x1400.. for RPDOs,
+    /// 0x1800.. for TPDOs), if the node has one.
+    fn pdo_for_comm_index(&self, comm_index: u16) -> Option<&PdoObject> {
+        self.pdo_objects.pdos[slot_for_comm_index(comm_index)?].as_ref()
+    }
+
+    /// The active (index, sub_index, bit-length) mappings of the PDO of
+    /// communication parameter `comm_index`.
+    pub fn pdo_mapping(&self, comm_index: u16) -> Option<Vec<(u16, u8, u8)>> {
+        self.pdo_for_comm_index(comm_index)
+            .map(|pdo| pdo.mappings[..pdo.num_of_map_objs as usize].to_vec())
+    }
+
+    /// The COB-ID of the PDO of communication parameter `comm_index`.
+    pub fn pdo_cob_id(&self, comm_index: u16) -> Option<u16> {
+        self.pdo_for_comm_index(comm_index).map(|pdo| pdo.cob_id)
+    }
+
+    /// Whether the PDO of communication parameter `comm_index` is enabled.
+    pub fn pdo_is_valid(&self, comm_index: u16) -> Option<bool> {
+        self.pdo_for_comm_index(comm_index).map(|pdo| pdo.is_pdo_valid)
+    }
+
+    /// Enables / disables the PDO of the communication parameter `comm_index`
+    /// (0x1400.. for RPDOs, 0x1800.. for TPDOs) by flipping bit 31 of its COB-ID.
+    pub fn set_pdo_valid(&mut self, comm_index: u16, valid: bool) -> Result<(), ErrorCode> {
+        let index = slot_for_comm_index(comm_index).ok_or(
+            make_abort_error(AbortCode::ObjectDoesNotExistInObjectDictionary, "".to_string()))?;
+        let cob_id: u32 = self.object_directory.get_variable(comm_index, 1)?.effective_value().try_to()?;
+        let cob_id = if valid { cob_id & !(1 << 31) } else { cob_id | (1 << 31) };
+        if index < 4 {
+            self.check_rpdo_cob_id(index, cob_id)?;
+        }
+        let var = self.object_directory.set_value(comm_index, 1, &cob_id.to_le_bytes(), true)?.clone();
+        self.update(&var)
+    }
+
+    /// Rejects enabling RPDO `index` (0..4) with a restricted COB-ID, or with
+    /// one another enabled RPDO already receives, only one of them would ever
+    /// get the frames.
+    pub(crate) fn check_rpdo_cob_id(&self, index: usize, raw_cob_id: u32) -> Result<(), ErrorCode> {
+        if raw_cob_id >> 31 & 0x1 == 1 {
+            return Ok(());
+        }
+        let cob_id = (raw_cob_id & 0xFFFF) as u16;
+        if is_restricted_cob_id(cob_id) {
+            return Err(make_abort_error(GeneralParameterIncompatibility,
+                                        format!("COB-ID {:#x} is restricted", cob_id)));
+        }
+        let taken = self.pdo_objects.pdos[0..4].iter().enumerate()
+            .any(|(i, pdo)| i != index && matches!(pdo, Some(pdo) if pdo.is_pdo_valid && pdo.cob_id == cob_id));
+        if taken {
+            return Err(make_abort_error(GeneralParameterIncompatibility,
+                                        format!("COB-ID {:#x} is used by another RPDO", cob_id)));
+        }
+        Ok(())
+    }
+
     pub(crate) fn update(&mut self, var: &Variable) -> Result<(), ErrorCode> {
-        let (pdo_type, pdo_index) = (var.index() >> 8, (var.index() & 0xF) as usize);
+        let pdo_type = var.index() >> 8;
         if !(0x14..0x1C).contains(&pdo_type) {
             return Ok(());
         }
-        let index = pdo_index + (pdo_type >= 0x18) as usize * 4;
+        // Only the first 4 RPDOs / TPDOs are supported.
+        // 0x16xx / 0x1Axx are the mapping parameters of the PDO of 0x14xx / 0x18xx.
+        let index = slot_for_comm_index(var.index() & !0x0200).ok_or(
+            ErrorCode::NoPdoObjectInIndex { index: (var.index() & 0xFF) as usize })?;
+        if pdo_type == 0x14 && var.sub_index() == 1 {
+            self.check_rpdo_cob_id(index, var.effective_value().try_to()?)?;
+        }
         let mut pdo = self.pdo_objects.pdos[index].take().ok_or(
             ErrorCode::NoPdoObjectInIndex {index})?;
         let result = (|| -> Result<(), ErrorCode> {
             if pdo_type & 0x3 < 2 {
-                pdo.update_comm_params(var);
-                self.pdo_objects.cob_to_index.insert(pdo.cob_id, pdo_index);
-            } else {
-                pdo.update_map_params(var);
-                if var.sub_index() == 0 {
-                    self.validate_pdo_mappings(&pdo, var.index())?;
-                    pdo.total_length = Node::<CAN>::calculate_total_length(&pdo);
-                    if pdo.total_length > MAX_PDO_MAPPING_LENGTH {
-                        return Err(make_abort_error(ExceedPDOSize, "".to_string()));
-                    }
+                pdo.update_comm_params(var)?;
+                if index < 4 {
+                    self.pdo_objects.cob_to_index.insert(pdo.cob_id, index);
                 }
+            } else {
+                pdo.update_map_params(var)?;
+                // Rewriting a counted entry changes the size as much as the count does.
+                self.validate_pdo_mappings(&pdo, var.index())?;
+                pdo.total_length = Node::<CAN>::validate_total_length(&pdo, var.index())? as u8;
             }
             Ok(())
         })();
         self.pdo_objects.pdos[index] = Some(pdo);
+        if pdo_type & 0x3 < 2 && var.sub_index() == 1 {
+            self.update_acceptance_filter();
+        }
         result
     }
 
     // TPDO section
+    /// Sends the TPDOs due for this event, returning the failures as
+    /// (communication parameter index, error). A failing TPDO doesn't keep
+    /// the others from being sent.
     pub(crate) fn transmit_pdo_messages(&mut self, is_sync: bool, event: NodeEvent, count: u32)
-        -> Result<(), ErrorCode> {
+        -> Vec<(u16, ErrorCode)> {
         trace!("xfguo: transmit_pdo_messages 0");
+        let mut failures = Vec::new();
+        if is_sync && !self.in_sync_window() {
+            info!("Skip synchronous TPDOs outside the synchronous window");
+            return failures;
+        }
         for index in 4..8 {
-            let pdo = self.pdo_objects.pdos[index].take().ok_or(ErrorCode::NoPdoObjectInIndex {index})?;
+            let comm_index = 0x1800 + index as u16 - 4;
+            let mut pdo = match self.pdo_objects.pdos[index].take() {
+                Some(pdo) => pdo,
+                None => {
+                    failures.push((comm_index, ErrorCode::NoPdoObjectInIndex { index }));
+                    continue;
+                }
+            };
             let result = (|| -> Result<(), ErrorCode> {
-                let tt = pdo.transmission_type as u32;
-                if !pdo.is_pdo_valid || !should_trigger_pdo(is_sync, event, tt, pdo.event_timer as u32, count) {
+                if !pdo.is_pdo_valid || !should_trigger_pdo(is_sync, event, &mut pdo, count) {
                     return Ok(())
                 }
 
@@ -253,22 +611,60 @@ impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
                 let mappings = pdo.mappings[..pdo.num_of_map_objs as usize].to_vec();
                 let frame = self.gen_pdo_frame(pdo.cob_id, pdo.num_of_map_objs, mappings)?;
                 self.transmit(&frame);
+                pdo.inhibit_remaining_us = pdo.inhibit_time as u32 * 100;
                 Ok(())
             })();
             self.pdo_objects.pdos[index] = Some(pdo);
-            result?
+            if let Err(err) = result {
+                failures.push((comm_index, err));
+            }
+        }
+        failures
+    }
+
+    /// Sends the TPDO of communication parameter `comm_index` (0x1800..) right
+    /// away with the current values of the mapped objects, whatever its
+    /// transmission type. With `honor_inhibit`, a TPDO still within its inhibit
+    /// time (0x18xx sub 3) is not sent. Returns whether a frame was sent,
+    /// a disabled TPDO or a node not Operational never sends.
+    pub fn trigger_tpdo(&mut self, comm_index: u16, honor_inhibit: bool) -> Result<bool, ErrorCode> {
+        let index = slot_for_comm_index(comm_index).filter(|index| *index >= 4).ok_or(
+            make_abort_error(AbortCode::ObjectDoesNotExistInObjectDictionary, "".to_string()))?;
+        let mut pdo = self.pdo_objects.pdos[index].take().ok_or(ErrorCode::NoPdoObjectInIndex { index })?;
+        let result = (|| -> Result<bool, ErrorCode> {
+            if self.state != NodeState::Operational || !pdo.is_pdo_valid
+                || (honor_inhibit && pdo.inhibit_remaining_us > 0) {
+                return Ok(false);
+            }
+            let mappings = pdo.mappings[..pdo.num_of_map_objs as usize].to_vec();
+            let frame = self.gen_pdo_frame(pdo.cob_id, pdo.num_of_map_objs, mappings)?;
+            self.transmit(&frame);
+            pdo.inhibit_remaining_us = pdo.inhibit_time as u32 * 100;
+            Ok(true)
+        })();
+        self.pdo_objects.pdos[index] = Some(pdo);
+        result
+    }
+
+    /// Called on each timer event, runs down the inhibit times of the TPDOs.
+    pub(crate) fn pdo_inhibit_tick(&mut self, elapsed_ms: u32) {
+        for pdo in self.pdo_objects.pdos[4..8].iter_mut().filter_map(|x| x.as_mut()) {
+            pdo.inhibit_remaining_us = pdo.inhibit_remaining_us.saturating_sub(elapsed_ms.saturating_mul(1000));
         }
-        Ok(())
     }
 
     fn gen_pdo_frame(&mut self, cob_id: u16, num_of_map_objs: u8, mappings: Vec<(u16, u8, u8)>)
                                 -> Result<CAN::Frame, ErrorCode> {
+        let total_bits: usize = mappings.iter().take(num_of_map_objs as usize).map(|(_, _, bits)| *bits as usize).sum();
+        if total_bits > 64 {
+            return Err(ErrorCode::ByteLengthExceedsLimit);
+        }
         let mut data_pairs = Vec::new();
         for (idx, sub_idx, bits) in mappings.iter().take(num_of_map_objs as usize) {
             let variable = self.object_directory.get_variable(*idx, *sub_idx)
                 .map_err(|_| ErrorCode::VariableNotFound { index: *idx, sub_index: *sub_idx })?;
 
-            let data = vec_to_u64(variable.default_value().data());
+            let data = vec_to_u64(variable.effective_value().data());
             data_pairs.push((data, *bits));
         }
 
@@ -277,39 +673,70 @@ impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
     }
 }
 
+fn bit_mask(bits: u8) -> u64 {
+    if bits >= 64 { u64::MAX } else { (1 << bits) - 1 }
+}
+
+/// Packs the mapped values into a PDO payload. Like every value on the wire the
+/// payload is little-endian: the first mapped object starts at bit 0 of byte 0.
 fn pack_data(vec: &[(u64, u8)]) -> Vec<u8> {
     let mut merged = 0u64;
     let mut total_bits = 0usize;
 
-    for &(data, bits) in vec.iter().rev() {
-        merged |= (data & ((1 << bits) - 1)) << total_bits;
+    for &(data, bits) in vec {
+        if total_bits < 64 {
+            merged |= (data & bit_mask(bits)) << total_bits;
+        }
         total_bits += bits as usize;
     }
 
-    merged.to_be_bytes()[8 - (total_bits + 7) / 8..].to_vec()
+    merged.to_le_bytes()[..total_bits.div_ceil(8).min(8)].to_vec()
 }
 
+/// Reverse of `pack_data()`, splits a PDO payload into the mapped values.
 fn unpack_data(vec: &[u8], bits: &[u8]) -> Vec<(u64, u8)> {
     let mut data = vec_to_u64(vec);
     let mut res = Vec::new();
 
-    for &bit in bits.iter().rev() {
-        let mask = (1 << bit) - 1;
-        res.push(((data & mask), bit));
-        data >>= bit;
+    for &bit in bits {
+        res.push((data & bit_mask(bit), bit));
+        data = data.checked_shr(bit as u32).unwrap_or(0);
     }
 
-    res.into_iter().rev().collect()
+    res
 }
 
 #[cfg(test)]
 mod tests {
+    use alloc::rc::Rc;
+    use core::cell::RefCell;
+
     use super::*;
+    use crate::test_util::{MockCan, TEST_EDS, TEST_NODE_ID, frame, new_node, sdo_read, sdo_write_u16, sdo_write_u32, sdo_write_u8, send, take_tx};
+
+    #[test]
+    fn test_pdo_mapping_issues() {
+        let eds = TEST_EDS.replace("DefaultValue=0x20000020", "DefaultValue=0x20050020") + r#"
+[2005]
+ParameterName=Write only u32
+ObjectType=0x7
+DataType=0x0007
+AccessType=wo
+DefaultValue=0
+PDOMapping=1
+"#;
+        let mut node = Node::new(TEST_NODE_ID, &eds, MockCan::default()).unwrap();
+        assert_eq!(node.pdo_mapping_issues(), vec![PdoMappingIssue {
+            mapping_index: 0x1A00, mapping_sub_index: 1, index: 0x2005, sub_index: 0 }]);
+
+        let mut node = Node::new(TEST_NODE_ID, TEST_EDS, MockCan::default()).unwrap();
+        assert!(node.pdo_mapping_issues().is_empty());
+    }
 
     fn cut_data_with_bits(vec: &Vec<(u64, u8)>) -> Vec<(u64, u8)> {
         let mut res: Vec<(u64, u8)> = Vec::new();
         for (data, bits) in vec {
-            res.push((data & ((1 << bits) - 1), *bits));
+            res.push((data & bit_mask(*bits), *bits));
         }
         res
     }
@@ -325,10 +752,428 @@ mod tests {
         let packet = pack_data(&initial_data);
         info!("xfguo: packet = {:?}", packet);
 
-        let result_data = unpack_data(&packet, &vec![12, 20, 9]);
+        let result_data = unpack_data(&packet, &[12, 20, 9]);
         info!("xfguo: unpacked = {:?}", result_data);
 
         let cutted_data = cut_data_with_bits(&initial_data);
         assert_eq!(result_data, cutted_data);
     }
+
+    #[test]
+    fn test_pack_data_is_little_endian() {
+        // The first mapped object occupies the lowest bits of the first byte.
+        assert_eq!(pack_data(&[(0x1234, 16), (0x56, 8)]), vec![0x34, 0x12, 0x56]);
+        assert_eq!(pack_data(&[(0x12345678, 32)]), vec![0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(unpack_data(&[0x34, 0x12, 0x56], &[16, 8]), vec![(0x1234, 16), (0x56, 8)]);
+        assert_eq!(pack_data(&[(u64::MAX, 64)]), vec![0xFF; 8]);
+    }
+
+    #[test]
+    fn test_tpdo_payload_matches_stored_bytes() {
+        let mut node = new_node();
+        send(&mut node, frame(0x000, &[0x01, TEST_NODE_ID]));
+        let tpdo = send(&mut node, frame(0x080, &[]));
+        let tpdo = tpdo.iter().find(|f| f.cob_id() == 0x182).expect("no TPDO on SYNC");
+
+        let stored = node.object_directory.get_variable(0x2000, 0).unwrap().effective_value().data().clone();
+        assert_eq!(stored, vec![0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(tpdo.data(), stored.as_slice());
+    }
+
+    #[test]
+    fn test_rpdo_values_are_not_byte_swapped() {
+        let mut node = new_node();
+        send(&mut node, frame(0x000, &[0x01, TEST_NODE_ID]));
+        // RPDO1 maps 0x2001 (u16) and 0x2002 (u8), applied on SYNC.
+        assert!(send(&mut node, frame(0x202, &[0x12, 0x34, 0x56])).is_empty());
+        send(&mut node, frame(0x080, &[]));
+
+        let u16_var = node.object_directory.get_variable(0x2001, 0).unwrap();
+        assert_eq!(u16_var.effective_value().data(), &vec![0x12, 0x34]);
+        assert_eq!(u16_var.effective_value().to::<u16>(), 0x3412);
+        assert_eq!(node.object_directory.get_variable(0x2002, 0).unwrap().effective_value().to::<u8>(), 0x56);
+        assert_eq!(sdo_read(&mut node, 0x2001, 0)[4..6], [0x12, 0x34]);
+    }
+
+    #[test]
+    fn test_set_pdo_valid() {
+        let mut node = new_node();
+        send(&mut node, frame(0x000, &[0x01, TEST_NODE_ID]));
+
+        node.set_pdo_valid(0x1800, false).unwrap();
+        assert!(!node.pdo_objects().pdos[4].as_ref().unwrap().is_pdo_valid());
+        assert_eq!(node.object_directory.get_variable(0x1800, 1).unwrap().effective_value().to::<u32>(),
+                   0x8000_0182);
+        assert!(send(&mut node, frame(0x080, &[])).iter().all(|f| f.cob_id() != 0x182));
+
+        node.set_pdo_valid(0x1800, true).unwrap();
+        assert!(send(&mut node, frame(0x080, &[])).iter().any(|f| f.cob_id() == 0x182));
+
+        // A disabled RPDO ignores its frames.
+        node.set_pdo_valid(0x1400, false).unwrap();
+        send(&mut node, frame(0x202, &[0x12, 0x34, 0x56]));
+        send(&mut node, frame(0x080, &[]));
+        assert_eq!(node.object_directory.get_variable(0x2001, 0).unwrap().effective_value().to::<u16>(), 0);
+
+        assert!(node.set_pdo_valid(0x2000, true).is_err());
+        assert!(node.set_pdo_valid(0x1404, true).is_err());
+    }
+
+    #[test]
+    fn test_pdo_mapping_introspection() {
+        let mut node = new_node();
+        assert_eq!(node.pdo_mapping(0x1400), Some(vec![(0x2001, 0, 16), (0x2002, 0, 8)]));
+        assert_eq!(node.pdo_mapping(0x1800), Some(vec![(0x2000, 0, 32)]));
+
+        assert_eq!(sdo_write_u8(&mut node, 0x1A00, 0, 0)[0], 0x60);
+        assert_eq!(sdo_write_u32(&mut node, 0x1A00, 1, 0x20010010)[0], 0x60);
+        assert_eq!(sdo_write_u32(&mut node, 0x1A00, 2, 0x20020008)[0], 0x60);
+        assert_eq!(sdo_write_u8(&mut node, 0x1A00, 0, 2)[0], 0x60);
+        assert_eq!(node.pdo_mapping(0x1800), Some(vec![(0x2001, 0, 16), (0x2002, 0, 8)]));
+
+        assert_eq!(node.pdo_cob_id(0x1800), Some(0x182));
+        assert_eq!(node.pdo_cob_id(0x1400), Some(0x202));
+        assert_eq!(node.pdo_is_valid(0x1800), Some(true));
+        node.set_pdo_valid(0x1800, false).unwrap();
+        assert_eq!(node.pdo_is_valid(0x1800), Some(false));
+
+        assert_eq!(node.pdo_mapping(0x1600), None);
+        assert_eq!(node.pdo_cob_id(0x1804), None);
+    }
+
+    #[test]
+    fn test_rpdo_cob_id_collision_rejected() {
+        let eds = format!("{}\n[1401]\nParameterName=RPDO communication parameter 2\nObjectType=0x9\nSubNumber=2\n\
+            \n[1401sub0]\nParameterName=Highest sub-index supported\nObjectType=0x7\nDataType=0x0005\nAccessType=ro\nDefaultValue=1\n\
+            \n[1401sub1]\nParameterName=COB-ID used by RPDO\nObjectType=0x7\nDataType=0x0007\nAccessType=rw\nDefaultValue=$NODEID+0x300\n",
+                          TEST_EDS);
+        let mut node = Node::new(TEST_NODE_ID, &eds, MockCan::default()).unwrap();
+        node.init().unwrap();
+        take_tx(&mut node);
+
+        // General parameter incompatibility, 0x06040043.
+        assert_eq!(sdo_write_u32(&mut node, 0x1401, 1, 0x202), vec![0x80, 0x01, 0x14, 1, 0x43, 0x00, 0x04, 0x06]);
+        assert_eq!(node.object_directory.get_variable(0x1401, 1).unwrap().effective_value().to::<u32>(), 0x302);
+        assert_eq!(node.pdo_cob_id(0x1401), Some(0x302));
+        assert_eq!(node.set_pdo_valid(0x1401, true), Ok(()));
+
+        // A disabled RPDO may share the COB-ID, until it gets enabled.
+        assert_eq!(sdo_write_u32(&mut node, 0x1401, 1, 0x8000_0202)[0], 0x60);
+        assert!(node.set_pdo_valid(0x1401, true).is_err());
+
+        // Free once RPDO1 moves away.
+        assert_eq!(sdo_write_u32(&mut node, 0x1400, 1, 0x210)[0], 0x60);
+        assert_eq!(sdo_write_u32(&mut node, 0x1401, 1, 0x202)[0], 0x60);
+        assert_eq!(node.pdo_cob_id(0x1401), Some(0x202));
+    }
+
+    #[test]
+    fn test_rpdo_cob_id_outside_default_range() {
+        let mut node = new_node();
+        send(&mut node, frame(0x000, &[0x01, TEST_NODE_ID]));
+        take_tx(&mut node);
+
+        // Restricted COB-IDs, e.g. heartbeat and the own SDO, are rejected with 0x06040043.
+        for cob_id in [0x701u32, 0x602, 0x000] {
+            assert_eq!(sdo_write_u32(&mut node, 0x1400, 1, cob_id)[4..8], [0x43, 0x00, 0x04, 0x06]);
+        }
+        assert_eq!(node.pdo_cob_id(0x1400), Some(0x202));
+
+        // Any other COB-ID is received, not only those of the 0x200..=0x57F RPDO range.
+        assert_eq!(sdo_write_u32(&mut node, 0x1400, 1, 0x190)[0], 0x60);
+        send(&mut node, frame(0x190, &[0x34, 0x12, 0x56]));
+        send(&mut node, frame(0x080, &[]));
+        assert_eq!(node.object_directory.get_variable(0x2001, 0).unwrap().effective_value().to::<u16>(), 0x1234);
+        assert_eq!(node.object_directory.get_variable(0x2002, 0).unwrap().effective_value().to::<u8>(), 0x56);
+    }
+
+    #[test]
+    fn test_boolean_bits_round_trip() {
+        // RPDO1 and TPDO1 both map three booleans into the bits 0..2 of one byte.
+        let mut eds = TEST_EDS
+            .replace("AccessType=rw\nDefaultValue=2\n", "AccessType=rw\nDefaultValue=3\n")
+            .replace("DefaultValue=0x20010010", "DefaultValue=0x22010001")
+            .replace("DefaultValue=0x20020008", "DefaultValue=0x22020001")
+            .replace("AccessType=rw\nDefaultValue=1\nPDOMapping=0\n\n[1A00sub1]",
+                     "AccessType=rw\nDefaultValue=3\nPDOMapping=0\n\n[1A00sub1]")
+            .replace("DefaultValue=0x20000020", "DefaultValue=0x22010001")
+            .replace("Mapping entry 2\nObjectType=0x7\nDataType=0x0007\nAccessType=rw\nDefaultValue=0\n",
+                     "Mapping entry 2\nObjectType=0x7\nDataType=0x0007\nAccessType=rw\nDefaultValue=0x22020001\n");
+        for (section, mapping) in [("1600sub3", 0x2203_0001u32), ("1A00sub3", 0x2203_0001)] {
+            eds += &format!("\n[{}]\nParameterName=Mapping entry 3\nObjectType=0x7\nDataType=0x0007\n\
+                             AccessType=rw\nDefaultValue={:#x}\nPDOMapping=0\n", section, mapping);
+        }
+        for index in 0x2201..=0x2203 {
+            eds += &format!("\n[{:X}]\nParameterName=Flag\nObjectType=0x7\nDataType=0x0001\n\
+                             AccessType=rw\nDefaultValue=0\nPDOMapping=1\n", index);
+        }
+        let mut node = Node::new(TEST_NODE_ID, &eds, MockCan::default()).unwrap();
+        node.init().unwrap();
+        send(&mut node, frame(0x000, &[0x01, TEST_NODE_ID]));
+        assert_eq!(node.pdo_mapping(0x1400), Some(vec![(0x2201, 0, 1), (0x2202, 0, 1), (0x2203, 0, 1)]));
+
+        // The other bits of the byte don't belong to any flag.
+        send(&mut node, frame(0x202, &[0b1111_0101]));
+        let tx = send(&mut node, frame(0x080, &[]));
+        let flags: Vec<u8> = (0x2201..=0x2203)
+            .map(|i| node.object_directory.get_variable(i, 0).unwrap().effective_value().to::<u8>())
+            .collect();
+        assert_eq!(flags, vec![1, 0, 1]);
+        let tpdo = tx.iter().find(|f| f.cob_id() == 0x182).unwrap();
+        assert_eq!(tpdo.data(), &[0b101]);
+    }
+
+    #[test]
+    fn test_boolean_is_one_bit_in_pdo_and_one_byte_over_sdo() {
+        let eds = format!("{}\n[2201]\nParameterName=Flag\nObjectType=0x7\nDataType=0x0001\n\
+                           AccessType=rw\nDefaultValue=0\nPDOMapping=1\n", TEST_EDS);
+        let mut node = Node::new(TEST_NODE_ID, &eds, MockCan::default()).unwrap();
+        node.init().unwrap();
+        take_tx(&mut node);
+
+        // Only a 1 bit mapping is accepted.
+        assert_eq!(sdo_write_u8(&mut node, 0x1A00, 0, 0)[0], 0x60);
+        assert_eq!(sdo_write_u32(&mut node, 0x1A00, 1, 0x2201_0008),
+                   vec![0x80, 0x00, 0x1A, 1, 0x41, 0x00, 0x04, 0x06]);
+        assert_eq!(sdo_write_u32(&mut node, 0x1A00, 1, 0x2201_0001)[0], 0x60);
+        assert_eq!(sdo_write_u8(&mut node, 0x1A00, 0, 1)[0], 0x60);
+        assert_eq!(node.pdo_mapping(0x1800), Some(vec![(0x2201, 0, 1)]));
+        assert_eq!(node.pdo_objects().pdos[4].as_ref().unwrap().total_length(), 1);
+
+        // SDO reads and writes one byte.
+        assert_eq!(sdo_write_u8(&mut node, 0x2201, 0, 1)[0], 0x60);
+        assert_eq!(sdo_read(&mut node, 0x2201, 0), vec![0x4F, 0x01, 0x22, 0, 1, 0, 0, 0]);
+        assert_eq!(sdo_write_u16(&mut node, 0x2201, 0, 1)[0], 0x80);
+
+        send(&mut node, frame(0x000, &[0x01, TEST_NODE_ID]));
+        let tx = send(&mut node, frame(0x080, &[]));
+        assert_eq!(tx.iter().find(|f| f.cob_id() == 0x182).unwrap().data(), &[1]);
+    }
+
+    #[test]
+    fn test_rtr_only_transmission_type_rejected() {
+        let mut node = new_node();
+        for tt in [0xFC, 0xFD] {
+            // General parameter incompatibility, 0x06040043.
+            assert_eq!(sdo_write_u8(&mut node, 0x1800, 2, tt), vec![0x80, 0x00, 0x18, 2, 0x43, 0x00, 0x04, 0x06]);
+            assert_eq!(sdo_write_u8(&mut node, 0x1400, 2, tt)[0], 0x80);
+        }
+        assert_eq!(sdo_read(&mut node, 0x1800, 2)[4], 1);
+        assert_eq!(node.pdo_objects().pdos[4].as_ref().unwrap().transmission_type(), 1);
+        assert_eq!(sdo_write_u8(&mut node, 0x1800, 2, 0xFE)[0], 0x60);
+    }
+
+    #[test]
+    fn test_over_long_pdo_mapping_is_an_error() {
+        let mut node = new_node();
+        let mappings = vec![(0x2000, 0, 32), (0x2000, 0, 32), (0x2002, 0, 8)];
+        assert_eq!(node.gen_pdo_frame(0x182, 3, mappings.clone()).unwrap_err(), ErrorCode::ByteLengthExceedsLimit);
+        let frame = node.gen_pdo_frame(0x182, 2, mappings).unwrap();
+        assert_eq!(frame.data(), &[0x78, 0x56, 0x34, 0x12, 0x78, 0x56, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn test_trigger_tpdo() {
+        let mut node = new_node();
+        assert_eq!(sdo_write_u32(&mut node, 0x2000, 0, 0xCAFE_F00D)[0], 0x60);
+        // PDOs are only sent when operational.
+        assert_eq!(node.trigger_tpdo(0x1800, false), Ok(false));
+        assert!(take_tx(&mut node).is_empty());
+
+        // Not a SYNC: a manual trigger sends anyway.
+        send(&mut node, frame(0x000, &[0x01, 2]));
+        take_tx(&mut node);
+        assert_eq!(node.trigger_tpdo(0x1800, false), Ok(true));
+        let tx = take_tx(&mut node);
+        assert_eq!(tx.len(), 1);
+        assert_eq!(tx[0].cob_id(), 0x182);
+        assert_eq!(tx[0].data(), &[0x0D, 0xF0, 0xFE, 0xCA]);
+
+        // Inhibit time of 2ms (20 * 100us).
+        assert_eq!(sdo_write_u16(&mut node, 0x1800, 3, 20)[0], 0x60);
+        assert_eq!(node.trigger_tpdo(0x1800, true), Ok(true));
+        assert_eq!(node.trigger_tpdo(0x1800, true), Ok(false));
+        assert_eq!(node.trigger_tpdo(0x1800, false), Ok(true));
+        node.event_timer_callback();
+        node.event_timer_callback();
+        assert_eq!(node.trigger_tpdo(0x1800, true), Ok(true));
+        assert_eq!(take_tx(&mut node).len(), 3);
+
+        node.set_pdo_valid(0x1800, false).unwrap();
+        assert_eq!(node.trigger_tpdo(0x1800, false), Ok(false));
+        assert!(node.trigger_tpdo(0x1400, false).is_err());
+        assert!(node.trigger_tpdo(0x1804, false).is_err());
+    }
+
+    #[test]
+    fn test_event_timer_rewrite_restarts_period() {
+        let mut node = new_node();
+        assert_eq!(sdo_write_u8(&mut node, 0x1800, 2, 0xFE)[0], 0x60);
+        assert_eq!(sdo_write_u16(&mut node, 0x1800, 5, 5)[0], 0x60);
+        send(&mut node, frame(0x000, &[0x01, TEST_NODE_ID]));
+        take_tx(&mut node);
+
+        let mut tpdo_times = Vec::new();
+        for ms in 1..=20 {
+            if ms == 8 {
+                // The new period counts from the write, not from the start.
+                assert_eq!(sdo_write_u16(&mut node, 0x1800, 5, 4)[0], 0x60);
+            }
+            node.event_timer_callback();
+            if take_tx(&mut node).iter().any(|f| f.cob_id() == 0x182) {
+                tpdo_times.push(ms);
+            }
+        }
+        assert_eq!(tpdo_times, vec![5, 11, 15, 19]);
+    }
+
+    #[test]
+    fn test_long_timer_tick_fires_event_pdo_once() {
+        let mut node = new_node();
+        assert_eq!(sdo_write_u8(&mut node, 0x1800, 2, 0xFE)[0], 0x60);
+        assert_eq!(sdo_write_u16(&mut node, 0x1800, 5, 10)[0], 0x60);
+        send(&mut node, frame(0x000, &[0x01, TEST_NODE_ID]));
+        take_tx(&mut node);
+
+        node.timer_tick(1000);
+        assert_eq!(take_tx(&mut node).iter().filter(|f| f.cob_id() == 0x182).count(), 1);
+        // The phase is kept: 3 + 7 ms complete the next period.
+        node.timer_tick(3);
+        assert!(take_tx(&mut node).is_empty());
+        node.timer_tick(7);
+        assert_eq!(take_tx(&mut node).iter().filter(|f| f.cob_id() == 0x182).count(), 1);
+    }
+
+    #[test]
+    fn test_rpdo_callback() {
+        let mut node = new_node();
+        let received = Rc::new(RefCell::new(Vec::new()));
+        let recorded = received.clone();
+        node.on_rpdo(Box::new(move |cob_id, fields| recorded.borrow_mut().push((cob_id, fields.to_vec()))));
+
+        send(&mut node, frame(0x000, &[0x01, TEST_NODE_ID]));
+        send(&mut node, frame(0x202, &[0x34, 0x12, 0x56]));
+        assert!(received.borrow().is_empty());
+        send(&mut node, frame(0x080, &[]));
+        assert_eq!(*received.borrow(), vec![(0x202, vec![(0x2001, 0, 0x1234), (0x2002, 0, 0x56)])]);
+    }
+
+    #[test]
+    fn test_tpdo_mapping_missing_object_reported() {
+        // TPDO1 maps 0x20FF, which isn't in the object directory.
+        let eds = TEST_EDS.replace("DefaultValue=0x20000020", "DefaultValue=0x20FF0020");
+        let mut node = Node::new(TEST_NODE_ID, &eds, MockCan::default()).unwrap();
+        node.init().unwrap();
+        let errors = Rc::new(RefCell::new(Vec::new()));
+        let recorded = errors.clone();
+        node.on_pdo_error(Box::new(move |comm_index, err| recorded.borrow_mut().push((comm_index, err.clone()))));
+        let expected = (0x1800, ErrorCode::VariableNotFound { index: 0x20FF, sub_index: 0 });
+        // Entering Operational sends the TPDOs a first time.
+        send(&mut node, frame(0x000, &[0x01, TEST_NODE_ID]));
+        assert_eq!(*errors.borrow(), vec![expected.clone()]);
+        errors.borrow_mut().clear();
+
+        let tx = send(&mut node, frame(0x080, &[]));
+        assert!(tx.iter().all(|f| f.cob_id() != 0x182));
+        assert_eq!(*errors.borrow(), vec![expected]);
+    }
+
+    fn map_rpdo1_to_u32(node: &mut Node<MockCan>) {
+        assert_eq!(sdo_write_u8(node, 0x1600, 0, 0)[0], 0x60);
+        assert_eq!(sdo_write_u32(node, 0x1600, 1, 0x2000_0020)[0], 0x60);
+        assert_eq!(sdo_write_u8(node, 0x1600, 0, 1)[0], 0x60);
+    }
+
+    #[test]
+    fn test_rpdo_accepts_padded_frame() {
+        let mut node = new_node();
+        map_rpdo1_to_u32(&mut node);
+        send(&mut node, frame(0x000, &[0x01, TEST_NODE_ID]));
+
+        let resp = send(&mut node, frame(0x202, &[0x78, 0x56, 0x34, 0x12, 0xAA, 0xBB, 0xCC, 0xDD]));
+        assert!(resp.is_empty(), "unexpected frames: {:x?}", resp);
+        send(&mut node, frame(0x080, &[]));
+        assert_eq!(node.object_directory.get_variable(0x2000, 0).unwrap().effective_value().to::<u32>(),
+                   0x1234_5678);
+    }
+
+    #[test]
+    fn test_rpdo_to_read_only_object_triggers_emergency() {
+        // RPDO1 maps the read-only device type 0x1000 in front of 0x2002.
+        let eds = TEST_EDS.replace("DefaultValue=0x20010010", "DefaultValue=0x10000020");
+        let mut node = Node::new(TEST_NODE_ID, &eds, MockCan::default()).unwrap();
+        node.init().unwrap();
+        send(&mut node, frame(0x000, &[0x01, TEST_NODE_ID]));
+        let device_type = node.object_directory.get_variable(0x1000, 0).unwrap().effective_value().to::<u32>();
+
+        assert!(send(&mut node, frame(0x202, &[0x11, 0x22, 0x33, 0x44, 0x56])).is_empty());
+        let resp = send(&mut node, frame(0x080, &[]));
+        let emcy = resp.iter().find(|f| f.cob_id() == 0x82).expect("no emergency");
+        assert_eq!(emcy.data()[0..5], [0x10, 0x82, 0x01, 0x02, 0x02]);
+
+        assert_eq!(node.object_directory.get_variable(0x1000, 0).unwrap().effective_value().to::<u32>(), device_type);
+        // The writable part of the mapping is still applied.
+        assert_eq!(node.object_directory.get_variable(0x2002, 0).unwrap().effective_value().to::<u8>(), 0x56);
+    }
+
+    #[test]
+    fn test_rpdo_too_short_triggers_emergency() {
+        let mut node = new_node();
+        map_rpdo1_to_u32(&mut node);
+        send(&mut node, frame(0x000, &[0x01, TEST_NODE_ID]));
+
+        let resp = send(&mut node, frame(0x202, &[0x78, 0x56]));
+        let emcy = resp.iter().find(|f| f.cob_id() == 0x82).expect("no emergency");
+        assert_eq!(emcy.data()[0..2], [0x10, 0x82]);
+    }
+
+    #[test]
+    fn test_apply_rpdo() {
+        let mut node = new_node();
+        // Map RPDO1 (0x202) to the u32 0x2000, no NMT start or SYNC needed.
+        assert_eq!(sdo_write_u8(&mut node, 0x1600, 0, 0)[0], 0x60);
+        assert_eq!(sdo_write_u32(&mut node, 0x1600, 1, 0x2000_0020)[0], 0x60);
+        assert_eq!(sdo_write_u8(&mut node, 0x1600, 0, 1)[0], 0x60);
+
+        node.apply_rpdo(0x202, &[0x78, 0x56, 0x34, 0x12]).unwrap();
+        assert_eq!(sdo_read(&mut node, 0x2000, 0)[4..8], [0x78, 0x56, 0x34, 0x12]);
+        assert!(node.apply_rpdo(0x202, &[0x01, 0x02]).is_err());
+        assert!(node.apply_rpdo(0x203, &[0x01, 0x02, 0x03, 0x04]).is_err());
+        assert_eq!(sdo_read(&mut node, 0x2000, 0)[4..8], [0x78, 0x56, 0x34, 0x12]);
+        assert!(take_tx(&mut node).is_empty());
+    }
+
+    #[test]
+    fn test_total_length_follows_mapping_entry_writes() {
+        let mut node = new_node();
+        assert_eq!(sdo_write_u8(&mut node, 0x1A00, 0, 0)[0], 0x60);
+        assert_eq!(sdo_write_u32(&mut node, 0x1A00, 1, 0x2000_0020)[0], 0x60);
+        assert_eq!(sdo_write_u32(&mut node, 0x1A00, 2, 0x2001_0010)[0], 0x60);
+        assert_eq!(sdo_write_u8(&mut node, 0x1A00, 0, 2)[0], 0x60);
+        assert_eq!(node.pdo_objects.pdos[4].as_ref().unwrap().total_length(), 48);
+
+        // Entry 2 rewritten without going through sub-index 0.
+        assert_eq!(sdo_write_u32(&mut node, 0x1A00, 2, 0x2002_0008)[0], 0x60);
+        assert_eq!(node.pdo_objects.pdos[4].as_ref().unwrap().total_length(), 40);
+        send(&mut node, frame(0x000, &[0x01, 2]));
+        let tpdo = send(&mut node, frame(0x080, &[]));
+        let tpdo = tpdo.iter().find(|f| f.cob_id() == 0x182).expect("no TPDO on SYNC");
+        assert_eq!(tpdo.dlc(), 5);
+
+        // A counted entry growing past 64 bits is rejected.
+        assert_eq!(sdo_write_u32(&mut node, 0x1A00, 2, 0x2000_0040)[0], 0x80);
+        // ... before the write, the entry, the mapping and the TPDO are left as they were.
+        assert_eq!(sdo_read(&mut node, 0x1A00, 2), vec![0x43, 0x00, 0x1A, 2, 0x08, 0x00, 0x02, 0x20]);
+        assert_eq!(node.pdo_mapping(0x1800), Some(vec![(0x2000, 0, 0x20), (0x2002, 0, 0x08)]));
+        let tpdo = send(&mut node, frame(0x080, &[]));
+        let tpdo = tpdo.iter().find(|f| f.cob_id() == 0x182).expect("no TPDO on SYNC");
+        assert_eq!(tpdo.dlc(), 5);
+
+        // So is a count taking in entries past 64 bits.
+        assert_eq!(sdo_write_u8(&mut node, 0x1A00, 0, 1)[0], 0x60);
+        assert_eq!(sdo_write_u32(&mut node, 0x1A00, 2, 0x2000_0040)[0], 0x60);
+        assert_eq!(sdo_write_u8(&mut node, 0x1A00, 0, 2)[0], 0x80);
+        assert_eq!(sdo_read(&mut node, 0x1A00, 0)[4], 1);
+        assert_eq!(node.pdo_mapping(0x1800), Some(vec![(0x2000, 0, 0x20)]));
+    }
 }
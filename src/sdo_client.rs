@@ -0,0 +1,358 @@
+use embedded_can::Frame;
+use embedded_can::nb::Can;
+
+use crate::constant::{COB_FUNC_RECEIVE_SDO, COB_FUNC_TRANSMIT_SDO};
+use crate::error::{AbortCode, ErrorCode};
+use crate::prelude::*;
+use crate::util::{create_frame_checked, get_cob_id};
+use crate::value::Value;
+
+/// Number of empty `receive()` polls a transaction waits for each response by default.
+pub const DEFAULT_SDO_TIMEOUT_POLLS: u32 = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SdoError {
+    /// The server did not answer within the configured number of polls.
+    Timeout,
+    /// The server aborted the transfer.
+    Abort(AbortCode),
+    /// The frame could not be built or sent, or the response was malformed.
+    Transport(ErrorCode),
+}
+
+/// One SDO transfer against the object (index, sub_index) of a remote node.
+///
+/// A transaction builds the requests, only accepts responses from the addressed
+/// server for the addressed object, and turns aborts and missing responses into
+/// `SdoError`s. It owns no CAN interface, so it can run on any `Can` implementation.
+pub struct SdoTransaction {
+    node_id: u8,
+    index: u16,
+    sub_index: u8,
+    timeout_polls: u32,
+}
+
+impl SdoTransaction {
+    pub fn new(node_id: u8, index: u16, sub_index: u8) -> Self {
+        SdoTransaction { node_id, index, sub_index, timeout_polls: DEFAULT_SDO_TIMEOUT_POLLS }
+    }
+
+    pub fn with_timeout(mut self, timeout_polls: u32) -> Self {
+        self.timeout_polls = timeout_polls;
+        self
+    }
+
+    /// Reads the object, with an expedited or a segmented transfer depending on the server's answer.
+    pub fn upload<CAN: Can>(&self, can: &mut CAN) -> Result<Value, SdoError> where CAN::Frame: Frame + Debug {
+        let resp = self.request(can, &self.initiate_request(0x40, &[]))?;
+        let cmd = resp[0];
+        if cmd >> 5 != 0x2 {
+            return Err(self.abort(can, AbortCode::CommandSpecifierNotValidOrUnknown));
+        }
+        self.check_object(&resp)?;
+        if cmd & 0x2 != 0 {
+            // Expedited, n (if indicated) is the number of unused bytes.
+            let n = if cmd & 0x1 != 0 { ((cmd >> 2) & 0x3) as usize } else { 0 };
+            return Ok(Value::new(resp[4..8 - n].to_vec()));
+        }
+        // Size indicated (s=1): the segments have to add up to it.
+        let size = (cmd & 0x1 != 0)
+            .then(|| u32::from_le_bytes([resp[4], resp[5], resp[6], resp[7]]) as usize);
+
+        let mut data = Vec::new();
+        let mut toggle = 0u8;
+        loop {
+            let resp = self.request(can, &[0x60 | toggle << 4])?;
+            if resp[0] >> 5 != 0x0 {
+                return Err(self.abort(can, AbortCode::CommandSpecifierNotValidOrUnknown));
+            }
+            if (resp[0] >> 4) & 0x1 != toggle {
+                return Err(self.abort(can, AbortCode::ToggleBitNotAlternated));
+            }
+            let n = ((resp[0] >> 1) & 0x7) as usize;
+            data.extend_from_slice(&resp[1..8 - n]);
+            let last = resp[0] & 0x1 != 0;
+            if size.is_some_and(|size| data.len() > size || (last && data.len() != size)) {
+                return Err(self.abort(can, AbortCode::DataTypeMismatchLengthMismatch));
+            }
+            if last {
+                return Ok(Value::new(data));
+            }
+            toggle ^= 1;
+        }
+    }
+
+    /// Writes the object, expedited for 1 to 4 bytes and segmented otherwise.
+    /// An empty value is a segmented transfer of size 0, expedited ones carry
+    /// at least 1 byte.
+    pub fn download<CAN: Can>(&self, can: &mut CAN, data: &[u8]) -> Result<(), SdoError>
+        where CAN::Frame: Frame + Debug {
+        if (1..=4).contains(&data.len()) {
+            let cmd = 0x23 | (((4 - data.len()) as u8) << 2);
+            let resp = self.request(can, &self.initiate_request(cmd, data))?;
+            return self.check_download_response(can, &resp);
+        }
+
+        let size = (data.len() as u32).to_le_bytes();
+        let resp = self.request(can, &self.initiate_request(0x21, &size))?;
+        self.check_download_response(can, &resp)?;
+
+        let mut toggle = 0u8;
+        // An empty value still takes a last, empty segment.
+        let chunks: Vec<&[u8]> = if data.is_empty() { vec![&[]] } else { data.chunks(7).collect() };
+        let mut chunks = chunks.into_iter().peekable();
+        while let Some(chunk) = chunks.next() {
+            let last = if chunks.peek().is_none() { 1 } else { 0 };
+            let cmd = toggle << 4 | (((7 - chunk.len()) as u8) << 1) | last;
+            let mut req = vec![cmd];
+            req.extend_from_slice(chunk);
+            let resp = self.request(can, &req)?;
+            if resp[0] != 0x20 | toggle << 4 {
+                return Err(self.abort(can, AbortCode::ToggleBitNotAlternated));
+            }
+            toggle ^= 1;
+        }
+        Ok(())
+    }
+
+    fn initiate_request(&self, cmd: u8, data: &[u8]) -> Vec<u8> {
+        let [il, ih] = self.index.to_le_bytes();
+        let mut req = vec![cmd, il, ih, self.sub_index];
+        req.extend_from_slice(data);
+        req
+    }
+
+    fn check_download_response<CAN: Can>(&self, can: &mut CAN, resp: &[u8]) -> Result<(), SdoError>
+        where CAN::Frame: Frame + Debug {
+        if resp[0] != 0x60 {
+            return Err(self.abort(can, AbortCode::CommandSpecifierNotValidOrUnknown));
+        }
+        self.check_object(resp)
+    }
+
+    fn check_object(&self, resp: &[u8]) -> Result<(), SdoError> {
+        if !self.is_same_object(resp) {
+            return Err(SdoError::Transport(ErrorCode::VariableNotFound {
+                index: u16::from_le_bytes([resp[1], resp[2]]),
+                sub_index: resp[3],
+            }));
+        }
+        Ok(())
+    }
+
+    fn is_same_object(&self, resp: &[u8]) -> bool {
+        u16::from_le_bytes([resp[1], resp[2]]) == self.index && resp[3] == self.sub_index
+    }
+
+    /// Sends one request and waits for the matching response of the server.
+    fn request<CAN: Can>(&self, can: &mut CAN, req: &[u8]) -> Result<[u8; 8], SdoError>
+        where CAN::Frame: Frame + Debug {
+        self.transmit(can, req)?;
+        let mut idle_polls = 0;
+        loop {
+            let frame = match can.receive() {
+                Ok(frame) => frame,
+                Err(nb::Error::WouldBlock) => {
+                    idle_polls += 1;
+                    if idle_polls > self.timeout_polls {
+                        return Err(self.abort(can, AbortCode::SdoProtocolTimedOut));
+                    }
+                    continue;
+                }
+                Err(nb::Error::Other(_)) => {
+                    return Err(SdoError::Transport(ErrorCode::LegacyError {
+                        str: "CAN receive failed".to_string(),
+                    }));
+                }
+            };
+            if get_cob_id(&frame) != Some(COB_FUNC_TRANSMIT_SDO | self.node_id as u16) || frame.dlc() != 8 {
+                continue;
+            }
+            let mut resp = [0u8; 8];
+            resp.copy_from_slice(frame.data());
+            if resp[0] == 0x80 {
+                if !self.is_same_object(&resp) {
+                    continue;
+                }
+                return Err(SdoError::Abort(AbortCode::from_wire(&resp[4..8]).unwrap_or(AbortCode::Other)));
+            }
+            return Ok(resp);
+        }
+    }
+
+    fn transmit<CAN: Can>(&self, can: &mut CAN, data: &[u8]) -> Result<(), SdoError>
+        where CAN::Frame: Frame + Debug {
+        let frame: CAN::Frame = create_frame_checked(COB_FUNC_RECEIVE_SDO | self.node_id as u16, data)
+            .map_err(SdoError::Transport)?;
+        can.transmit(&frame).map_err(|_| SdoError::Transport(ErrorCode::LegacyError {
+            str: "CAN transmit failed".to_string(),
+        }))?;
+        Ok(())
+    }
+
+    /// Tells the server the transfer is aborted, and returns the matching error.
+    fn abort<CAN: Can>(&self, can: &mut CAN, abort_code: AbortCode) -> SdoError where CAN::Frame: Frame + Debug {
+        let data = self.initiate_request(0x80, &abort_code.code().to_le_bytes());
+        // The transfer has failed anyway, a lost abort frame changes nothing.
+        let _ = self.transmit(can, &data);
+        match abort_code {
+            AbortCode::SdoProtocolTimedOut => SdoError::Timeout,
+            _ => SdoError::Abort(abort_code),
+        }
+    }
+}
+
+/// SDO client of a master, running `SdoTransaction`s on its own CAN interface.
+pub struct SdoClient<CAN: Can> {
+    can: CAN,
+    timeout_polls: u32,
+}
+
+impl<CAN: Can> SdoClient<CAN> where CAN::Frame: Frame + Debug {
+    pub fn new(can: CAN) -> Self {
+        SdoClient { can, timeout_polls: DEFAULT_SDO_TIMEOUT_POLLS }
+    }
+
+    pub fn set_timeout(&mut self, timeout_polls: u32) {
+        self.timeout_polls = timeout_polls;
+    }
+
+    pub fn upload(&mut self, node_id: u8, index: u16, sub_index: u8) -> Result<Value, SdoError> {
+        SdoTransaction::new(node_id, index, sub_index)
+            .with_timeout(self.timeout_polls)
+            .upload(&mut self.can)
+    }
+
+    pub fn download(&mut self, node_id: u8, index: u16, sub_index: u8, data: &[u8]) -> Result<(), SdoError> {
+        SdoTransaction::new(node_id, index, sub_index)
+            .with_timeout(self.timeout_polls)
+            .download(&mut self.can, data)
+    }
+
+    pub fn can_network(&mut self) -> &mut CAN {
+        &mut self.can
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::collections::VecDeque;
+
+    use crate::node::Node;
+    use crate::test_util::{MockCan, MockError, MockFrame, TEST_EDS, TEST_NODE_ID, frame, new_node, send};
+
+    use super::*;
+
+    /// A bus with the server node on the other side: every request is answered right away.
+    struct Loopback {
+        node: Node<MockCan>,
+        rx: VecDeque<MockFrame>,
+    }
+
+    impl Can for Loopback {
+        type Frame = MockFrame;
+        type Error = MockError;
+
+        fn transmit(&mut self, frame: &Self::Frame) -> nb::Result<Option<Self::Frame>, Self::Error> {
+            let resp = send(&mut self.node, frame.clone());
+            self.rx.extend(resp);
+            Ok(None)
+        }
+
+        fn receive(&mut self) -> nb::Result<Self::Frame, Self::Error> {
+            self.rx.pop_front().ok_or(nb::Error::WouldBlock)
+        }
+    }
+
+    fn client() -> SdoClient<Loopback> {
+        SdoClient::new(Loopback { node: new_node(), rx: VecDeque::new() })
+    }
+
+    /// A server answering each request with the next of `responses`, whatever it is.
+    struct Scripted {
+        responses: VecDeque<[u8; 8]>,
+        rx: Option<MockFrame>,
+    }
+
+    impl Can for Scripted {
+        type Frame = MockFrame;
+        type Error = MockError;
+
+        fn transmit(&mut self, _frame: &Self::Frame) -> nb::Result<Option<Self::Frame>, Self::Error> {
+            self.rx = self.responses.pop_front().map(|data| frame(0x582, &data));
+            Ok(None)
+        }
+
+        fn receive(&mut self) -> nb::Result<Self::Frame, Self::Error> {
+            self.rx.take().ok_or(nb::Error::WouldBlock)
+        }
+    }
+
+    #[test]
+    fn test_expedited_upload_and_download() {
+        let mut client = client();
+        assert_eq!(client.upload(TEST_NODE_ID, 0x1000, 0).unwrap().data(), &vec![0x92, 0x01, 0x01, 0x00]);
+
+        client.download(TEST_NODE_ID, 0x1017, 0, &[0xE8, 0x03]).unwrap();
+        assert_eq!(client.upload(TEST_NODE_ID, 0x1017, 0).unwrap().data(), &vec![0xE8, 0x03]);
+    }
+
+    #[test]
+    fn test_segmented_upload_and_download() {
+        let mut client = client();
+        assert_eq!(client.upload(TEST_NODE_ID, 0x1008, 0).unwrap().data(), &b"Atomi Test Device".to_vec());
+
+        let text: Vec<u8> = (b'a'..=b'z').collect();
+        client.download(TEST_NODE_ID, 0x2003, 0, &text).unwrap();
+        assert_eq!(client.upload(TEST_NODE_ID, 0x2003, 0).unwrap().data(), &text);
+    }
+
+    #[test]
+    fn test_empty_download() {
+        let eds = format!("{}\n[2101]\nParameterName=Firmware\nObjectType=0x7\nDataType=0x000F\n\
+            AccessType=rw\nPDOMapping=0\n", TEST_EDS);
+        let mut node = Node::new(TEST_NODE_ID, &eds, MockCan::default()).unwrap();
+        node.object_directory.set_value(0x2101, 0, &[1, 2, 3], true).unwrap();
+        let mut client = SdoClient::new(Loopback { node, rx: VecDeque::new() });
+        client.download(TEST_NODE_ID, 0x2101, 0, &[]).unwrap();
+        assert_eq!(client.can_network().node.object_raw(0x2101, 0), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_upload_size_mismatch() {
+        // 10 bytes indicated, 7 + 7 sent.
+        let mut can = Scripted { responses: VecDeque::from([
+            [0x41, 0x03, 0x20, 0, 10, 0, 0, 0],
+            [0x00, 1, 2, 3, 4, 5, 6, 7],
+            [0x11, 1, 2, 3, 4, 5, 6, 7],
+        ]), rx: None };
+        assert_eq!(SdoTransaction::new(TEST_NODE_ID, 0x2003, 0).upload(&mut can),
+                   Err(SdoError::Abort(AbortCode::DataTypeMismatchLengthMismatch)));
+
+        // 10 bytes indicated, 7 + 1 sent.
+        let mut can = Scripted { responses: VecDeque::from([
+            [0x41, 0x03, 0x20, 0, 10, 0, 0, 0],
+            [0x00, 1, 2, 3, 4, 5, 6, 7],
+            [0x1D, 1, 0, 0, 0, 0, 0, 0],
+        ]), rx: None };
+        assert_eq!(SdoTransaction::new(TEST_NODE_ID, 0x2003, 0).upload(&mut can),
+                   Err(SdoError::Abort(AbortCode::DataTypeMismatchLengthMismatch)));
+    }
+
+    #[test]
+    fn test_timeout_without_response() {
+        let mut client = client();
+        client.set_timeout(5);
+        // No node 0x10 on the bus.
+        assert_eq!(client.upload(0x10, 0x1000, 0), Err(SdoError::Timeout));
+    }
+
+    #[test]
+    fn test_abort_from_server() {
+        let mut client = client();
+        assert_eq!(client.upload(TEST_NODE_ID, 0x5555, 0),
+                   Err(SdoError::Abort(AbortCode::ObjectDoesNotExistInObjectDictionary)));
+        assert_eq!(client.download(TEST_NODE_ID, 0x1000, 0, &[1, 2, 3, 4]),
+                   Err(SdoError::Abort(AbortCode::AttemptToWriteReadOnlyObject)));
+    }
+}
@@ -5,14 +5,16 @@ use crate::cmd_header::{
     SdoBlockDownloadInitiateCmd, SdoBlockUploadCmd, SdoDownloadInitiateCmd, SdoDownloadSegmentCmd,
     SdoEndBlockDownloadCmd, SdoInitBlockUploadCmd,
 };
-use crate::constant::{COB_FUNC_TRANSMIT_SDO, REG_PRODUCER_HEARTBEAT_TIME, REG_RESTORE_DEFAULT_PARAMETERS, RESET_MAGIC_CODE};
-use crate::error::ErrorCode;
+use crate::constant::{COB_FUNC_TRANSMIT_SDO, REG_CONSUMER_HEARTBEAT_TIME, REG_PRODUCER_HEARTBEAT_TIME, REG_RESTORE_DEFAULT_PARAMETERS, REG_STORE_PARAMETERS, RESET_MAGIC_CODE, SAVE_MAGIC_CODE};
+use crate::data_type::DataType;
+use crate::error::{AbortCode, ErrorCode};
 use crate::error;
-use crate::error::AbortCode::{CommandSpecifierNotValidOrUnknown, DataTransferOrStoreFailed, GeneralError, InvalidBlockSize, ObjectCannotBeMappedToPDO, ToggleBitNotAlternated};
+use crate::error::AbortCode::{CommandSpecifierNotValidOrUnknown, CRCError, DataTransferOrStoreFailed, DataTransferOrStoreFailedDueToDeviceState, DataTypeMismatchLengthTooHigh, DataTypeMismatchLengthTooLow, GeneralError, InvalidBlockSize, InvalidSequenceNumber, ObjectCannotBeMappedToPDO, SubIndexDoesNotExist, ToggleBitNotAlternated};
 use crate::node::Node;
+use crate::pdo::validate_transmission_type;
 use crate::prelude::*;
 use crate::sdo_server::SdoState::{ConfirmUploadSdoBlock, DownloadSdoBlock, EndSdoBlockDownload, FinalConfirmUploadSdoBlock, Normal, SdoSegmentDownload, SdoSegmentUpload, StartSdoBlockUpload};
-use crate::util::{convert_bytes_to_u32, crc16_canopen_with_lut, create_frame_with_padding, flatten, make_abort_error};
+use crate::util::{convert_bytes_to_u32, crc16_canopen_with_lut, create_frame_checked, flatten, make_abort_error};
 
 /// Represents the various states of the SDO (Service Data Object) communication process.
 /// These states govern the different phases or modes of SDO transmissions in a CANopen system.
@@ -40,9 +42,46 @@ pub enum SdoState {
     FinalConfirmUploadSdoBlock,
 }
 
+/// The shortest request the handler of `state` / command byte `cmd` can
+/// process. CiA 301 requests are 8 bytes, the handlers which only look at the
+/// first bytes accept shorter ones.
+fn min_request_len(state: &SdoState, cmd: u8) -> usize {
+    match state {
+        SdoSegmentUpload | StartSdoBlockUpload | FinalConfirmUploadSdoBlock => 1,
+        EndSdoBlockDownload | ConfirmUploadSdoBlock => 3,
+        SdoSegmentDownload | DownloadSdoBlock => 8,
+        // Uploads only need the multiplexer.
+        Normal if cmd >> 5 == 0x2 => 4,
+        Normal => 8,
+    }
+}
+
+/// The size indicated in bytes 4..8 of an initiate request, missing bytes
+/// of a short frame count as 0.
+fn size_field(req: &[u8]) -> Result<usize, ErrorCode> {
+    let size = convert_bytes_to_u32(req.get(4..req.len().min(8)).unwrap_or_default())?;
+    usize::try_from(size).map_err(|_| make_abort_error(AbortCode::OutOfMemory, "".to_string()))
+}
+
+/// The signature written to sub-index 1 (all), 2 (communication) or 3
+/// (application) of 0x1010 / 0x1011, "save" / "load" as a little-endian u32.
+fn magic_code(sub_index: u8, data: &[u8]) -> Result<u32, ErrorCode> {
+    if !(1..=3).contains(&sub_index) {
+        return Err(make_abort_error(SubIndexDoesNotExist, "".to_string()));
+    }
+    let bytes = <[u8; 4]>::try_from(data).map_err(|_| make_abort_error(
+        if data.len() < 4 { DataTypeMismatchLengthTooLow } else { DataTypeMismatchLengthTooHigh }, "".to_string()))?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
 impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
+    /// The COB-ID of the SDO responses, 0x580 + node-id unless overridden.
+    pub(crate) fn sdo_response_cob_id(&self) -> u16 {
+        self.sdo_response_cob_id.unwrap_or(COB_FUNC_TRANSMIT_SDO | self.node_id as u16)
+    }
+
     fn create_can_frame(&self, data: &[u8]) -> Result<Option<CAN::Frame>, ErrorCode> {
-        create_frame_with_padding(COB_FUNC_TRANSMIT_SDO | self.node_id as u16, data)
+        create_frame_checked(self.sdo_response_cob_id(), data)
             .map(Some)
             .map_err(|ec| make_abort_error(GeneralError, format!("{:?}", ec)))
     }
@@ -53,39 +92,76 @@ impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
         self.create_can_frame(&bytes)
     }
 
+    /// Returns (bytes transferred, total bytes) of the active SDO transfer,
+    /// or `None` if no segmented / block transfer is in progress.
+    pub fn sdo_progress(&self) -> Option<(usize, usize)> {
+        match self.sdo_state {
+            Normal => None,
+            SdoSegmentUpload | StartSdoBlockUpload | ConfirmUploadSdoBlock => {
+                let total = self.read_buf.as_ref()?.len();
+                Some((self.read_buf_index, total))
+            }
+            FinalConfirmUploadSdoBlock => {
+                let total = self.read_buf.as_ref()?.len();
+                Some((total, total))
+            }
+            SdoSegmentDownload | DownloadSdoBlock | EndSdoBlockDownload => {
+                let received = self.write_buf.as_ref()?.len();
+                if self.write_data_size > 0 {
+                    Some((received.min(self.write_data_size), self.write_data_size))
+                } else {
+                    Some((received, received))
+                }
+            }
+        }
+    }
+
+    pub fn crc_enabled(&self) -> bool {
+        self.crc_enabled
+    }
+
+    /// Sets whether CRC is offered in SDO block transfers. When disabled, the
+    /// block init responses don't advertise CRC and no CRC is checked.
+    pub fn set_crc_enabled(&mut self, enabled: bool) {
+        self.crc_enabled = enabled;
+    }
+
+    /// Sets the number of segments per block the server asks for in block
+    /// downloads, 1..=127. Block uploads use the size requested by the client.
+    pub fn set_block_size(&mut self, block_size: u8) -> Result<(), ErrorCode> {
+        if !(1..=0x7F).contains(&block_size) {
+            return Err(make_abort_error(InvalidBlockSize, format!("block size {}", block_size)));
+        }
+        self.download_block_size = block_size;
+        Ok(())
+    }
+
     fn next_state(&mut self, state: SdoState, res: Result<Option<CAN::Frame>, ErrorCode>)
                   -> Result<Option<CAN::Frame>, ErrorCode> {
         self.sdo_state = state;
         res
     }
 
+    /// Drops any transfer in progress, so that the next one starts from scratch.
+    pub(crate) fn reset_sdo_transfer(&mut self) {
+        self.sdo_state = Normal;
+        self.read_buf = None;
+        self.read_buf_index = 0;
+        self.next_read_toggle = 0;
+        self.write_buf = None;
+        self.next_write_toggle = 0;
+        self.need_crc = false;
+    }
+
     pub(crate) fn process_sdo_frame(&mut self, frame: &CAN::Frame) {
-        if self.filter_frame(frame) {
-            return;
-        }
-        let cmd = frame.data()[0];
-        let ccs = cmd >> 5;
-
-        let index = u16::from_le_bytes([frame.data()[1], frame.data()[2]]);
-        let sub_index = frame.data()[3];
-        let res = match &self.sdo_state {
-            SdoSegmentDownload => self.download_segment(frame.data()),
-            SdoSegmentUpload => self.upload_segment(cmd),
-            DownloadSdoBlock => self.block_download(frame.data()),
-            EndSdoBlockDownload => self.end_block_download(frame.data()),
-            StartSdoBlockUpload => self.start_block_upload(frame.data()),
-            ConfirmUploadSdoBlock => self.confirm_block_upload(frame.data()),
-            FinalConfirmUploadSdoBlock => self.final_confirm_block_upload(frame.data()),
-            Normal => {
-                // ccs: 0x1 / 0x2 / 0x6 / 0x5, based on Canopen 301.
-                match ccs {
-                    0x1 => self.initiate_download(index, sub_index, frame.data()),
-                    0x2 => self.initiate_upload(index, sub_index),
-                    0x6 => self.init_block_download(index, sub_index, frame.data()),
-                    0x5 => self.init_block_upload(index, sub_index, frame.data()),
-                    _ => Err(make_abort_error(CommandSpecifierNotValidOrUnknown, "".to_string())),
-                }
-            }
+        let data = frame.data();
+        let cmd = data.first().copied().unwrap_or(0);
+        let index = u16::from_le_bytes([data.get(1).copied().unwrap_or(0), data.get(2).copied().unwrap_or(0)]);
+        let sub_index = data.get(3).copied().unwrap_or(0);
+        let res = if data.len() < min_request_len(&self.sdo_state, cmd) {
+            Err(make_abort_error(GeneralError, format!("SDO request of {} bytes is too short", data.len())))
+        } else {
+            self.dispatch_sdo_request(index, sub_index, data)
         };
 
         match res {
@@ -94,18 +170,16 @@ impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
                     self.transmit(&frame);
                 }
             }
-            Err(ErrorCode::AbortCodeWrapper { abort_code, .. }) => {
-                if abort_code == GeneralError {
-                    error!("debug");
+            Err(err) => {
+                if !matches!(err, ErrorCode::AbortCodeWrapper { .. }) {
+                    error!("Errors in processing SDO frame: {:x?}, err: {:?}", frame, err);
                 }
+                let abort_code = AbortCode::from(err);
                 let (idx, sidx) = match self.sdo_state {
                     Normal => (index, sub_index),
                     _ => (self.reserved_index, self.reserved_sub_index),
                 };
-                self.sdo_state = Normal;
-                self.read_buf = None;
-                self.write_buf = None;
-                self.need_crc = false;
+                self.reset_sdo_transfer();
 
                 match self.create_sdo_frame(0x80, idx, sidx, &abort_code.code().to_le_bytes()) {
                     Ok(Some(err_frame)) => { self.transmit(&err_frame) }
@@ -116,24 +190,38 @@ impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
                     _ => {}
                 }
             }
-            Err(err) => {
-                error!("Errors in processing SDO frame: {:x?}, err: {:?}", frame, err);
+        }
+    }
+
+    fn dispatch_sdo_request(&mut self, index: u16, sub_index: u8, req: &[u8]) -> Result<Option<CAN::Frame>, ErrorCode> {
+        match &self.sdo_state {
+            SdoSegmentDownload => self.download_segment(req),
+            SdoSegmentUpload => self.upload_segment(req[0]),
+            DownloadSdoBlock => self.block_download(req),
+            EndSdoBlockDownload => self.end_block_download(req),
+            StartSdoBlockUpload => self.start_block_upload(req),
+            ConfirmUploadSdoBlock => self.confirm_block_upload(req),
+            FinalConfirmUploadSdoBlock => self.final_confirm_block_upload(req),
+            Normal => {
+                // ccs: 0x1 / 0x2 / 0x6 / 0x5, based on Canopen 301.
+                match req[0] >> 5 {
+                    0x1 => self.initiate_download(index, sub_index, req),
+                    0x2 => self.initiate_upload(index, sub_index),
+                    0x6 => self.init_block_download(index, sub_index, req),
+                    0x5 => self.init_block_upload(index, sub_index, req),
+                    _ => Err(make_abort_error(CommandSpecifierNotValidOrUnknown, "".to_string())),
+                }
             }
         }
     }
 
     fn initiate_upload(&mut self, index: u16, sub_index: u8) -> Result<Option<CAN::Frame>, ErrorCode> {
         let var = self.object_directory.get_variable(index, sub_index)?;
-        let data = var.default_value().data();
+        let data = var.effective_value().data();
 
-        if data.is_empty() {
-            return Err(make_abort_error(GeneralError, "".to_string()));
-        }
-
-        if data.len() <= 4 {
-            let cmd = 0x43 | ((((4 - data.len()) as u8) & 0x3) << 2);
-            let t = data.clone();
-            return self.create_sdo_frame(cmd, index, sub_index, &t);
+        let (cmd, payload) = var.sdo_init_payload();
+        if cmd != 0x41 {
+            return self.create_sdo_frame(cmd, index, sub_index, &payload);
         }
 
         self.read_buf = Some(data.clone());
@@ -141,8 +229,7 @@ impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
         self.next_read_toggle = 0;
         self.reserved_index = index;
         self.reserved_sub_index = sub_index;
-        let len_bytes_vec = (data.len() as u32).to_le_bytes().to_vec();
-        let res = self.create_sdo_frame(0x41, index, sub_index, &len_bytes_vec);
+        let res = self.create_sdo_frame(cmd, index, sub_index, &payload);
         self.next_state(SdoSegmentUpload, res)
     }
 
@@ -188,7 +275,12 @@ impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
 
         // Check if the download is expedited.
         if cmd.e() && cmd.s() {
-            // Handle expedited download.
+            // Handle expedited download, 64-bit objects never fit into the 4 data bytes.
+            if let Ok(data_type) = self.object_directory.data_type_of(index, sub_index) {
+                if data_type.size() > 4 {
+                    return Err(make_abort_error(DataTypeMismatchLengthTooHigh, "".to_string()));
+                }
+            }
             let data = &req[4..(8 - cmd.n() as usize)];
             self.set_value_with_check(index, sub_index, data)?;
             return self.create_sdo_frame(0x60, index, sub_index, &[0, 0, 0, 0]);
@@ -196,12 +288,13 @@ impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
 
         // Set up for normal download.
         self.write_buf = Some(Vec::new());
+        self.next_write_toggle = 0;
         self.reserved_index = index;
         self.reserved_sub_index = sub_index;
 
         // Determine the write data size.
         self.write_data_size = if cmd.s() {
-            convert_bytes_to_u32(&req[4..])? as usize
+            size_field(req)?
         } else {
             0
         };
@@ -217,12 +310,18 @@ impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
             return Err(make_abort_error(GeneralError, "".to_string()));
         }
 
+        if req_cmd.t() != self.next_write_toggle {
+            return Err(make_abort_error(ToggleBitNotAlternated, "".to_string()));
+        }
+        self.next_write_toggle ^= 1;
+
         let mut buf = self.write_buf.take().ok_or(make_abort_error(GeneralError, "".to_string()))?;
         let result = (|| {
             let resp_cmd = 0x20 | (req_cmd.t() << 4);
             if !req_cmd.c() {
                 // Not finished, append data and continue.
                 buf.extend_from_slice(&req[1..]);
+                self.check_download_length(buf.len(), false)?;
                 self.create_can_frame(&[resp_cmd])
             } else {
                 // Handle the final segment of the download.
@@ -230,6 +329,8 @@ impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
                 if self.write_data_size > 0 && self.write_data_size != buf.len() {
                     return Err(make_abort_error(GeneralError, "".to_string())); // Size mismatch error.
                 }
+                // Also applies when the client didn't indicate the size (s = 0).
+                self.check_download_length(buf.len(), true)?;
                 let (index, sub_index) = (self.reserved_index, self.reserved_sub_index);
                 self.set_value_with_check(index, sub_index, &buf)?;
                 self.create_can_frame(&[resp_cmd])
@@ -237,18 +338,36 @@ impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
         })();
         // Regardless of the outcome, restore the write_buf.
         self.write_buf = Some(buf);
-        self.next_state(Normal, result)
+        // More segments follow until the c bit is set, aborts reset the state themselves.
+        let state = if req_cmd.c() && result.is_ok() { Normal } else { SdoSegmentDownload };
+        self.next_state(state, result)
+    }
+
+    /// Fixed-size objects take exactly their size, variable-length ones any length.
+    fn check_download_length(&mut self, len: usize, complete: bool) -> Result<(), ErrorCode> {
+        let (index, sub_index) = (self.reserved_index, self.reserved_sub_index);
+        let size = self.object_directory.data_type_of(index, sub_index)?.size();
+        if size == 0 {
+            return Ok(());
+        }
+        if len > size {
+            return Err(make_abort_error(DataTypeMismatchLengthTooHigh, "".to_string()));
+        }
+        if complete && len < size {
+            return Err(make_abort_error(DataTypeMismatchLengthTooLow, "".to_string()));
+        }
+        Ok(())
     }
 
     fn init_block_download(&mut self, index: u16, sub_index: u8, req: &[u8]) -> Result<Option<CAN::Frame>, ErrorCode> {
         let cmd = SdoBlockDownloadInitiateCmd::from(req[0]);
 
-        // Update the flag for CRC need based on the command.
-        self.need_crc = cmd.cc();
+        // CRC is used only if both the client and the server support it.
+        self.need_crc = cmd.cc() && self.crc_enabled;
 
         // Determine the write data size if specified, otherwise set it to zero.
         self.write_data_size = if cmd.s() {
-            convert_bytes_to_u32(&req[4..8])? as usize
+            size_field(req)?
         } else {
             0
         };
@@ -258,6 +377,7 @@ impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
         self.current_seq_number = 0;
         self.reserved_index = index;
         self.reserved_sub_index = sub_index;
+        self.block_size = self.download_block_size;
 
         // Create the response frame for initiating block download.
         let resp_cmd = 0xA0 | ((self.crc_enabled as u8) << 2);
@@ -275,38 +395,44 @@ impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
             return Err(make_abort_error(GeneralError, "".to_string()));
         }
 
-        let mut buf = self.write_buf.take().ok_or(make_abort_error(GeneralError, "".to_string()))?;
-        buf.extend_from_slice(&req[1..]);
-
-        let result = (|| {
-            if req[0] >> 7 == 1 {
-                // No more segments
-                if buf.len() >= self.write_data_size && buf.len() - 7 < self.write_data_size {
-                    buf.resize(self.write_data_size, 0);
-                }
-                // TODO(zephyr): Check correctness: CRC
-
-                // Write data to object directory.
-                let (i, si) = (self.reserved_index, self.reserved_sub_index);
-                self.set_value_with_check(i, si, &buf)?;
+        self.write_buf.as_mut().ok_or(make_abort_error(GeneralError, "".to_string()))?
+            .extend_from_slice(&req[1..]);
 
-                let (c, b) = (self.current_seq_number, self.block_size);
-                self.next_state(EndSdoBlockDownload, self.create_can_frame(&[0xA2, c, b]))
-            } else {
-                self.create_can_frame(&[])
-            }
-        })();
-        self.write_buf = Some(buf);
-        result
+        if req[0] >> 7 == 1 {
+            // No more segments, the data is written once the end frame brings the CRC.
+            let (c, b) = (self.current_seq_number, self.block_size);
+            self.next_state(EndSdoBlockDownload, self.create_can_frame(&[0xA2, c, b]))
+        } else {
+            self.create_can_frame(&[])
+        }
     }
 
     fn end_block_download(&mut self, req: &[u8]) -> Result<Option<CAN::Frame>, ErrorCode> {
         let cmd = SdoEndBlockDownloadCmd::from(req[0]);
-        if cmd.n() as usize != 7 - self.write_data_size % 7 {
+        let mut buf = self.write_buf.take().ok_or(make_abort_error(GeneralError, "".to_string()))?;
+        if self.write_data_size > 0 {
+            if cmd.n() as usize != (7 - self.write_data_size % 7) % 7 {
+                return Err(make_abort_error(GeneralError, "".to_string()));
+            }
+        } else {
+            // Size not indicated, drop the unused bytes of the last segment.
+            self.write_data_size = buf.len().saturating_sub(cmd.n() as usize);
+        }
+        if buf.len() < self.write_data_size {
             return Err(make_abort_error(GeneralError, "".to_string()));
         }
-        // TODO(zephyr): CRC check.
-        let _crc = u16::from_le_bytes([req[1], req[2]]);
+        buf.truncate(self.write_data_size);
+
+        if self.need_crc {
+            let crc = u16::from_le_bytes([req[1], req[2]]);
+            if crc != crc16_canopen_with_lut(&buf) {
+                return Err(make_abort_error(CRCError, "".to_string()));
+            }
+        }
+
+        // Write data to object directory.
+        let (i, si) = (self.reserved_index, self.reserved_sub_index);
+        self.set_value_with_check(i, si, &buf)?;
 
         self.next_state(Normal, self.create_can_frame(&[0xA1]))
     }
@@ -319,17 +445,17 @@ impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
         if cmd.ccs() != 0x5 || cmd.cs() != 0 {
             return Err(make_abort_error(GeneralError, "".to_string()));
         }
-        if blk_size >= 0x80 {
+        if blk_size == 0 || blk_size >= 0x80 {
             return Err(make_abort_error(InvalidBlockSize, "".to_string()));
         }
 
         // Init setting for upload (read)
-        self.need_crc = cmd.cc();
+        self.need_crc = cmd.cc() && self.crc_enabled;
         self.block_size = blk_size;
         self.reserved_index = index;
         self.reserved_sub_index = sub_index;
         let var = self.object_directory.get_variable(index, sub_index)?;
-        self.read_buf = Some(var.default_value().data().clone());
+        self.read_buf = Some(var.effective_value().data().clone());
         self.read_buf_index = 0;
 
         // Prepare the response packet.
@@ -346,30 +472,35 @@ impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
         if cmd.ccs() != 0x5 || cmd.cs() != 0x3 {
             return Err(make_abort_error(GeneralError, "".to_string()));
         }
+        let res = self.send_upload_block();
+        self.next_state(ConfirmUploadSdoBlock, res)
+    }
 
-        // TODO(zephyr): Some additional scenarios that need consideration:
-        // - total_seqs > blksize: Transmission requires multiple blocks, each
-        //   containing several sequences.
-        // - Logic for retransmission based on ack seq. (This also needs to be
-        //   considered for download)
-
+    /// Sends up to `block_size` segments starting at `read_buf_index`. All but
+    /// the last segment are transmitted directly, the last one is returned.
+    fn send_upload_block(&mut self) -> Result<Option<CAN::Frame>, ErrorCode> {
         let buf = self.read_buf.take().ok_or(make_abort_error(GeneralError, "".to_string()))?;
-        let result = (|| {
-            let total_seqs = ((buf.len() - 1) / 7 + 1) as u8;
-            for i in 0..total_seqs - 1 {
-                // This is a special case, directly transmit (total_seq - 1) frames,
-                // only leave the last one at last for change the state.
-                let (s, e) = ((i * 7) as usize, (i * 7 + 7) as usize);
-                let bytes = [&[i + 1], &buf[s..e]].concat();
-                let frame = create_frame_with_padding(COB_FUNC_TRANSMIT_SDO | self.node_id as u16, &bytes)?;
-                self.transmit(&frame);
-            }
-            let s = ((total_seqs - 1) * 7) as usize;
-            self.create_can_frame(flatten(&[&[total_seqs | 0x80], &buf[s..]]).as_slice())
-        })();
+        let result = self.send_upload_segments(&buf);
         self.read_buf = Some(buf);
+        result
+    }
 
-        self.next_state(ConfirmUploadSdoBlock, result)
+    fn send_upload_segments(&mut self, buf: &[u8]) -> Result<Option<CAN::Frame>, ErrorCode> {
+        let remaining = buf.len().saturating_sub(self.read_buf_index);
+        let seqs = remaining.div_ceil(7).clamp(1, self.block_size as usize);
+        for seq in 1..=seqs {
+            let s = (self.read_buf_index + (seq - 1) * 7).min(buf.len());
+            let e = (s + 7).min(buf.len());
+            // The c bit marks the last segment of the whole transfer.
+            let cmd = seq as u8 | if e == buf.len() { 0x80 } else { 0 };
+            let bytes = flatten(&[&[cmd], &buf[s..e]]);
+            if seq == seqs {
+                return self.create_can_frame(&bytes);
+            }
+            let frame = create_frame_checked(self.sdo_response_cob_id(), &bytes)?;
+            self.transmit(&frame);
+        }
+        Ok(None)
     }
 
     fn confirm_block_upload(&mut self, req: &[u8]) -> Result<Option<CAN::Frame>, ErrorCode> {
@@ -377,14 +508,25 @@ impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
         if cmd.ccs() != 0x5 || cmd.cs() != 2 {
             return Err(make_abort_error(GeneralError, "".to_string()));
         }
-        let buf = self.read_buf.as_ref().ok_or(make_abort_error(GeneralError, "".to_string()))?;
         let (ackseq, blksize) = (req[1], req[2]);
-        if ackseq as usize != (buf.len() - 1) / 7 + 1 {
-            return Err(make_abort_error(CommandSpecifierNotValidOrUnknown, "".to_string()));
+        if blksize == 0 || blksize >= 0x80 {
+            return Err(make_abort_error(InvalidBlockSize, "".to_string()));
+        }
+        let len = self.read_buf.as_ref().ok_or(make_abort_error(GeneralError, "".to_string()))?.len();
+        let sent = len.saturating_sub(self.read_buf_index).div_ceil(7).clamp(1, self.block_size as usize);
+        if ackseq as usize > sent {
+            return Err(make_abort_error(InvalidSequenceNumber, "".to_string()));
         }
 
+        // Continue after the last acknowledged segment, retransmitting the rest.
+        self.read_buf_index = (self.read_buf_index + ackseq as usize * 7).min(len);
         self.block_size = blksize;
-        let n = (7 - buf.len() % 7) as u8;
+        if self.read_buf_index < len {
+            return self.send_upload_block();
+        }
+
+        let buf = self.read_buf.as_ref().ok_or(make_abort_error(GeneralError, "".to_string()))?;
+        let n = ((7 - buf.len() % 7) % 7) as u8;
         let resp_cmd = 0xC1 | (n << 2);
         let crc: u16 = if self.need_crc {
             crc16_canopen_with_lut(buf)
@@ -405,8 +547,8 @@ impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
         self.next_state(Normal, Ok(None))
     }
 
-    fn validate_pdo_mapping_params_on_setting(&mut self, index: u16, sub_index: u8, data: &[u8])
-                                              -> Result<(), ErrorCode> {
+    pub(crate) fn validate_pdo_mapping_params_on_setting(&mut self, index: u16, sub_index: u8, data: &[u8])
+                                                         -> Result<(), ErrorCode> {
         // Early return if sub_index is not within valid PDO mapping range.
         if !(sub_index > 0 && sub_index <= crate::pdo::MAX_PDO_MAPPING_LENGTH) {
             return Ok(());
@@ -426,57 +568,617 @@ impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
         if !var.pdo_mappable() || (index < 0x1800 && !var.access_type().is_writable()) {
             return Err(make_abort_error(ObjectCannotBeMappedToPDO, "".to_string()));
         }
+        // A Boolean takes 1 bit in a PDO, its byte only exists for SDO access.
+        if var.data_type() == DataType::Boolean && data[0] != 1 {
+            return Err(make_abort_error(ObjectCannotBeMappedToPDO, format!("Boolean mapped with {} bits", data[0])));
+        }
 
         Ok(())
     }
 
     fn try_reset(&mut self, sub_index: u8, data: &[u8]) -> Result<bool, ErrorCode> {
-        let magic_code = u32::from_le_bytes(data[0..4].try_into().map_err(|_| {
-            error!("Errors in converting data: {:x?}", data);
-            make_abort_error(GeneralError, "".to_string())
-        })?);
-        if magic_code != RESET_MAGIC_CODE {
+        if magic_code(sub_index, data)? != RESET_MAGIC_CODE {
             return Err(make_abort_error(DataTransferOrStoreFailed, "".to_string()));
         }
         let success = match sub_index {
-            0x1 => self.reset(),
-            0x2 => self.reset_communication(),
-            0x3 => self.reset_application(),
+            0x1 => self.restore_defaults(),
+            0x2 => self.restore_communication_defaults(),
+            0x3 => self.restore_application_defaults(),
             _ => { false }
         };
 
-        if success {
-            self.object_directory.set_value(REG_RESTORE_DEFAULT_PARAMETERS, sub_index, &1u32.to_le_bytes(), true)?;
+        if !success {
+            return Err(make_abort_error(DataTransferOrStoreFailed, "".to_string()));
         }
+        self.object_directory.set_value(REG_RESTORE_DEFAULT_PARAMETERS, sub_index, &1u32.to_le_bytes(), true)?;
+        Ok(true)
+    }
 
+    fn try_save(&mut self, sub_index: u8, data: &[u8]) -> Result<bool, ErrorCode> {
+        if magic_code(sub_index, data)? != SAVE_MAGIC_CODE {
+            return Err(make_abort_error(DataTransferOrStoreFailed, "".to_string()));
+        }
+        let success = match sub_index {
+            0x1 => self.save(),
+            0x2 => self.save_communication(),
+            0x3 => self.save_application(),
+            _ => { false }
+        };
+        if !success {
+            return Err(make_abort_error(DataTransferOrStoreFailed, "".to_string()));
+        }
         Ok(true)
     }
 
     fn set_value_preprocess(&mut self, index: u16, sub_index: u8, data: &[u8]) -> Result<bool, ErrorCode> {
         match index {
-            0x1600..=0x17FF | 0x1A00..=0x1BFF =>
-                self.validate_pdo_mapping_params_on_setting(index, sub_index, data).map(|_| false),
+            0x1600..=0x17FF | 0x1A00..=0x1BFF => {
+                self.validate_pdo_mapping_params_on_setting(index, sub_index, data)?;
+                // A staged mapping is only checked as a whole, on commit.
+                if self.in_transaction(index) {
+                    return Ok(false);
+                }
+                self.validate_pdo_mapping_write(index, sub_index, data).map(|_| false)
+            }
+            0x1400..=0x15FF | 0x1800..=0x19FF if sub_index == 2 => validate_transmission_type(data).map(|_| false),
+            // Checked before the write, so that a rejected COB-ID isn't left in the object directory.
+            0x1400..=0x1403 if sub_index == 1 => match <[u8; 4]>::try_from(data) {
+                Ok(bytes) => self.check_rpdo_cob_id((index - 0x1400) as usize, u32::from_le_bytes(bytes)).map(|_| false),
+                // The write itself rejects the wrong size.
+                Err(_) => Ok(false),
+            },
+            REG_STORE_PARAMETERS => self.try_save(sub_index, data),
             REG_RESTORE_DEFAULT_PARAMETERS => self.try_reset(sub_index, data),
             _ => Ok(false),
         }
     }
 
     fn set_value_with_check(&mut self, index: u16, sub_index: u8, data: &[u8]) -> Result<(), ErrorCode> {
+        if let Some(states) = self.write_state_policies.get(&index) {
+            if !states.contains(&self.state) {
+                return Err(make_abort_error(DataTransferOrStoreFailedDueToDeviceState, "".to_string()));
+            }
+        }
         if self.set_value_preprocess(index, sub_index, data)? {
             return Ok(());
         }
+        let staged = self.in_transaction(index);
         let var = self.object_directory.set_value(index, sub_index, data, false)?;
         match index {
+            // Staged writes take effect on commit.
+            _ if staged => {}
             0x1400..=0x1BFF => {
                 let var_clone = var.clone();
                 self.update(&var_clone)?;
             }
+            REG_CONSUMER_HEARTBEAT_TIME => self.update_acceptance_filter(),
             REG_PRODUCER_HEARTBEAT_TIME => {
-                let t: u16 = var.default_value().to();
-                self.heartbeats_timer = t as u32;
+                let t: u16 = var.effective_value().try_to()?;
+                self.restart_heartbeat(t);
             }
             _ => {}
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::data_type::DataType;
+    use crate::node::{Node, NodeState};
+    use crate::object_directory::{AccessType, ObjectDirectory};
+    use crate::value::Value;
+    use crate::util::crc16_canopen_with_lut;
+    use embedded_can::Frame;
+
+    use crate::test_util::{MockCan, MockFrame, TEST_EDS, frame, new_node, sdo, sdo_read, sdo_write_u16, sdo_write_u32, send, take_tx};
+
+    #[test]
+    fn test_heartbeat_period_restarts_on_write() {
+        let mut node = new_node();
+        assert_eq!(sdo_write_u16(&mut node, 0x1017, 0, 10)[0], 0x60);
+        for _ in 0..4 {
+            node.event_timer_callback();
+        }
+        assert!(take_tx(&mut node).is_empty());
+
+        // Shorten the period partway through, the next heartbeat is 5ms after the write.
+        assert_eq!(sdo_write_u16(&mut node, 0x1017, 0, 5)[0], 0x60);
+        for _ in 0..4 {
+            node.event_timer_callback();
+        }
+        assert!(take_tx(&mut node).is_empty());
+        node.event_timer_callback();
+        let tx = take_tx(&mut node);
+        assert_eq!(tx.len(), 1);
+        assert_eq!(tx[0].cob_id(), 0x702);
+    }
+
+    #[test]
+    fn test_sdo_progress_during_segmented_upload() {
+        let mut node = new_node();
+        assert_eq!(node.sdo_progress(), None);
+
+        // 0x2003 holds a 19 bytes string, uploaded in 7 + 7 + 5 bytes.
+        let resp = sdo(&mut node, &[0x40, 0x03, 0x20, 0, 0, 0, 0, 0]);
+        assert_eq!(resp[0], 0x41);
+        assert_eq!(node.sdo_progress(), Some((0, 19)));
+
+        sdo(&mut node, &[0x60, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(node.sdo_progress(), Some((7, 19)));
+        sdo(&mut node, &[0x70, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(node.sdo_progress(), Some((14, 19)));
+
+        let resp = sdo(&mut node, &[0x60, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(resp[0] & 0x1, 0x1);
+        assert_eq!(node.sdo_progress(), None);
+    }
+
+    #[test]
+    fn test_hardware_version_upload_and_write() {
+        let mut node = new_node();
+        // "HW 1.0.0" takes a segment of 7 bytes and one of 1 byte.
+        assert_eq!(sdo(&mut node, &[0x40, 0x09, 0x10, 0, 0, 0, 0, 0]), vec![0x41, 0x09, 0x10, 0, 8, 0, 0, 0]);
+        assert_eq!(sdo(&mut node, &[0x60, 0, 0, 0, 0, 0, 0, 0]), [&[0x00][..], b"HW 1.0."].concat());
+        assert_eq!(sdo(&mut node, &[0x70, 0, 0, 0, 0, 0, 0, 0]), vec![0x1D, b'0', 0, 0, 0, 0, 0, 0]);
+
+        // Attempt to write a read only object, 0x06010002.
+        assert_eq!(sdo_write_u32(&mut node, 0x1009, 0, 0x12345678), vec![0x80, 0x09, 0x10, 0, 0x02, 0x00, 0x01, 0x06]);
+        assert_eq!(node.object_raw(0x1009, 0).unwrap(), b"HW 1.0.0");
+    }
+
+    #[test]
+    fn test_short_sdo_requests_aborted() {
+        let mut node = new_node();
+        // General error, 0x08000000.
+        assert_eq!(sdo(&mut node, &[0x40]), vec![0x80, 0, 0, 0, 0x00, 0x00, 0x00, 0x08]);
+        assert_eq!(sdo(&mut node, &[]), vec![0x80, 0, 0, 0, 0x00, 0x00, 0x00, 0x08]);
+        // An expedited download needs all 8 bytes, an upload request only the multiplexer.
+        assert_eq!(sdo(&mut node, &[0x23, 0x00, 0x20, 0]), vec![0x80, 0x00, 0x20, 0, 0x00, 0x00, 0x00, 0x08]);
+        assert_eq!(sdo(&mut node, &[0x40, 0x00, 0x20, 0]), vec![0x43, 0x00, 0x20, 0, 0x78, 0x56, 0x34, 0x12]);
+
+        // A short segment aborts the transfer in progress.
+        assert_eq!(sdo(&mut node, &[0x21, 0x03, 0x20, 0, 4, 0, 0, 0])[0], 0x60);
+        assert_eq!(sdo(&mut node, &[0x07, b'a']), vec![0x80, 0x03, 0x20, 0, 0x00, 0x00, 0x00, 0x08]);
+        assert_eq!(sdo(&mut node, &[0x40, 0x00, 0x20, 0, 0, 0, 0, 0])[0], 0x43);
+    }
+
+    #[test]
+    fn test_upload_prefers_parameter_value() {
+        let mut node = new_node();
+        assert_eq!(sdo_read(&mut node, 0x2004, 0), vec![0x4B, 0x04, 0x20, 0, 0x20, 0, 0, 0]);
+
+        let var = node.object_directory.get_variable(0x2004, 0).unwrap();
+        assert_eq!(var.default_value().to::<u16>(), 0x10);
+        assert_eq!(var.parameter_value().as_ref().unwrap().to::<u16>(), 0x20);
+
+        // Writes change the effective value only, the factory default stays.
+        assert_eq!(sdo_write_u16(&mut node, 0x2004, 0, 0x30)[0], 0x60);
+        assert_eq!(sdo_read(&mut node, 0x2004, 0)[4], 0x30);
+        let var = node.object_directory.get_variable(0x2004, 0).unwrap();
+        assert_eq!(var.default_value().to::<u16>(), 0x10);
+    }
+
+    #[test]
+    fn test_write_state_policy() {
+        let mut node = new_node();
+        node.set_write_state_policy(0x2001, &[NodeState::PreOperational]);
+
+        send(&mut node, frame(0x000, &[0x80, 2]));
+        assert_eq!(node.state, NodeState::PreOperational);
+        assert_eq!(sdo_write_u16(&mut node, 0x2001, 0, 0x55)[0], 0x60);
+
+        send(&mut node, frame(0x000, &[0x01, 2]));
+        take_tx(&mut node);
+        assert_eq!(node.state, NodeState::Operational);
+        assert_eq!(sdo_write_u16(&mut node, 0x2001, 0, 0x66), vec![0x80, 0x01, 0x20, 0, 0x22, 0, 0, 0x08]);
+        assert_eq!(sdo_read(&mut node, 0x2001, 0)[4], 0x55);
+
+        // Objects without a policy stay writable.
+        assert_eq!(sdo_write_u16(&mut node, 0x2004, 0, 0x66)[0], 0x60);
+        node.clear_write_state_policy(0x2001);
+        assert_eq!(sdo_write_u16(&mut node, 0x2001, 0, 0x66)[0], 0x60);
+    }
+
+    #[test]
+    fn test_store_and_restore_parameters() {
+        const SAVE: u32 = 0x65_76_61_73;
+        const LOAD: u32 = 0x64_61_6F_6C;
+        let mut node = new_node();
+
+        // Save a modified communication value.
+        assert_eq!(sdo_write_u16(&mut node, 0x1017, 0, 100)[0], 0x60);
+        assert_eq!(sdo_write_u32(&mut node, 0x1010, 2, SAVE)[0], 0x60);
+
+        // Changes after the save are dropped by a reset, which loads the saved values.
+        assert_eq!(sdo_write_u16(&mut node, 0x1017, 0, 200)[0], 0x60);
+        send(&mut node, frame(0x000, &[0x82, 2]));
+        assert_eq!(sdo_read(&mut node, 0x1017, 0)[4..6], [100, 0]);
+
+        // A wrong signature is refused.
+        assert_eq!(sdo_write_u32(&mut node, 0x1010, 1, 0x1234)[0], 0x80);
+
+        // Restoring defaults applies to the live values and the saved ones.
+        assert_eq!(sdo_write_u32(&mut node, 0x1011, 2, LOAD)[0], 0x60);
+        assert_eq!(sdo_read(&mut node, 0x1017, 0)[4..6], [0, 0]);
+        assert_eq!(sdo_write_u16(&mut node, 0x1017, 0, 300)[0], 0x60);
+        send(&mut node, frame(0x000, &[0x82, 2]));
+        assert_eq!(sdo_read(&mut node, 0x1017, 0)[4..6], [0, 0]);
+    }
+
+    #[test]
+    fn test_store_restore_reject_malformed_writes() {
+        let mut node = new_node();
+        for index in [0x1010u16, 0x1011] {
+            let [il, ih] = index.to_le_bytes();
+            // 1-byte expedited writes.
+            assert_eq!(sdo(&mut node, &[0x2F, il, ih, 1, 0x73, 0, 0, 0]), [0x80, il, ih, 1, 0x13, 0x00, 0x07, 0x06]);
+            // 2-byte expedited writes.
+            assert_eq!(sdo(&mut node, &[0x2B, il, ih, 2, 0x73, 0x61, 0, 0]), [0x80, il, ih, 2, 0x13, 0x00, 0x07, 0x06]);
+            // No sub-index 4, whatever the signature.
+            let sig = if index == 0x1010 { *b"save" } else { *b"load" };
+            assert_eq!(sdo(&mut node, &[0x23, il, ih, 4, sig[0], sig[1], sig[2], sig[3]]),
+                       [0x80, il, ih, 4, 0x11, 0x00, 0x09, 0x06]);
+        }
+    }
+
+    #[test]
+    fn test_restore_application_covers_manufacturer_area() {
+        const LOAD: u32 = 0x64_61_6F_6C;
+        let eds = format!("{}\n[3020]\nParameterName=Manufacturer u32\nObjectType=0x7\nDataType=0x0007\n\
+                           AccessType=rw\nDefaultValue=7\nPDOMapping=0\n", TEST_EDS);
+        let mut node = Node::new(2, &eds, MockCan::default()).unwrap();
+        assert_eq!(sdo_write_u32(&mut node, 0x3020, 0, 99)[0], 0x60);
+        assert_eq!(sdo_write_u16(&mut node, 0x1017, 0, 100)[0], 0x60);
+
+        // Restoring communication parameters leaves the manufacturer area alone.
+        assert_eq!(sdo_write_u32(&mut node, 0x1011, 2, LOAD)[0], 0x60);
+        assert_eq!(sdo_read(&mut node, 0x3020, 0)[4], 99);
+        assert_eq!(sdo_read(&mut node, 0x1017, 0)[4..6], [0, 0]);
+
+        assert_eq!(sdo_write_u16(&mut node, 0x1017, 0, 100)[0], 0x60);
+        assert_eq!(sdo_write_u32(&mut node, 0x1011, 3, LOAD)[0], 0x60);
+        assert_eq!(sdo_read(&mut node, 0x3020, 0)[4], 7);
+        assert_eq!(sdo_read(&mut node, 0x1017, 0)[4..6], [100, 0]);
+
+        // Restore all covers both.
+        assert_eq!(sdo_write_u32(&mut node, 0x3020, 0, 99)[0], 0x60);
+        assert_eq!(sdo_write_u32(&mut node, 0x1011, 1, LOAD)[0], 0x60);
+        assert_eq!(sdo_read(&mut node, 0x3020, 0)[4], 7);
+        assert_eq!(sdo_read(&mut node, 0x1017, 0)[4..6], [0, 0]);
+    }
+
+    #[test]
+    fn test_download_shorter_string() {
+        let mut node = new_node();
+        assert_eq!(sdo(&mut node, &[0x21, 0x03, 0x20, 0, 5, 0, 0, 0])[0], 0x60);
+        assert_eq!(sdo(&mut node, &[0x05, b'H', b'e', b'l', b'l', b'o', 0, 0])[0], 0x20);
+
+        assert_eq!(sdo_read(&mut node, 0x2003, 0), vec![0x41, 0x03, 0x20, 0, 5, 0, 0, 0]);
+        assert_eq!(sdo(&mut node, &[0x60, 0, 0, 0, 0, 0, 0, 0]),
+                   vec![0x05, b'H', b'e', b'l', b'l', b'o', 0, 0]);
+    }
+
+    #[test]
+    fn test_sdo_response_cob_id_override() {
+        let mut node = new_node();
+        node.set_sdo_response_cob_id(Some(0x5C0));
+        let resp = send(&mut node, frame(0x602, &[0x40, 0x00, 0x20, 0, 0, 0, 0, 0]));
+        assert_eq!(resp.len(), 1);
+        assert_eq!(resp[0].cob_id(), 0x5C0);
+        assert_eq!(resp[0].data(), &[0x43, 0x00, 0x20, 0, 0x78, 0x56, 0x34, 0x12]);
+
+        // Segmented upload of the device name.
+        let resp = send(&mut node, frame(0x602, &[0x40, 0x08, 0x10, 0, 0, 0, 0, 0]));
+        assert_eq!(resp[0].cob_id(), 0x5C0);
+        let resp = send(&mut node, frame(0x602, &[0x60, 0, 0, 0, 0, 0, 0, 0]));
+        assert_eq!(resp[0].cob_id(), 0x5C0);
+
+        node.set_sdo_response_cob_id(None);
+        let resp = send(&mut node, frame(0x602, &[0x40, 0x00, 0x20, 0, 0, 0, 0, 0]));
+        assert_eq!(resp[0].cob_id(), 0x582);
+    }
+
+    #[test]
+    fn test_toggle_reset_between_transfers() {
+        let mut node = new_node();
+        // A segmented upload aborted after its first segment...
+        assert_eq!(sdo_read(&mut node, 0x2003, 0)[0], 0x41);
+        assert_eq!(sdo(&mut node, &[0x60, 0, 0, 0, 0, 0, 0, 0])[0], 0x00);
+        assert_eq!(sdo(&mut node, &[0x80, 0x03, 0x20, 0, 0, 0, 0x04, 0x05])[0], 0x80);
+        // ... doesn't leave the next one expecting toggle 1.
+        assert_eq!(sdo_read(&mut node, 0x2003, 0)[0], 0x41);
+        assert_eq!(sdo(&mut node, &[0x60, 0, 0, 0, 0, 0, 0, 0])[0], 0x00);
+        assert_eq!(sdo(&mut node, &[0x70, 0, 0, 0, 0, 0, 0, 0])[0], 0x10);
+        assert_eq!(sdo(&mut node, &[0x60, 0, 0, 0, 0, 0, 0, 0])[0], 0x05);
+
+        // Back-to-back uploads both start with toggle 0.
+        assert_eq!(sdo_read(&mut node, 0x2003, 0)[0], 0x41);
+        assert_eq!(sdo(&mut node, &[0x60, 0, 0, 0, 0, 0, 0, 0])[0], 0x00);
+
+        // Downloads check the toggle as well, and start over at 0.
+        let mut node = new_node();
+        assert_eq!(sdo(&mut node, &[0x21, 0x03, 0x20, 0, 14, 0, 0, 0])[0], 0x60);
+        assert_eq!(sdo(&mut node, &[0x00, b'a', b'b', b'c', b'd', b'e', b'f', b'g'])[0], 0x20);
+        assert_eq!(sdo(&mut node, &[0x00, b'h', b'i', b'j', b'k', b'l', b'm', b'n']),
+                   vec![0x80, 0x03, 0x20, 0, 0, 0, 0x03, 0x05]);
+        assert_eq!(sdo(&mut node, &[0x21, 0x03, 0x20, 0, 14, 0, 0, 0])[0], 0x60);
+        assert_eq!(sdo(&mut node, &[0x00, b'a', b'b', b'c', b'd', b'e', b'f', b'g'])[0], 0x20);
+        assert_eq!(sdo(&mut node, &[0x11, b'h', b'i', b'j', b'k', b'l', b'm', b'n'])[0], 0x30);
+        assert_eq!(sdo_read(&mut node, 0x2003, 0)[4], 14);
+    }
+
+    fn block_download_u32(node: &mut Node<MockCan>, value: u32, crc: u16) -> Vec<u8> {
+        let resp = sdo(node, &[0xC6, 0x00, 0x20, 0, 4, 0, 0, 0]);
+        assert_eq!(resp[0] & 0xFB, 0xA0);
+        let [d0, d1, d2, d3] = value.to_le_bytes();
+        assert_eq!(sdo(node, &[0x81, d0, d1, d2, d3, 0, 0, 0])[..2], [0xA2, 1]);
+        let [cl, ch] = crc.to_le_bytes();
+        sdo(node, &[0xCD, cl, ch, 0, 0, 0, 0, 0])
+    }
+
+    #[test]
+    fn test_crc_enabled_toggle() {
+        let mut node = new_node();
+        assert!(node.crc_enabled());
+        assert_eq!(sdo(&mut node, &[0xC6, 0x00, 0x20, 0, 4, 0, 0, 0])[0], 0xA4);
+        sdo(&mut node, &[0x80, 0, 0, 0, 0, 0, 0, 0]);
+
+        node.set_crc_enabled(false);
+        assert!(!node.crc_enabled());
+        assert_eq!(sdo(&mut node, &[0xC6, 0x00, 0x20, 0, 4, 0, 0, 0])[0], 0xA0);
+    }
+
+    #[test]
+    fn test_block_download_crc_check() {
+        let mut node = new_node();
+        let crc = crc16_canopen_with_lut(&0xCAFEu32.to_le_bytes());
+        assert_eq!(block_download_u32(&mut node, 0xCAFE, crc)[0], 0xA1);
+        assert_eq!(sdo_read(&mut node, 0x2000, 0)[4..], 0xCAFEu32.to_le_bytes());
+
+        // Wrong CRC is aborted with 0x05040004 and the value is kept.
+        let resp = block_download_u32(&mut node, 0xBEEF, crc ^ 0xFFFF);
+        assert_eq!(resp, vec![0x80, 0x00, 0x20, 0, 0x04, 0x00, 0x04, 0x05]);
+        assert_eq!(sdo_read(&mut node, 0x2000, 0)[4..], 0xCAFEu32.to_le_bytes());
+
+        // Without CRC support, the CRC field is ignored.
+        node.set_crc_enabled(false);
+        assert_eq!(block_download_u32(&mut node, 0xBEEF, 0)[0], 0xA1);
+        assert_eq!(sdo_read(&mut node, 0x2000, 0)[4..], 0xBEEFu32.to_le_bytes());
+    }
+
+    fn block_upload_eds(len: usize) -> String {
+        let text: String = (0..len).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+        format!("{}\n[2100]\nParameterName=Large string\nObjectType=0x7\nDataType=0x0009\n\
+            AccessType=rw\nDefaultValue={}\nPDOMapping=0\n", TEST_EDS, text)
+    }
+
+    /// Collects the segments of one upload block, returns (payload bytes, last seqno, is last block).
+    fn receive_block(frames: &[MockFrame]) -> (Vec<u8>, u8, bool) {
+        let mut data = Vec::new();
+        let mut last = (0, false);
+        for (i, f) in frames.iter().enumerate() {
+            assert_eq!(f.data()[0] & 0x7F, i as u8 + 1);
+            data.extend_from_slice(&f.data()[1..]);
+            last = (f.data()[0] & 0x7F, f.data()[0] & 0x80 != 0);
+        }
+        (data, last.0, last.1)
+    }
+
+    #[test]
+    fn test_multi_block_upload() {
+        let len = 2000;
+        let eds = block_upload_eds(len);
+        let mut node = Node::new(2, &eds, MockCan::default()).unwrap();
+        let expected = node.object_directory.get_variable(0x2100, 0).unwrap().effective_value().data().clone();
+
+        let resp = sdo(&mut node, &[0xA4, 0x00, 0x21, 0, 127, 0, 0, 0]);
+        assert_eq!(resp, vec![0xC6, 0x00, 0x21, 0, 0xD0, 0x07, 0, 0]);
+
+        let mut frames = send(&mut node, frame(0x602, &[0xA3, 0, 0, 0, 0, 0, 0, 0]));
+        let mut received = Vec::new();
+        let mut blocks = 0;
+        loop {
+            blocks += 1;
+            let (data, seqs, is_last) = receive_block(&frames);
+            // Lose the tail of the second block, it must be sent again.
+            let ackseq = if blocks == 2 { 100 } else { seqs };
+            received.extend_from_slice(&data[..ackseq as usize * 7]);
+            frames = send(&mut node, frame(0x602, &[0xA2, ackseq, 127, 0, 0, 0, 0, 0]));
+            if is_last && ackseq == seqs {
+                break;
+            }
+        }
+        assert_eq!(blocks, 3);
+
+        // End of upload: n unused bytes in the last segment, and the CRC.
+        assert_eq!(frames.len(), 1);
+        let end = frames[0].data();
+        let n = ((end[0] >> 2) & 0x7) as usize;
+        received.truncate(received.len() - n);
+        assert_eq!(received, expected);
+        assert_eq!(u16::from_le_bytes([end[1], end[2]]), crc16_canopen_with_lut(&expected));
+
+        assert!(send(&mut node, frame(0x602, &[0xA1, 0, 0, 0, 0, 0, 0, 0])).is_empty());
+        assert_eq!(node.sdo_progress(), None);
+    }
+
+    #[test]
+    fn test_upload_empty_domain() {
+        let eds = format!("{}\n[2101]\nParameterName=Firmware\nObjectType=0x7\nDataType=0x000F\n\
+            AccessType=rw\nPDOMapping=0\n", TEST_EDS);
+        let mut node = Node::new(2, &eds, MockCan::default()).unwrap();
+        // Expedited, without a size: there is no way to indicate 0 bytes.
+        assert_eq!(sdo(&mut node, &[0x40, 0x01, 0x21, 0, 0, 0, 0, 0]), vec![0x42, 0x01, 0x21, 0, 0, 0, 0, 0]);
+        // No transfer is left open.
+        assert_eq!(sdo(&mut node, &[0x60, 0, 0, 0, 0, 0, 0, 0])[0], 0x80);
+        assert_eq!(sdo(&mut node, &[0x40, 0x00, 0x20, 0, 0, 0, 0, 0])[0], 0x43);
+    }
+
+    #[test]
+    fn test_block_upload_of_large_domain() {
+        let eds = format!("{}\n[2101]\nParameterName=Firmware\nObjectType=0x7\nDataType=0x000F\n\
+            AccessType=rw\nPDOMapping=0\n", TEST_EDS);
+        let mut node = Node::new(2, &eds, MockCan::default()).unwrap();
+        let image: Vec<u8> = (0..3000u32).map(|i| (i * 7 % 251) as u8).collect();
+        node.object_directory.set_value(0x2101, 0, &image, true).unwrap();
+
+        // 3000 bytes are 429 segments: 21 blocks of the negotiated 20 segments and a last one of 9.
+        let resp = sdo(&mut node, &[0xA4, 0x01, 0x21, 0, 20, 0, 0, 0]);
+        assert_eq!(resp, vec![0xC6, 0x01, 0x21, 0, 0xB8, 0x0B, 0, 0]);
+        let mut frames = send(&mut node, frame(0x602, &[0xA3, 0, 0, 0, 0, 0, 0, 0]));
+        let mut received = Vec::new();
+        let mut blocks = 0;
+        loop {
+            blocks += 1;
+            let (data, seqs, is_last) = receive_block(&frames);
+            assert_eq!(seqs, if is_last { 9 } else { 20 });
+            received.extend_from_slice(&data);
+            frames = send(&mut node, frame(0x602, &[0xA2, seqs, 20, 0, 0, 0, 0, 0]));
+            if is_last {
+                break;
+            }
+        }
+        assert_eq!(blocks, 22);
+
+        let n = ((frames[0].data()[0] >> 2) & 0x7) as usize;
+        received.truncate(received.len() - n);
+        assert_eq!(received, image);
+        assert!(send(&mut node, frame(0x602, &[0xA1, 0, 0, 0, 0, 0, 0, 0])).is_empty());
+    }
+
+    #[test]
+    fn test_domain_segmented_download_and_upload() {
+        let eds = format!("{}\n[2101]\nParameterName=Firmware\nObjectType=0x7\nDataType=0x000F\n\
+            AccessType=rw\nPDOMapping=0\n", TEST_EDS);
+        let mut node = Node::new(2, &eds, MockCan::default()).unwrap();
+        let blob: Vec<u8> = (0..50u8).collect();
+
+        // Segmented download, size indicated.
+        let resp = sdo(&mut node, &[0x21, 0x01, 0x21, 0, 50, 0, 0, 0]);
+        assert_eq!(resp[0], 0x60);
+        for (i, chunk) in blob.chunks(7).enumerate() {
+            let toggle = (i as u8 & 1) << 4;
+            let last = if (i + 1) * 7 >= blob.len() { 1 } else { 0 };
+            let mut data = [0u8; 8];
+            data[0] = toggle | (((7 - chunk.len()) as u8) << 1) | last;
+            data[1..=chunk.len()].copy_from_slice(chunk);
+            let resp = sdo(&mut node, &data);
+            assert_eq!(resp[0], 0x20 | toggle, "{:x?}", resp);
+        }
+        assert_eq!(node.object_directory.get_variable(0x2101, 0).unwrap().effective_value().data(), &blob);
+
+        // Segmented upload of the same blob.
+        let resp = sdo(&mut node, &[0x40, 0x01, 0x21, 0, 0, 0, 0, 0]);
+        assert_eq!(resp, vec![0x41, 0x01, 0x21, 0, 50, 0, 0, 0]);
+        let mut received = Vec::new();
+        for i in 0..blob.len().div_ceil(7) {
+            let toggle = (i as u8 & 1) << 4;
+            let resp = sdo(&mut node, &[0x60 | toggle, 0, 0, 0, 0, 0, 0, 0]);
+            assert_eq!(resp[0] & 0x10, toggle);
+            let unused = ((resp[0] >> 1) & 0x7) as usize;
+            received.extend_from_slice(&resp[1..8 - unused]);
+        }
+        assert_eq!(received, blob);
+    }
+
+    #[test]
+    fn test_sdo_read_from_object_directory_built_in_code() {
+        let mut od = ObjectDirectory::empty(2);
+        od.add_variable(0x1017, 0, DataType::Unsigned16, AccessType::new(true, true),
+                        Value::new(1000u16.to_le_bytes().to_vec()), false).unwrap();
+        let mut node = Node::from_object_directory(2, od, MockCan::default()).unwrap();
+        node.can_network().push_rx(frame(0x602, &[0x40, 0x17, 0x10, 0, 0, 0, 0, 0]));
+        node.process_one_frame();
+        node.can_network().assert_next_tx(0x582, &[0x4B, 0x17, 0x10, 0, 0xE8, 0x03, 0, 0]);
+        assert!(node.can_network().pop_tx().is_none());
+    }
+
+    #[test]
+    fn test_set_node_id() {
+        let mut node = new_node();
+        node.set_node_id(7).unwrap();
+        assert!(send(&mut node, frame(0x602, &[0x40, 0x00, 0x10, 0, 0, 0, 0, 0])).is_empty());
+        let resp = send(&mut node, frame(0x607, &[0x40, 0x00, 0x10, 0, 0, 0, 0, 0]));
+        assert_eq!(resp.len(), 1);
+        assert_eq!(resp[0].cob_id(), 0x587);
+
+        // $NODEID defaults follow, and so do the PDOs.
+        assert_eq!(sdo_read(&mut node, 0x1800, 1)[4..8], [0x87, 0x01, 0, 0]);
+        assert_eq!(sdo_read(&mut node, 0x1400, 1)[4..8], [0x07, 0x02, 0, 0]);
+        send(&mut node, frame(0x000, &[0x01, 7]));
+        assert!(send(&mut node, frame(0x080, &[])).iter().any(|f| f.cob_id() == 0x187));
+    }
+
+    #[test]
+    fn test_segmented_download_length_of_fixed_size_object() {
+        let mut node = new_node();
+        // Exact length: 4 bytes into the u32 0x2000, in one segment with 3 unused bytes.
+        assert_eq!(sdo(&mut node, &[0x21, 0x00, 0x20, 0, 4, 0, 0, 0])[0], 0x60);
+        assert_eq!(sdo(&mut node, &[0x07, 0x11, 0x22, 0x33, 0x44, 0, 0, 0])[0], 0x20);
+        assert_eq!(sdo_read(&mut node, 0x2000, 0)[4..8], [0x11, 0x22, 0x33, 0x44]);
+
+        // Too long: the first 7 byte segment already exceeds the object.
+        assert_eq!(sdo(&mut node, &[0x21, 0x00, 0x20, 0, 10, 0, 0, 0])[0], 0x60);
+        assert_eq!(sdo(&mut node, &[0x00, 1, 2, 3, 4, 5, 6, 7]), vec![0x80, 0x00, 0x20, 0, 0x12, 0x00, 0x07, 0x06]);
+        assert_eq!(sdo_read(&mut node, 0x2000, 0)[4..8], [0x11, 0x22, 0x33, 0x44]);
+
+        // Too short.
+        assert_eq!(sdo(&mut node, &[0x21, 0x00, 0x20, 0, 2, 0, 0, 0])[0], 0x60);
+        assert_eq!(sdo(&mut node, &[0x0B, 1, 2, 0, 0, 0, 0, 0]), vec![0x80, 0x00, 0x20, 0, 0x13, 0x00, 0x07, 0x06]);
+
+        // The checks don't create the array entries they look at.
+        assert_eq!(sdo(&mut node, &[0x21, 0x03, 0x10, 20, 10, 0, 0, 0])[0], 0x60);
+        assert_eq!(sdo(&mut node, &[0x00, 1, 2, 3, 4, 5, 6, 7]), vec![0x80, 0x03, 0x10, 20, 0x12, 0x00, 0x07, 0x06]);
+        assert_eq!(node.object_directory.declared_largest_sub_index(0x1003), Some(8));
+    }
+
+    #[test]
+    fn test_segmented_download_without_size_into_fixed_size_object() {
+        let mut node = new_node();
+        // e=0, s=0: the client doesn't tell the size, the object's type still applies.
+        assert_eq!(sdo(&mut node, &[0x20, 0x00, 0x20, 0, 0, 0, 0, 0])[0], 0x60);
+        assert_eq!(sdo(&mut node, &[0x09, 1, 2, 3, 0, 0, 0, 0]), vec![0x80, 0x00, 0x20, 0, 0x13, 0x00, 0x07, 0x06]);
+        assert_eq!(sdo_read(&mut node, 0x2000, 0)[4..8], [0x78, 0x56, 0x34, 0x12]);
+
+        assert_eq!(sdo(&mut node, &[0x20, 0x00, 0x20, 0, 0, 0, 0, 0])[0], 0x60);
+        assert_eq!(sdo(&mut node, &[0x07, 1, 2, 3, 4, 0, 0, 0])[0], 0x20);
+        assert_eq!(sdo_read(&mut node, 0x2000, 0)[4..8], [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_expedited_download_into_64_bit_object() {
+        let mut node = new_node();
+        assert_eq!(sdo(&mut node, &[0x23, 0x40, 0x30, 0, 1, 2, 3, 4]), vec![0x80, 0x40, 0x30, 0, 0x12, 0x00, 0x07, 0x06]);
+        assert_eq!(node.object_directory.get_variable(0x3040, 0).unwrap().effective_value().to::<i64>(), 0);
+
+        // Segmented download is the way to write it.
+        assert_eq!(sdo(&mut node, &[0x21, 0x40, 0x30, 0, 8, 0, 0, 0])[0], 0x60);
+        assert_eq!(sdo(&mut node, &[0x00, 1, 2, 3, 4, 5, 6, 7])[0], 0x20);
+        assert_eq!(sdo(&mut node, &[0x1D, 8, 0, 0, 0, 0, 0, 0])[0], 0x30);
+        assert_eq!(node.object_directory.get_variable(0x3040, 0).unwrap().effective_value().to::<i64>(),
+                   0x0807060504030201);
+    }
+
+    #[test]
+    fn test_configured_block_size_and_crc() {
+        let mut node = new_node();
+        assert_eq!(sdo(&mut node, &[0xC6, 0x00, 0x20, 0, 4, 0, 0, 0]), vec![0xA4, 0x00, 0x20, 0, 0x7F, 0, 0, 0]);
+        send(&mut node, frame(0x602, &[0x80, 0x00, 0x20, 0, 0, 0, 0, 0]));
+
+        node.set_block_size(16).unwrap();
+        node.set_crc_enabled(false);
+        assert_eq!(sdo(&mut node, &[0xC6, 0x00, 0x20, 0, 4, 0, 0, 0]), vec![0xA0, 0x00, 0x20, 0, 16, 0, 0, 0]);
+
+        assert!(node.set_block_size(0).is_err());
+        assert!(node.set_block_size(128).is_err());
+    }
+
+    #[test]
+    fn test_const_object_readable_not_writable() {
+        let mut node = new_node();
+        assert_eq!(sdo_read(&mut node, 0x1018, 1), vec![0x43, 0x18, 0x10, 1, 0xCD, 0xAB, 0, 0]);
+        assert_eq!(sdo_write_u32(&mut node, 0x1018, 1, 0x1111),
+                   vec![0x80, 0x18, 0x10, 1, 0x02, 0x00, 0x01, 0x06]);
+    }
+}
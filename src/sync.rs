@@ -0,0 +1,217 @@
+use core::fmt::Debug;
+
+use embedded_can::Frame;
+use embedded_can::nb::Can;
+
+use crate::constant::{REG_COB_ID_SYNC, REG_COMMUNICATION_CYCLE_PERIOD, REG_SYNC_COUNTER_OVERFLOW, REG_SYNC_WINDOW_LENGTH};
+use crate::error::ErrorCode;
+use crate::node::Node;
+use crate::prelude::*;
+use crate::util::create_frame;
+use crate::{error, warn};
+
+/// Bit 30 of 0x1005: the node generates the SYNC message.
+const SYNC_PRODUCER_BIT: u32 = 1 << 30;
+
+/// Valid range of 0x1019, other values mean the SYNC message carries no counter.
+fn sync_overflow_in_range(overflow: u8) -> bool {
+    (2..=240).contains(&overflow)
+}
+
+impl<CAN: Can> Node<CAN> where CAN::Frame: Frame + Debug {
+    fn read_u32(&mut self, index: u16) -> u32 {
+        self.object_directory.get_variable(index, 0)
+            .and_then(|var| var.effective_value().try_to::<u32>())
+            .unwrap_or(0)
+    }
+
+    fn sync_counter_overflow(&mut self) -> u8 {
+        self.object_directory.get_variable(REG_SYNC_COUNTER_OVERFLOW, 0)
+            .and_then(|var| var.effective_value().try_to::<u8>())
+            .unwrap_or(0)
+    }
+
+    /// Transmits one SYNC message. When 0x1019 holds a value N in 2..=240 the
+    /// frame carries a counter byte cycling through 1..=N.
+    pub fn produce_sync(&mut self) -> Result<(), ErrorCode> {
+        let cob_id = (self.read_u32(REG_COB_ID_SYNC) & 0x7FF) as u16;
+        let overflow = self.sync_counter_overflow();
+        let frame = if sync_overflow_in_range(overflow) {
+            self.sync_producer_counter = if self.sync_producer_counter >= overflow {
+                1
+            } else {
+                self.sync_producer_counter + 1
+            };
+            create_frame(cob_id, &[self.sync_producer_counter])?
+        } else {
+            create_frame(cob_id, &[])?
+        };
+        self.transmit(&frame);
+        // The producer handles its own SYNC as well.
+        self.on_sync();
+        Ok(())
+    }
+
+    /// Called on each timer event, produces SYNC every 0x1006 microseconds
+    /// when the node is configured as the SYNC producer.
+    pub(crate) fn sync_producer_tick(&mut self, elapsed_ms: u32) {
+        let cob_id = self.read_u32(REG_COB_ID_SYNC);
+        let period_ms = self.read_u32(REG_COMMUNICATION_CYCLE_PERIOD) / 1000;
+        if cob_id & SYNC_PRODUCER_BIT == 0 || period_ms == 0 {
+            self.sync_producer_timer = 0;
+            return;
+        }
+        self.sync_producer_timer += elapsed_ms;
+        if self.sync_producer_timer >= period_ms {
+            self.sync_producer_timer %= period_ms;
+            if let Err(ec) = self.produce_sync() {
+                error!("Errors in producing SYNC message: error_code = {:?}", ec);
+            }
+        }
+    }
+
+    /// Called on each timer event, ages the last SYNC for the synchronous window.
+    pub(crate) fn sync_window_tick(&mut self, elapsed_ms: u32) {
+        if let Some(elapsed) = self.sync_elapsed_us.as_mut() {
+            *elapsed = elapsed.saturating_add(elapsed_ms.saturating_mul(1000));
+        }
+    }
+
+    /// Whether synchronous PDOs may be transmitted / received now: always when
+    /// 0x1007 is 0, otherwise only up to 0x1007 microseconds after a SYNC.
+    pub(crate) fn in_sync_window(&mut self) -> bool {
+        let window_us = self.read_u32(REG_SYNC_WINDOW_LENGTH);
+        window_us == 0 || self.sync_elapsed_us.is_some_and(|elapsed| elapsed <= window_us)
+    }
+
+    /// Validates the counter of a received SYNC message against the expected
+    /// sequence, and re-synchronizes on mismatch.
+    pub(crate) fn check_sync_counter(&mut self, counter: u8) {
+        let overflow = self.sync_counter_overflow();
+        if !sync_overflow_in_range(overflow) {
+            return;
+        }
+        if counter == 0 || counter > overflow {
+            warn!("SYNC counter {} out of range 1..={}", counter, overflow);
+            return;
+        }
+        if self.sync_consumer_counter != 0 {
+            let expected = self.sync_consumer_counter % overflow + 1;
+            if counter != expected {
+                warn!("Unexpected SYNC counter {}, expected {}", counter, expected);
+            }
+        }
+        self.sync_consumer_counter = counter;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_can::Frame;
+
+    use crate::node::NodeEvent;
+    use crate::test_util::{frame, new_node, sdo_write_u32, sdo_write_u8, send, take_tx};
+
+    #[test]
+    fn test_sync_counter_wraps_at_overflow_value() {
+        let mut node = new_node();
+        assert_eq!(sdo_write_u8(&mut node, 0x1019, 0, 4)[0], 0x60);
+        assert_eq!(sdo_write_u32(&mut node, 0x1006, 0, 1000)[0], 0x60);
+        assert_eq!(sdo_write_u32(&mut node, 0x1005, 0, 0x4000_0080)[0], 0x60);
+
+        for _ in 0..6 {
+            node.event_timer_callback();
+        }
+        let counters: Vec<u8> = take_tx(&mut node).iter()
+            .filter(|f| f.cob_id() == 0x80)
+            .map(|f| f.data()[0])
+            .collect();
+        assert_eq!(counters, vec![1, 2, 3, 4, 1, 2]);
+    }
+
+    #[test]
+    fn test_sync_without_counter() {
+        let mut node = new_node();
+        node.produce_sync().unwrap();
+        let tx = take_tx(&mut node);
+        assert_eq!(tx.len(), 1);
+        assert_eq!(tx[0].cob_id(), 0x80);
+        assert!(tx[0].data().is_empty());
+
+        // Not the producer, no SYNC from the timer.
+        assert_eq!(sdo_write_u32(&mut node, 0x1006, 0, 1000)[0], 0x60);
+        node.event_timer_callback();
+        assert!(take_tx(&mut node).is_empty());
+    }
+
+    #[test]
+    fn test_sync_consumer_tracks_counter() {
+        let mut node = new_node();
+        assert_eq!(sdo_write_u8(&mut node, 0x1019, 0, 3)[0], 0x60);
+        for c in [1u8, 2, 3, 1] {
+            send(&mut node, frame(0x80, &[c]));
+            assert_eq!(node.sync_consumer_counter, c);
+        }
+        // Out of range counters are ignored.
+        send(&mut node, frame(0x80, &[7]));
+        assert_eq!(node.sync_consumer_counter, 1);
+        // A gap re-synchronizes to the received value.
+        send(&mut node, frame(0x80, &[3]));
+        assert_eq!(node.sync_consumer_counter, 3);
+    }
+
+    #[test]
+    fn test_emergency_of_other_node_is_not_sync() {
+        let mut node = new_node();
+        send(&mut node, frame(0x000, &[0x01, 2]));
+
+        // EMCY of node 5 shares the 0x080 function code with SYNC.
+        assert!(send(&mut node, frame(0x085, &[0x00, 0x50, 0x01, 0, 0, 0, 0, 0])).is_empty());
+        assert_eq!(node.sync_count, 0);
+
+        let tx = send(&mut node, frame(0x080, &[]));
+        assert_eq!(node.sync_count, 1);
+        assert!(tx.iter().any(|f| f.cob_id() == 0x182));
+    }
+
+    #[test]
+    fn test_sync_window_suppresses_late_tpdo() {
+        let mut node = new_node();
+        send(&mut node, frame(0x000, &[0x01, 2]));
+        assert_eq!(sdo_write_u32(&mut node, 0x1007, 0, 2000)[0], 0x60);
+
+        assert!(send(&mut node, frame(0x080, &[])).iter().any(|f| f.cob_id() == 0x182));
+        // Synchronous TPDOs produced 3ms after the SYNC are too late for a 2ms window.
+        for _ in 0..3 {
+            node.event_timer_callback();
+        }
+        take_tx(&mut node);
+        assert!(node.transmit_pdo_messages(true, NodeEvent::Unused, node.sync_count).is_empty());
+        assert!(take_tx(&mut node).iter().all(|f| f.cob_id() != 0x182));
+
+        // Without a window they go out regardless.
+        assert_eq!(sdo_write_u32(&mut node, 0x1007, 0, 0)[0], 0x60);
+        assert!(node.transmit_pdo_messages(true, NodeEvent::Unused, node.sync_count).is_empty());
+        assert!(take_tx(&mut node).iter().any(|f| f.cob_id() == 0x182));
+    }
+
+    #[test]
+    fn test_sync_window_discards_late_rpdo() {
+        let mut node = new_node();
+        send(&mut node, frame(0x000, &[0x01, 2]));
+        assert_eq!(sdo_write_u32(&mut node, 0x1007, 0, 2000)[0], 0x60);
+        send(&mut node, frame(0x080, &[]));
+
+        for _ in 0..3 {
+            node.event_timer_callback();
+        }
+        send(&mut node, frame(0x202, &[0x34, 0x12, 0x56]));
+        send(&mut node, frame(0x080, &[]));
+        assert_eq!(node.object_directory.get_variable(0x2001, 0).unwrap().effective_value().to::<u16>(), 0);
+
+        // Right after a SYNC the RPDO is within the window.
+        send(&mut node, frame(0x202, &[0x34, 0x12, 0x56]));
+        send(&mut node, frame(0x080, &[]));
+        assert_eq!(node.object_directory.get_variable(0x2001, 0).unwrap().effective_value().to::<u16>(), 0x1234);
+    }
+}
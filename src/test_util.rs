@@ -0,0 +1,670 @@
+// In-memory CAN doubles and a small EDS used by the unit tests, so node level
+// behaviours (SDO / PDO / NMT / heartbeat) can be verified without socketcan.
+// The CAN doubles are public with the `test-util` feature, for the tests of
+// applications built on the crate.
+#![allow(dead_code)]
+
+use alloc::collections::VecDeque;
+use core::fmt::Formatter;
+
+use embedded_can::{ErrorKind, Frame, Id, StandardId};
+use embedded_can::nb::Can;
+
+use crate::node::Node;
+use crate::prelude::*;
+
+pub(crate) const TEST_NODE_ID: u8 = 2;
+
+pub(crate) const TEST_EDS: &str = r#"
+[DeviceInfo]
+VendorName=Atomi
+ProductName=Test Device
+
+[1000]
+ParameterName=Device type
+ObjectType=0x7
+DataType=0x0007
+AccessType=ro
+DefaultValue=0x00010192
+PDOMapping=0
+
+[1001]
+ParameterName=Error register
+ObjectType=0x7
+DataType=0x0005
+AccessType=ro
+DefaultValue=0
+PDOMapping=0
+
+[1003]
+ParameterName=Pre-defined error field
+ObjectType=0x8
+SubNumber=9
+
+[1003sub0]
+ParameterName=Number of errors
+ObjectType=0x7
+DataType=0x0005
+AccessType=rw
+DefaultValue=0
+PDOMapping=0
+
+[1003sub1]
+ParameterName=Standard error field 1
+ObjectType=0x7
+DataType=0x0007
+AccessType=ro
+DefaultValue=0
+PDOMapping=0
+
+[1003sub2]
+ParameterName=Standard error field 2
+ObjectType=0x7
+DataType=0x0007
+AccessType=ro
+DefaultValue=0
+PDOMapping=0
+
+[1003sub3]
+ParameterName=Standard error field 3
+ObjectType=0x7
+DataType=0x0007
+AccessType=ro
+DefaultValue=0
+PDOMapping=0
+
+[1003sub4]
+ParameterName=Standard error field 4
+ObjectType=0x7
+DataType=0x0007
+AccessType=ro
+DefaultValue=0
+PDOMapping=0
+
+[1003sub5]
+ParameterName=Standard error field 5
+ObjectType=0x7
+DataType=0x0007
+AccessType=ro
+DefaultValue=0
+PDOMapping=0
+
+[1003sub6]
+ParameterName=Standard error field 6
+ObjectType=0x7
+DataType=0x0007
+AccessType=ro
+DefaultValue=0
+PDOMapping=0
+
+[1003sub7]
+ParameterName=Standard error field 7
+ObjectType=0x7
+DataType=0x0007
+AccessType=ro
+DefaultValue=0
+PDOMapping=0
+
+[1003sub8]
+ParameterName=Standard error field 8
+ObjectType=0x7
+DataType=0x0007
+AccessType=ro
+DefaultValue=0
+PDOMapping=0
+
+[1005]
+ParameterName=COB-ID SYNC message
+ObjectType=0x7
+DataType=0x0007
+AccessType=rw
+DefaultValue=0x00000080
+PDOMapping=0
+
+[1006]
+ParameterName=Communication cycle period
+ObjectType=0x7
+DataType=0x0007
+AccessType=rw
+DefaultValue=0
+PDOMapping=0
+
+[1007]
+ParameterName=Synchronous window length
+ObjectType=0x7
+DataType=0x0007
+AccessType=rw
+DefaultValue=0
+PDOMapping=0
+
+[1008]
+ParameterName=Manufacturer device name
+ObjectType=0x7
+DataType=0x0009
+AccessType=ro
+DefaultValue=Atomi Test Device
+PDOMapping=0
+
+[1009]
+ParameterName=Manufacturer hardware version
+ObjectType=0x7
+DataType=0x0009
+AccessType=ro
+DefaultValue=HW 1.0.0
+PDOMapping=0
+
+[100A]
+ParameterName=Manufacturer software version
+ObjectType=0x7
+DataType=0x0009
+AccessType=ro
+DefaultValue=SW 0.1.3
+PDOMapping=0
+
+[1010]
+ParameterName=Store parameters
+ObjectType=0x8
+SubNumber=4
+
+[1010sub0]
+ParameterName=Largest subindex supported
+ObjectType=0x7
+DataType=0x0005
+AccessType=ro
+DefaultValue=3
+PDOMapping=0
+
+[1010sub1]
+ParameterName=Save all parameters
+ObjectType=0x7
+DataType=0x0007
+AccessType=rw
+DefaultValue=1
+PDOMapping=0
+
+[1010sub2]
+ParameterName=Save communication parameters
+ObjectType=0x7
+DataType=0x0007
+AccessType=rw
+DefaultValue=1
+PDOMapping=0
+
+[1010sub3]
+ParameterName=Save application parameters
+ObjectType=0x7
+DataType=0x0007
+AccessType=rw
+DefaultValue=1
+PDOMapping=0
+
+[1011]
+ParameterName=Restore default parameters
+ObjectType=0x8
+SubNumber=4
+
+[1011sub0]
+ParameterName=Largest subindex supported
+ObjectType=0x7
+DataType=0x0005
+AccessType=ro
+DefaultValue=3
+PDOMapping=0
+
+[1011sub1]
+ParameterName=Restore all default parameters
+ObjectType=0x7
+DataType=0x0007
+AccessType=rw
+DefaultValue=1
+PDOMapping=0
+
+[1011sub2]
+ParameterName=Restore communication default parameters
+ObjectType=0x7
+DataType=0x0007
+AccessType=rw
+DefaultValue=1
+PDOMapping=0
+
+[1011sub3]
+ParameterName=Restore application default parameters
+ObjectType=0x7
+DataType=0x0007
+AccessType=rw
+DefaultValue=1
+PDOMapping=0
+
+[1014]
+ParameterName=COB-ID EMCY
+ObjectType=0x7
+DataType=0x0007
+AccessType=rw
+DefaultValue=$NODEID+0x80
+PDOMapping=0
+
+[1015]
+ParameterName=Inhibit time EMCY
+ObjectType=0x7
+DataType=0x0006
+AccessType=rw
+DefaultValue=0
+PDOMapping=0
+
+[1017]
+ParameterName=Producer heartbeat time
+ObjectType=0x7
+DataType=0x0006
+AccessType=rw
+DefaultValue=0
+PDOMapping=0
+
+[1019]
+ParameterName=Synchronous counter overflow value
+ObjectType=0x7
+DataType=0x0005
+AccessType=rw
+DefaultValue=0
+PDOMapping=0
+
+[1018]
+ParameterName=Identity object
+ObjectType=0x9
+SubNumber=5
+
+[1018sub0]
+ParameterName=Number of entries
+ObjectType=0x7
+DataType=0x0005
+AccessType=ro
+DefaultValue=4
+PDOMapping=0
+
+[1018sub1]
+ParameterName=Vendor-ID
+ObjectType=0x7
+DataType=0x0007
+AccessType=const
+DefaultValue=0x0000ABCD
+PDOMapping=0
+
+[1018sub2]
+ParameterName=Product code
+ObjectType=0x7
+DataType=0x0007
+AccessType=const
+DefaultValue=0x00001234
+PDOMapping=0
+
+[1018sub3]
+ParameterName=Revision number
+ObjectType=0x7
+DataType=0x0007
+AccessType=const
+DefaultValue=0x00010001
+PDOMapping=0
+
+[1018sub4]
+ParameterName=Serial number
+ObjectType=0x7
+DataType=0x0007
+AccessType=const
+DefaultValue=0x00000042
+PDOMapping=0
+
+[1400]
+ParameterName=RPDO communication parameter 1
+ObjectType=0x9
+SubNumber=3
+
+[1400sub0]
+ParameterName=Highest sub-index supported
+ObjectType=0x7
+DataType=0x0005
+AccessType=ro
+DefaultValue=2
+PDOMapping=0
+
+[1400sub1]
+ParameterName=COB-ID used by RPDO
+ObjectType=0x7
+DataType=0x0007
+AccessType=rw
+DefaultValue=$NODEID+0x200
+PDOMapping=0
+
+[1400sub2]
+ParameterName=Transmission type
+ObjectType=0x7
+DataType=0x0005
+AccessType=rw
+DefaultValue=1
+PDOMapping=0
+
+[1600]
+ParameterName=RPDO mapping parameter 1
+ObjectType=0x9
+SubNumber=3
+
+[1600sub0]
+ParameterName=Number of mapped objects
+ObjectType=0x7
+DataType=0x0005
+AccessType=rw
+DefaultValue=2
+PDOMapping=0
+
+[1600sub1]
+ParameterName=Mapping entry 1
+ObjectType=0x7
+DataType=0x0007
+AccessType=rw
+DefaultValue=0x20010010
+PDOMapping=0
+
+[1600sub2]
+ParameterName=Mapping entry 2
+ObjectType=0x7
+DataType=0x0007
+AccessType=rw
+DefaultValue=0x20020008
+PDOMapping=0
+
+[1800]
+ParameterName=TPDO communication parameter 1
+ObjectType=0x9
+SubNumber=5
+
+[1800sub0]
+ParameterName=Highest sub-index supported
+ObjectType=0x7
+DataType=0x0005
+AccessType=ro
+DefaultValue=5
+PDOMapping=0
+
+[1800sub1]
+ParameterName=COB-ID used by TPDO
+ObjectType=0x7
+DataType=0x0007
+AccessType=rw
+DefaultValue=$NODEID+0x180
+PDOMapping=0
+
+[1800sub2]
+ParameterName=Transmission type
+ObjectType=0x7
+DataType=0x0005
+AccessType=rw
+DefaultValue=1
+PDOMapping=0
+
+[1800sub3]
+ParameterName=Inhibit time
+ObjectType=0x7
+DataType=0x0006
+AccessType=rw
+DefaultValue=0
+PDOMapping=0
+
+[1800sub5]
+ParameterName=Event timer
+ObjectType=0x7
+DataType=0x0006
+AccessType=rw
+DefaultValue=0
+PDOMapping=0
+
+[1A00]
+ParameterName=TPDO mapping parameter 1
+ObjectType=0x9
+SubNumber=3
+
+[1A00sub0]
+ParameterName=Number of mapped objects
+ObjectType=0x7
+DataType=0x0005
+AccessType=rw
+DefaultValue=1
+PDOMapping=0
+
+[1A00sub1]
+ParameterName=Mapping entry 1
+ObjectType=0x7
+DataType=0x0007
+AccessType=rw
+DefaultValue=0x20000020
+PDOMapping=0
+
+[1A00sub2]
+ParameterName=Mapping entry 2
+ObjectType=0x7
+DataType=0x0007
+AccessType=rw
+DefaultValue=0
+PDOMapping=0
+
+[2000]
+ParameterName=Application u32
+ObjectType=0x7
+DataType=0x0007
+AccessType=rw
+DefaultValue=0x12345678
+PDOMapping=1
+
+[2001]
+ParameterName=Application u16
+ObjectType=0x7
+DataType=0x0006
+AccessType=rw
+DefaultValue=0
+PDOMapping=1
+
+[2002]
+ParameterName=Application u8
+ObjectType=0x7
+DataType=0x0005
+AccessType=rw
+DefaultValue=0
+PDOMapping=1
+
+[2003]
+ParameterName=Application string
+ObjectType=0x7
+DataType=0x0009
+AccessType=rw
+DefaultValue=Hello CANopen world
+PDOMapping=0
+
+[2004]
+ParameterName=Configured u16
+ObjectType=0x7
+DataType=0x0006
+AccessType=rw
+DefaultValue=0x0010
+ParameterValue=0x0020
+PDOMapping=1
+
+[3040]
+ParameterName=Position i64
+ObjectType=0x7
+DataType=0x0015
+AccessType=rw
+LowLimit=-9223372036854775808
+HighLimit=0x7FFFFFFFFFFFFFFF
+DefaultValue=0
+PDOMapping=0
+
+[3041]
+ParameterName=Limited i16
+ObjectType=0x7
+DataType=0x0003
+AccessType=rw
+LowLimit=-100
+HighLimit=100
+DefaultValue=0
+PDOMapping=0
+"#;
+
+#[derive(Clone, PartialEq)]
+pub struct MockFrame {
+    id: Id,
+    data: Vec<u8>,
+    remote: bool,
+}
+
+impl MockFrame {
+    pub fn cob_id(&self) -> u16 {
+        match self.id {
+            Id::Standard(sid) => sid.as_raw(),
+            Id::Extended(eid) => eid.as_raw() as u16,
+        }
+    }
+}
+
+impl Frame for MockFrame {
+    fn new(id: impl Into<Id>, data: &[u8]) -> Option<Self> {
+        if data.len() > 8 {
+            return None;
+        }
+        Some(MockFrame { id: id.into(), data: data.to_vec(), remote: false })
+    }
+
+    fn new_remote(id: impl Into<Id>, dlc: usize) -> Option<Self> {
+        if dlc > 8 {
+            return None;
+        }
+        Some(MockFrame { id: id.into(), data: vec![0; dlc], remote: true })
+    }
+
+    fn is_extended(&self) -> bool {
+        matches!(self.id, Id::Extended(_))
+    }
+
+    fn is_remote_frame(&self) -> bool {
+        self.remote
+    }
+
+    fn id(&self) -> Id {
+        self.id
+    }
+
+    fn dlc(&self) -> usize {
+        self.data.len()
+    }
+
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl Debug for MockFrame {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "MockFrame({:#x}, {:x?})", self.cob_id(), self.data)
+    }
+}
+
+#[derive(Debug)]
+pub struct MockError;
+
+impl embedded_can::Error for MockError {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Other
+    }
+}
+
+/// A CAN "bus" with an injectable RX queue and a captured TX list.
+#[derive(Default)]
+pub struct MockCan {
+    pub rx: VecDeque<MockFrame>,
+    pub tx: Vec<MockFrame>,
+}
+
+impl MockCan {
+    /// Queues a frame to be received.
+    pub fn push_rx(&mut self, frame: MockFrame) {
+        self.rx.push_back(frame);
+    }
+
+    /// Removes and returns the oldest transmitted frame.
+    pub fn pop_tx(&mut self) -> Option<MockFrame> {
+        if self.tx.is_empty() { None } else { Some(self.tx.remove(0)) }
+    }
+
+    /// Asserts that the oldest transmitted frame has this COB-ID and payload, and removes it.
+    pub fn assert_next_tx(&mut self, cob_id: u16, data: &[u8]) {
+        match self.pop_tx() {
+            Some(frame) => {
+                assert_eq!((frame.cob_id(), frame.data()), (cob_id, data), "unexpected frame {:x?}", frame);
+            }
+            None => panic!("no frame transmitted, expected {:#x} {:x?}", cob_id, data),
+        }
+    }
+}
+
+impl Can for MockCan {
+    type Frame = MockFrame;
+    type Error = MockError;
+
+    fn transmit(&mut self, frame: &Self::Frame) -> nb::Result<Option<Self::Frame>, Self::Error> {
+        self.tx.push(frame.clone());
+        Ok(None)
+    }
+
+    fn receive(&mut self) -> nb::Result<Self::Frame, Self::Error> {
+        self.rx.pop_front().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+pub fn frame(cob_id: u16, data: &[u8]) -> MockFrame {
+    MockFrame::new(StandardId::new(cob_id).unwrap(), data).unwrap()
+}
+
+/// A booted node in Pre-operational, its boot-up frames already consumed.
+pub(crate) fn new_node() -> Node<MockCan> {
+    let mut node = Node::new(TEST_NODE_ID, TEST_EDS, MockCan::default()).unwrap();
+    node.init().unwrap();
+    take_tx(&mut node);
+    node
+}
+
+/// Feeds one frame into the node and returns all frames it transmitted in response.
+pub(crate) fn send(node: &mut Node<MockCan>, frame: MockFrame) -> Vec<MockFrame> {
+    node.can_network.rx.push_back(frame);
+    node.process_one_frame();
+    take_tx(node)
+}
+
+pub(crate) fn take_tx(node: &mut Node<MockCan>) -> Vec<MockFrame> {
+    core::mem::take(&mut node.can_network.tx)
+}
+
+/// Sends an SDO request to the node and returns the (single) response payload.
+pub(crate) fn sdo(node: &mut Node<MockCan>, data: &[u8]) -> Vec<u8> {
+    let resp = send(node, frame(0x600 + node.node_id as u16, data));
+    assert_eq!(resp.len(), 1, "expected one SDO response, got {:x?}", resp);
+    assert_eq!(resp[0].cob_id(), 0x580 + node.node_id as u16);
+    resp[0].data().to_vec()
+}
+
+pub(crate) fn sdo_write_u16(node: &mut Node<MockCan>, index: u16, sub_index: u8, value: u16) -> Vec<u8> {
+    let [il, ih] = index.to_le_bytes();
+    let [d0, d1] = value.to_le_bytes();
+    sdo(node, &[0x2B, il, ih, sub_index, d0, d1, 0, 0])
+}
+
+pub(crate) fn sdo_write_u32(node: &mut Node<MockCan>, index: u16, sub_index: u8, value: u32) -> Vec<u8> {
+    let [il, ih] = index.to_le_bytes();
+    let [d0, d1, d2, d3] = value.to_le_bytes();
+    sdo(node, &[0x23, il, ih, sub_index, d0, d1, d2, d3])
+}
+
+pub(crate) fn sdo_write_u8(node: &mut Node<MockCan>, index: u16, sub_index: u8, value: u8) -> Vec<u8> {
+    let [il, ih] = index.to_le_bytes();
+    sdo(node, &[0x2F, il, ih, sub_index, value, 0, 0, 0])
+}
+
+pub(crate) fn sdo_read(node: &mut Node<MockCan>, index: u16, sub_index: u8) -> Vec<u8> {
+    let [il, ih] = index.to_le_bytes();
+    sdo(node, &[0x40, il, ih, sub_index, 0, 0, 0, 0])
+}
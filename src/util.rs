@@ -4,7 +4,6 @@ use core::str::FromStr;
 use embedded_can::{Frame, Id, StandardId};
 
 use crate::error::{AbortCode, ErrorCode};
-use crate::error::AbortCode::GeneralError;
 use crate::prelude::*;
 
 pub trait ParseRadix: FromStr {
@@ -57,10 +56,7 @@ pub fn parse_number<T: ParseRadix + Default>(s: &str) -> T {
 }
 
 pub fn result_to_option<T, Err>(res: Result<T, Err>) -> Option<T> {
-    match res {
-        Ok(val) => Some(val),
-        Err(_) => None,
-    }
+    res.ok()
 }
 
 pub fn get_cob_id<F: Frame>(frame: &F) -> Option<u16> {
@@ -91,18 +87,27 @@ pub(crate) fn is_sub(s: &str) -> Option<(u16, u8)> {
     }
 }
 
-pub(crate) fn is_name(s: &str) -> Option<u16> {
-    s.ends_with("Name")
-        .then(|| s[0..4].chars().all(is_hex_char))
+fn is_compact_section(s: &str, suffix: &str) -> Option<u16> {
+    s.ends_with(suffix)
+        .then(|| s.len() == 4 + suffix.len() && s[0..4].chars().all(is_hex_char))
         .and_then(|valid| valid.then(|| u16::from_str_radix(&s[0..4], 16).ok()))
         .flatten()
 }
 
-pub fn get_index_from_can_frame<F: Frame>(frame: &F) -> (u16, u8) {
-    (
-        u16::from_le_bytes([frame.data()[1], frame.data()[2]]),
-        frame.data()[3],
-    )
+pub(crate) fn is_name(s: &str) -> Option<u16> {
+    is_compact_section(s, "Name")
+}
+
+pub(crate) fn is_value(s: &str) -> Option<u16> {
+    is_compact_section(s, "Value")
+}
+
+/// The (index, sub_index) multiplexer of a SDO frame, `None` if the frame is too short.
+pub fn get_index_from_can_frame<F: Frame>(frame: &F) -> Option<(u16, u8)> {
+    match frame.data() {
+        [_, lo, hi, sub_index, ..] => Some((u16::from_le_bytes([*lo, *hi]), *sub_index)),
+        _ => None,
+    }
 }
 
 pub(crate) fn flatten(slices: &[&[u8]]) -> Vec<u8> {
@@ -110,18 +115,21 @@ pub(crate) fn flatten(slices: &[&[u8]]) -> Vec<u8> {
         .iter()
         .flat_map(|&slice| slice.iter().cloned())
         .take(8)
-        .chain(core::iter::repeat(0).take(8))
+        .chain(core::iter::repeat_n(0, 8))
         .take(8)
         .collect()
 }
 
+/// Writes the low `bytes` bytes (at most 8) of a number in little-endian, the
+/// CANopen wire order. Reverse of `vec_to_u64()`.
 pub fn u64_to_vec(data: u64, bytes: usize) -> Vec<u8> {
-    data.to_be_bytes()[8 - min(bytes, 8)..].to_vec()
+    data.to_le_bytes()[..min(bytes, 8)].to_vec()
 }
 
+/// Reads up to 8 bytes as a little-endian number, the CANopen wire order.
 pub(crate) fn vec_to_u64(v: &[u8]) -> u64 {
     let mut res = 0u64;
-    for &x in v.iter().take(8) {
+    for &x in v.iter().take(8).rev() {
         res = (res << 8) | (x as u64);
     }
     res
@@ -136,18 +144,43 @@ pub fn create_frame_with_padding<F: Frame + Debug>(cob_id: u16, data: &[u8])
            &packet).ok_or(ErrorCode::FrameCreationFailed {data: data.to_vec()})
 }
 
+/// Like `create_frame_with_padding()`, but a payload longer than the 8 bytes
+/// of a CAN frame is an error instead of being truncated.
+pub fn create_frame_checked<F: Frame + Debug>(cob_id: u16, data: &[u8]) -> Result<F, ErrorCode> {
+    if data.len() > 8 {
+        return Err(ErrorCode::ByteLengthExceedsLimit);
+    }
+    create_frame_with_padding(cob_id, data)
+}
+
 pub fn create_frame<F: Frame + Debug>(cob_id: u16, data: &[u8]) -> Result<F, ErrorCode> {
     F::new(StandardId::new(cob_id).ok_or(ErrorCode::InvalidStandardId {cob_id})?, data)
         .ok_or(ErrorCode::FrameCreationFailed{data: data.to_vec()})
 }
 
+/// Copies a frame, `Frame` doesn't require `Clone`.
+pub(crate) fn copy_frame<F: Frame>(frame: &F) -> Option<F> {
+    if frame.is_remote_frame() {
+        F::new_remote(frame.id(), frame.dlc())
+    } else {
+        F::new(frame.id(), frame.data())
+    }
+}
+
+/// Reads a little-endian field of 1 to 4 bytes, shorter fields are zero-extended.
 pub fn convert_bytes_to_u32(data: &[u8]) -> Result<u32, ErrorCode> {
-    match data.try_into() {
-        Ok(arr) => Ok(u32::from_le_bytes(arr)),
-        Err(_) => Err(make_abort_error(GeneralError, "".to_string())),
+    match data.len() {
+        0 => Err(make_abort_error(AbortCode::DataTypeMismatchLengthTooLow, "".to_string())),
+        1..=4 => {
+            let mut bytes = [0u8; 4];
+            bytes[..data.len()].copy_from_slice(data);
+            Ok(u32::from_le_bytes(bytes))
+        }
+        _ => Err(make_abort_error(AbortCode::DataTypeMismatchLengthTooHigh, "".to_string())),
     }
 }
 
+#[cfg(not(feature = "small-crc"))]
 static CCITT_HASH: [u16; 256] = [
     0x0000, 0x1021, 0x2042, 0x3063, 0x4084, 0x50a5, 0x60c6, 0x70e7, 0x8108, 0x9129, 0xa14a, 0xb16b,
     0xc18c, 0xd1ad, 0xe1ce, 0xf1ef, 0x1231, 0x0210, 0x3273, 0x2252, 0x52b5, 0x4294, 0x72f7, 0x62d6,
@@ -173,6 +206,8 @@ static CCITT_HASH: [u16; 256] = [
     0x2e93, 0x3eb2, 0x0ed1, 0x1ef0,
 ];
 
+/// The CRC of the SDO block transfers (CiA 301: CCITT polynomial 0x1021, initial value 0).
+#[cfg(not(feature = "small-crc"))]
 pub fn crc16_canopen_with_lut(bytes: &[u8]) -> u16 {
     let mut crc: u16 = 0x0000;
 
@@ -184,6 +219,26 @@ pub fn crc16_canopen_with_lut(bytes: &[u8]) -> u16 {
     crc
 }
 
+/// With `small-crc` the CRC is computed bit by bit, without the 512 bytes table.
+#[cfg(feature = "small-crc")]
+pub fn crc16_canopen_with_lut(bytes: &[u8]) -> u16 {
+    crc16_canopen_bitwise(bytes)
+}
+
+#[cfg(any(test, feature = "small-crc"))]
+fn crc16_canopen_bitwise(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0x0000;
+
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+
+    crc
+}
+
 pub fn make_abort_error(abort_code: AbortCode, more_info: String) -> ErrorCode {
     ErrorCode::AbortCodeWrapper {
         abort_code,
@@ -191,78 +246,25 @@ pub fn make_abort_error(abort_code: AbortCode, more_info: String) -> ErrorCode {
     }
 }
 
+/// Node-ids are 1..=127, 0 addresses all nodes. The LSS "unconfigured"
+/// node-id 0xFF is no node-id either, `$NODEID` can't be evaluated with it.
+pub(crate) fn check_node_id(node_id: u8) -> Result<(), ErrorCode> {
+    if (1..=127).contains(&node_id) {
+        Ok(())
+    } else {
+        Err(ErrorCode::InvalidNodeId { node_id })
+    }
+}
+
 #[cfg(test)]
 mod util_tests {
     use alloc::vec;
     use alloc::vec::Vec;
-    use core::fmt::{Debug, Formatter};
-    use embedded_can::{ExtendedId, Frame, Id, StandardId};
-    use super::{create_frame, parse_number, ErrorCode, vec_to_u64, result_to_option, get_cob_id, get_index_from_can_frame, convert_bytes_to_u32};
+    use embedded_can::{ExtendedId, Frame, StandardId};
+    use super::{create_frame, create_frame_checked, parse_number, ErrorCode, vec_to_u64, result_to_option, get_cob_id, get_index_from_can_frame, convert_bytes_to_u32, make_abort_error};
+    use crate::error::AbortCode;
     use super::u64_to_vec;
-
-    struct MockFrame {
-        id: Id,
-        data: Vec<u8>,
-    }
-
-    impl MockFrame {
-        fn new_standard(id: u16, data: &[u8]) -> Self {
-            MockFrame {
-                id: Id::Standard(StandardId::new(id).expect("Invalid StandardId")),
-                data: data.to_vec(),
-            }
-        }
-
-        fn new_extended(id: u32, data: &[u8]) -> Self {
-            MockFrame {
-                id: Id::Extended(ExtendedId::new(id).expect("Invalid ExtendedId")),
-                data: data.to_vec(),
-            }
-        }
-    }
-
-    impl Frame for MockFrame {
-        fn new(_id: impl Into<Id>, data: &[u8]) -> Option<Self> {
-            if data.len() > 4 {
-                None
-            } else {
-                Some(MockFrame {
-                    id: _id.into(),
-                    data: data.to_vec(),
-                })
-            }
-        }
-
-        fn new_remote(_id: impl Into<Id>, _dlc: usize) -> Option<Self> {
-            None
-        }
-
-        fn is_extended(&self) -> bool {
-            matches!(self.id, Id::Extended(_))
-        }
-
-        fn is_remote_frame(&self) -> bool {
-            false
-        }
-
-        fn id(&self) -> Id {
-            self.id
-        }
-
-        fn dlc(&self) -> usize {
-            self.data.len()
-        }
-
-        fn data(&self) -> &[u8] {
-            &self.data
-        }
-    }
-
-    impl Debug for MockFrame {
-        fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
-            write!(f, "mock_frame: {:x?}", self.data)
-        }
-    }
+    use crate::test_util::MockFrame;
 
     #[test]
     fn test_create_frame_success() {
@@ -283,7 +285,7 @@ mod util_tests {
     #[test]
     fn test_create_frame_frame_creation_failed() {
         let cob_id = 0x123;
-        let data = &[0x01, 0x02, 0x03, 0x04, 0x05];
+        let data = &[0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09];
         let result = create_frame::<MockFrame>(cob_id, data);
         match result {
             Err(ErrorCode::FrameCreationFailed { data: returned_data }) => {
@@ -296,22 +298,23 @@ mod util_tests {
     #[test]
     fn test_basic() {
         assert_eq!(u64_to_vec(0x123456789ABCDEF0, 8),
-                   vec![0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0]);
-        assert_eq!(u64_to_vec(0x01, 2), vec![0x0, 0x1]);
-        assert_eq!(u64_to_vec(0x8002, 2), vec![0x80, 0x02]);
+                   vec![0xF0, 0xDE, 0xBC, 0x9A, 0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(u64_to_vec(0x01, 2), vec![0x1, 0x0]);
+        assert_eq!(u64_to_vec(0x8002, 2), vec![0x02, 0x80]);
+        assert_eq!(vec_to_u64(&u64_to_vec(0x8002, 2)), 0x8002);
     }
 
     #[test]
     fn test_byte_length_exceeds_limit() {
-        assert_eq!(u64_to_vec(0x01, 9), vec![0, 0, 0, 0, 0, 0, 0, 1]);
-        assert_eq!(u64_to_vec(0x123456789ABCDEF0, 10), vec![0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0]);
+        assert_eq!(u64_to_vec(0x01, 9), vec![1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(u64_to_vec(0x123456789ABCDEF0, 10), vec![0xF0, 0xDE, 0xBC, 0x9A, 0x78, 0x56, 0x34, 0x12]);
     }
 
     #[test]
     fn test_boundary_conditions() {
-        assert_eq!(u64_to_vec(0x123456789ABCDEF0, 0), vec![]);
-        assert_eq!(u64_to_vec(0x123456789ABCDEF0, 3), vec![0xBC, 0xDE, 0xF0]);
-        assert_eq!(u64_to_vec(0x123456789ABCDEF0, 8), vec![0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0xDE, 0xF0]);
+        assert_eq!(u64_to_vec(0x123456789ABCDEF0, 0), Vec::<u8>::new());
+        assert_eq!(u64_to_vec(0x123456789ABCDEF0, 3), vec![0xF0, 0xDE, 0xBC]);
+        assert_eq!(u64_to_vec(0x123456789ABCDEF0, 8), vec![0xF0, 0xDE, 0xBC, 0x9A, 0x78, 0x56, 0x34, 0x12]);
     }
 
     #[test]
@@ -322,34 +325,34 @@ mod util_tests {
 
     #[test]
     fn test_empty_vector() {
-        assert_eq!(vec_to_u64(&vec![]), 0);
+        assert_eq!(vec_to_u64(&[]), 0);
     }
 
     #[test]
     fn test_full_length_vector() {
-        assert_eq!(vec_to_u64(&vec![0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]),
-                   0x0102030405060708);
+        assert_eq!(vec_to_u64(&[0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08]),
+                   0x0807060504030201);
     }
 
     #[test]
     fn test_partial_length_vector() {
-        assert_eq!(vec_to_u64(&vec![0x01, 0x02, 0x03]), 0x010203);
+        assert_eq!(vec_to_u64(&[0x01, 0x02, 0x03]), 0x030201);
     }
 
     #[test]
     fn test_max_value_vector() {
-        assert_eq!(vec_to_u64(&vec![0xFF; 8]), 0xFFFFFFFFFFFFFFFF);
+        assert_eq!(vec_to_u64(&[0xFF; 8]), 0xFFFFFFFFFFFFFFFF);
     }
 
     #[test]
     fn test_single_element_vector() {
-        assert_eq!(vec_to_u64(&vec![0x01]), 0x01);
+        assert_eq!(vec_to_u64(&[0x01]), 0x01);
     }
 
     #[test]
     fn test_long_vector() {
-        assert_eq!(vec_to_u64(&vec![0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A]),
-                   0x0102030405060708);
+        assert_eq!(vec_to_u64(&[0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0A]),
+                   0x0807060504030201);
     }
 
     #[test]
@@ -393,6 +396,15 @@ mod util_tests {
         assert_eq!(crc, 0x43F3, "({:x} != 0x43F3)", crc);
     }
 
+    #[test]
+    fn test_crc16_bitwise_matches_table() {
+        let inputs: [&[u8]; 5] = [b"", b"\x00", b"123456789", b"CANopenDemoPIC32", &[0xFF; 300]];
+        for input in inputs {
+            assert_eq!(super::crc16_canopen_bitwise(input), crate::util::crc16_canopen_with_lut(input));
+        }
+        assert_eq!(super::crc16_canopen_bitwise(b"123456789"), 0x31C3);
+    }
+
     #[test]
     fn test_result_to_option() {
         let ok_result: Result<i32, &str> = Ok(10);
@@ -404,8 +416,10 @@ mod util_tests {
 
     #[test]
     fn test_get_cob_id() {
-        let frame_with_standard_id = MockFrame::new_standard(123, &[0, 0, 0, 0]);
-        let frame_with_extended_id = MockFrame::new_extended(123456, &[0, 0, 0, 0]);
+        let frame_with_standard_id =
+            MockFrame::new(StandardId::new(123).unwrap(), &[0, 0, 0, 0]).unwrap();
+        let frame_with_extended_id =
+            MockFrame::new(ExtendedId::new(123456).unwrap(), &[0, 0, 0, 0]).unwrap();
 
         assert_eq!(get_cob_id(&frame_with_standard_id), Some(123));
         assert_eq!(get_cob_id(&frame_with_extended_id), None);
@@ -413,16 +427,32 @@ mod util_tests {
 
     #[test]
     fn test_get_index_from_can_frame() {
-        let frame = MockFrame::new_standard(123, &[0, 1, 2, 3]);
-        assert_eq!(get_index_from_can_frame(&frame), (0x0201, 3));
+        let frame = MockFrame::new(StandardId::new(123).unwrap(), &[0, 1, 2, 3]).unwrap();
+        assert_eq!(get_index_from_can_frame(&frame), Some((0x0201, 3)));
+        let frame = MockFrame::new(StandardId::new(123).unwrap(), &[0, 1, 2]).unwrap();
+        assert_eq!(get_index_from_can_frame(&frame), None);
     }
 
     #[test]
     fn test_convert_bytes_to_u32() {
-        let valid_data = &[1, 0, 0, 0];
-        let invalid_data = &[1, 0, 0];
+        assert_eq!(convert_bytes_to_u32(&[1, 0, 0, 0]), Ok(1));
+        assert_eq!(convert_bytes_to_u32(&[0x78, 0x56, 0x34, 0x12]), Ok(0x12345678));
+        assert_eq!(convert_bytes_to_u32(&[0x34, 0x12]), Ok(0x1234));
+        assert_eq!(convert_bytes_to_u32(&[0xFF]), Ok(0xFF));
+        assert_eq!(convert_bytes_to_u32(&[]),
+                   Err(make_abort_error(AbortCode::DataTypeMismatchLengthTooLow, "".to_string())));
+        assert_eq!(convert_bytes_to_u32(&[1, 0, 0, 0, 0]),
+                   Err(make_abort_error(AbortCode::DataTypeMismatchLengthTooHigh, "".to_string())));
+    }
 
-        assert_eq!(convert_bytes_to_u32(valid_data), Ok(1));
-        assert!(convert_bytes_to_u32(invalid_data).is_err());
+    #[test]
+    fn test_create_frame_checked() {
+        let frame: MockFrame = create_frame_checked(0x582, &[1, 2, 3, 4, 5, 6, 7, 8]).unwrap();
+        assert_eq!(frame.data(), &[1, 2, 3, 4, 5, 6, 7, 8]);
+        let frame: MockFrame = create_frame_checked(0x582, &[1, 2]).unwrap();
+        assert_eq!(frame.data(), &[1, 2, 0, 0, 0, 0, 0, 0]);
+
+        let res: Result<MockFrame, ErrorCode> = create_frame_checked(0x582, &[0; 9]);
+        assert_eq!(res.unwrap_err(), ErrorCode::ByteLengthExceedsLimit);
     }
 }
@@ -1,9 +1,12 @@
 use crate::data_type::DataType;
 use crate::prelude::*;
 use crate::{error, util};
+use crate::error::AbortCode::DataTypeMismatchLengthMismatch;
 use crate::error::ErrorCode;
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
 pub struct Value {
     data: Vec<u8>,
 }
@@ -31,9 +34,19 @@ impl PartialEq for Value {
     }
 }
 
+impl Eq for Value {}
+
+/// Conversion between a value and its bytes in the object directory. Numbers
+/// are always little-endian, as on the CANopen wire, whatever the host is.
 pub trait ByteConvertible: Sized {
     fn from_bytes(bytes: &[u8]) -> Self;
     fn to_bytes(&self) -> Vec<u8>;
+    /// Like `from_bytes()`, but returns `None` instead of a fallback value
+    /// when the bytes don't fit the type. By default, when their length
+    /// differs from the size of the type.
+    fn try_from_bytes(bytes: &[u8]) -> Option<Self> {
+        (bytes.len() == core::mem::size_of::<Self>()).then(|| Self::from_bytes(bytes))
+    }
 }
 
 macro_rules! impl_byte_convertible_for_int {
@@ -44,12 +57,14 @@ macro_rules! impl_byte_convertible_for_int {
             }
 
             fn from_bytes(bytes: &[u8]) -> Self {
-                if bytes.len() == $len {
-                    if let Ok(arr) = bytes.try_into() {
-                        return <$t>::from_le_bytes(arr);
-                    }
+                Self::try_from_bytes(bytes).unwrap_or(0 as $t)
+            }
+
+            fn try_from_bytes(bytes: &[u8]) -> Option<Self> {
+                if bytes.len() != $len {
+                    return None;
                 }
-                0 as $t
+                bytes.try_into().ok().map(<$t>::from_le_bytes)
             }
         }
     };
@@ -74,6 +89,10 @@ impl ByteConvertible for String {
     fn to_bytes(&self) -> Vec<u8> {
         self.as_bytes().to_vec()
     }
+
+    fn try_from_bytes(bytes: &[u8]) -> Option<Self> {
+        String::from_utf8(bytes.to_vec()).ok()
+    }
 }
 
 impl Value {
@@ -85,6 +104,11 @@ impl Value {
     pub fn to<T: ByteConvertible>(&self) -> T {
         T::from_bytes(self.as_slice())
     }
+
+    pub fn try_to<T: ByteConvertible>(&self) -> Result<T, ErrorCode> {
+        T::try_from_bytes(self.as_slice()).ok_or_else(|| util::make_abort_error(
+            DataTypeMismatchLengthMismatch, format!("{} bytes: {:x?}", self.data.len(), self.data)))
+    }
 }
 
 fn make_error(data_type: DataType, data_string: &str) -> ErrorCode {
@@ -159,10 +183,28 @@ fn string_to_value(data_type: &DataType, data_string: &str) -> Result<Value, Err
             Ok(Value::new(val.to_bytes()))
         }
 
-        DataType::VisibleString | DataType::OctetString | DataType::UnicodeString => Ok(Value {
-            data: data_string.as_bytes().to_vec(),
+        DataType::VisibleString => Ok(Value {
+            data: unescape_string(data_string).into_bytes(),
+        }),
+
+        // Whitespace-separated hex bytes, e.g. "00 11 22" or "0x00 0xFF".
+        DataType::OctetString => {
+            let data = data_string
+                .split_whitespace()
+                .map(|token| {
+                    let hex = token.strip_prefix("0x").or_else(|| token.strip_prefix("0X")).unwrap_or(token);
+                    u8::from_str_radix(hex, 16).map_err(|_| make_error(*data_type, data_string))
+                })
+                .collect::<Result<Vec<u8>, ErrorCode>>()?;
+            Ok(Value { data })
+        }
+
+        DataType::UnicodeString => Ok(Value {
+            data: data_string.encode_utf16().flat_map(u16::to_le_bytes).collect(),
         }),
 
+        DataType::Domain if data_string.is_empty() => Ok(Value { data: vec![] }),
+
         DataType::Domain => {
             let val: i32 = data_string
                 .parse()
@@ -172,21 +214,55 @@ fn string_to_value(data_type: &DataType, data_string: &str) -> Result<Value, Err
     }
 }
 
+/// Strips surrounding double quotes and decodes the `\n`, `\t` and `\\`
+/// escapes of an EDS string. Any other backslash, e.g. a trailing one, is kept.
+fn unescape_string(s: &str) -> String {
+    let s = match s.strip_prefix('"').and_then(|rest| rest.strip_suffix('"')) {
+        Some(inner) => inner,
+        None => s,
+    };
+    let mut res = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        let escaped = match (c, chars.peek()) {
+            ('\\', Some('n')) => '\n',
+            ('\\', Some('t')) => '\t',
+            ('\\', Some('\\')) => '\\',
+            _ => {
+                res.push(c);
+                continue;
+            }
+        };
+        chars.next();
+        res.push(escaped);
+    }
+    res
+}
+
+fn parse_operand(s: &str) -> Option<i64> {
+    if s.starts_with("0x") || s.starts_with("0X") {
+        i64::from_str_radix(&s[2..], 16).ok()
+    } else {
+        s.parse::<i64>().ok()
+    }
+}
+
+/// Evaluates EDS expressions like `$NODEID+0x180` or `$NODEID*0x10+0x200`:
+/// a sum of terms, each term being a product of decimal / hex operands.
+/// Malformed or overflowing expressions are returned unchanged.
 fn evaluate_expression_with_node_id(node_id: u8, expression: &str) -> String {
     // Replace $NODEID with the actual node_id
     let modified_expression = expression.replace("$NODEID", &node_id.to_string());
 
-    // Evaluate simple arithmetic expressions
-    modified_expression
-        .split('+')
-        .map(|s| s.trim())
-        .filter_map(|s| if s.starts_with("0x") || s.starts_with("0X") {
-            i64::from_str_radix(&s[2..], 16).ok()
-        } else {
-            s.parse::<i64>().ok()
-        })
-        .sum::<i64>()
-        .to_string()
+    let sum = modified_expression.split('+').try_fold(0i64, |acc, term| {
+        let product = term.split('*')
+            .try_fold(1i64, |p, factor| p.checked_mul(parse_operand(factor.trim())?))?;
+        acc.checked_add(product)
+    });
+    match sum {
+        Some(v) => v.to_string(),
+        None => expression.to_string(),
+    }
 }
 
 pub(crate) fn get_formatted_value_from_properties(
@@ -199,11 +275,15 @@ pub(crate) fn get_formatted_value_from_properties(
         Some(value) if !value.is_empty() => value,
         _ => return None,
     };
+    format_value_with_node_id(raw, node_id, data_type)
+}
 
+/// Converts a raw EDS value, which may depend on `$NODEID`, to a value of `data_type`.
+pub(crate) fn format_value_with_node_id(raw: &str, node_id: u8, data_type: &DataType) -> Option<Value> {
     let modified_raw = if raw.contains("$NODEID") {
         evaluate_expression_with_node_id(node_id, raw)
     } else {
-        raw.clone()
+        raw.to_string()
     };
 
     match string_to_value(data_type, &modified_raw) {
@@ -220,6 +300,8 @@ mod value_tests {
     use alloc::string::{String, ToString};
     use alloc::{format, vec};
     use crate::data_type::DataType;
+    use crate::error::{AbortCode, ErrorCode};
+    use crate::object_directory::ObjectDirectory;
     use super::{ByteConvertible, evaluate_expression_with_node_id, make_error, string_to_value, Value};
 
     #[test]
@@ -230,10 +312,20 @@ mod value_tests {
         assert_eq!(evaluate_expression_with_node_id(2, "$NODEID+100+200"), "302");
         assert_eq!(evaluate_expression_with_node_id(2, "$NODEID + 100 + 200"), "302");
         assert_eq!(evaluate_expression_with_node_id(34, "$NODEID + 100 + 200"), "334");
-        assert_eq!(evaluate_expression_with_node_id(2, "No arithmetic here"), "0");
+        assert_eq!(evaluate_expression_with_node_id(2, "No arithmetic here"), "No arithmetic here");
         assert_eq!(evaluate_expression_with_node_id(2, "$NODEID+0x600"), "1538");
     }
 
+    #[test]
+    fn test_to_value_with_node_id_multiplication() {
+        assert_eq!(evaluate_expression_with_node_id(3, "$NODEID*0x10+0x180"), "432");
+        assert_eq!(evaluate_expression_with_node_id(3, "0x200 + $NODEID * 16"), "560");
+        assert_eq!(evaluate_expression_with_node_id(3, "$NODEID*2*2"), "12");
+        assert_eq!(evaluate_expression_with_node_id(3, "$NODEID*"), "$NODEID*");
+        assert_eq!(evaluate_expression_with_node_id(3, "$NODEID*0x7FFFFFFFFFFFFFFF"),
+                   "$NODEID*0x7FFFFFFFFFFFFFFF");
+    }
+
     #[test]
     fn test_value_creation_and_access() {
         let data = vec![1, 2, 3, 4];
@@ -302,6 +394,18 @@ mod value_tests {
         assert_eq!(u32::from_bytes(&[0x01, 0x02, 0x03]), 0);
     }
 
+    #[test]
+    fn test_try_to() {
+        assert_eq!(Value::from(0x1234u16).try_to::<u16>(), Ok(0x1234));
+        assert_eq!(Value::from(-5i64).try_to::<i64>(), Ok(-5));
+        assert_eq!(Value::from("abc".to_string()).try_to::<String>(), Ok("abc".to_string()));
+
+        let err = Value::from(0x1234u16).try_to::<u32>().unwrap_err();
+        assert!(matches!(err, ErrorCode::AbortCodeWrapper {
+            abort_code: AbortCode::DataTypeMismatchLengthMismatch, .. }));
+        assert!(Value::new(vec![0xFF, 0xFE]).try_to::<String>().is_err());
+    }
+
     #[test]
     fn test_from_bytes_for_string_with_wrong_length_returns_empty_string() {
         assert_eq!(String::from_bytes(&[0xFF, 0xFF]), "");
@@ -342,9 +446,61 @@ mod value_tests {
         assert_eq!(string_to_value(&DataType::VisibleString, test_string), Ok(Value::new(test_string.as_bytes().to_vec())));
     }
 
+    #[test]
+    fn test_visible_string_escapes() {
+        let parse = |s| string_to_value(&DataType::VisibleString, s).unwrap().to::<String>();
+        assert_eq!(parse(r#""Hello\tWorld""#), "Hello\tWorld");
+        assert_eq!(parse(r"Line1\nLine2\\"), "Line1\nLine2\\");
+        // Unknown / partial escapes and unbalanced quotes stay as they are.
+        assert_eq!(parse(r"a\x\"), r"a\x\");
+        assert_eq!(parse(r#""open"#), r#""open"#);
+        assert_eq!(parse(r#""""#), "");
+
+        let eds = "[2000]\nParameterName=Text\nObjectType=0x7\nDataType=0x0009\nAccessType=ro\nDefaultValue=\"Hello\\tWorld\"\n";
+        let mut od = ObjectDirectory::new(2, eds).unwrap();
+        assert_eq!(od.get_variable(0x2000, 0).unwrap().default_value().to::<String>(), "Hello\tWorld");
+    }
+
+    #[test]
+    fn test_string_to_value_for_octet_string() {
+        assert_eq!(string_to_value(&DataType::OctetString, "0x00 0xFF"), Ok(Value::new(vec![0x00, 0xFF])));
+        assert_eq!(string_to_value(&DataType::OctetString, "00 11 22"), Ok(Value::new(vec![0x00, 0x11, 0x22])));
+        assert_eq!(string_to_value(&DataType::OctetString, ""), Ok(Value::new(vec![])));
+        assert_eq!(string_to_value(&DataType::OctetString, "00 1G"), Err(make_error(DataType::OctetString, "00 1G")));
+        assert_eq!(string_to_value(&DataType::OctetString, "0x100"), Err(make_error(DataType::OctetString, "0x100")));
+    }
+
+    #[test]
+    fn test_string_to_value_for_unicode_string() {
+        let value = string_to_value(&DataType::UnicodeString, "Hé€").unwrap();
+        assert_eq!(value.as_slice(), &[0x48, 0x00, 0xE9, 0x00, 0xAC, 0x20]);
+
+        let units: Vec<u16> = value.as_slice().chunks(2).map(|c| u16::from_le_bytes([c[0], c[1]])).collect();
+        assert_eq!(String::from_utf16(&units).unwrap(), "Hé€");
+    }
+
     #[test]
     fn test_string_to_value_for_domain() {
         assert_eq!(string_to_value(&DataType::Domain, "123"), Ok(Value::new(123i32.to_bytes())));
+        assert_eq!(string_to_value(&DataType::Domain, ""), Ok(Value::new(vec![])));
         assert_eq!(string_to_value(&DataType::Domain, "invalid"), Err(make_error(DataType::Domain, "invalid")));
     }
+
+    #[test]
+    fn test_default_try_from_bytes() {
+        // An implementation from before `try_from_bytes()` existed.
+        #[derive(Debug, PartialEq)]
+        struct Pair(u8, u8);
+        impl ByteConvertible for Pair {
+            fn from_bytes(bytes: &[u8]) -> Self {
+                Pair(bytes[0], bytes[1])
+            }
+            fn to_bytes(&self) -> Vec<u8> {
+                vec![self.0, self.1]
+            }
+        }
+        assert_eq!(Pair::try_from_bytes(&[1, 2]), Some(Pair(1, 2)));
+        assert_eq!(Pair::try_from_bytes(&[1]), None);
+        assert_eq!(Pair::try_from_bytes(&[1, 2, 3]), None);
+    }
 }
\ No newline at end of file